@@ -0,0 +1,108 @@
+
+use std::io;
+use std::io::prelude::*;
+
+///Bytes read from a connection which haven't been dealt with yet. Consuming from the front is
+///just an offset bump, the space is reclaimed when the buffer next needs to grow.
+pub struct Buffer {
+    data: Vec<u8>,
+    start: usize
+}
+impl Buffer {
+
+    ///Build an empty buffer. Nothing is allocated until it is filled.
+    pub fn new() -> Buffer {
+        Buffer {
+            data: Vec::new(),
+            start: 0
+        }
+    }
+
+    ///The unconsumed bytes
+    #[inline(always)]
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data[self.start..]
+    }
+
+    #[inline(always)]
+    pub fn len(&self) -> usize {
+        self.data.len() - self.start
+    }
+
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    ///Mark `n` bytes from the front as dealt with
+    #[inline(always)]
+    pub fn consume(&mut self, n: usize) {
+        self.start = ::std::cmp::min(self.start + n, self.data.len());
+        if self.start == self.data.len() {
+            self.clear();
+        }
+    }
+
+    ///Drop everything
+    #[inline(always)]
+    pub fn clear(&mut self) {
+        self.data.clear();
+        self.start = 0;
+    }
+
+    ///Add bytes to the end
+    pub fn extend(&mut self, x: &[u8]) {
+        self.compact();
+        self.data.extend_from_slice(x);
+    }
+
+    ///Move the unconsumed bytes to the front
+    fn compact(&mut self) {
+        if self.start > 0 {
+            self.data.drain(..self.start);
+            self.start = 0;
+        }
+    }
+
+    ///Do one read of at most `max` bytes from `r`. Returns the number of bytes read, 0 meaning
+    ///EOF. Reading while already holding `max` bytes is an error, callers should consume first.
+    pub fn fill_from<R: Read>(&mut self, r: &mut R, max: usize) -> io::Result<usize> {
+        let len = self.len();
+        if len >= max {
+            return Err(io::Error::new(io::ErrorKind::Other, "buffer is full"));
+        }
+        self.compact();
+        self.data.resize(len + (max - len), 0);
+        match r.read(&mut self.data[len..]) {
+            Ok(n) => {
+                //uninitialized streams report usize::MAX
+                let n = ::std::cmp::min(n, max - len);
+                self.data.truncate(len + n);
+                Ok(n)
+            },
+            Err(e) => {
+                self.data.truncate(len);
+                Err(e)
+            }
+        }
+    }
+}
+#[test]
+fn test_buffer() {
+    let mut b = Buffer::new();
+    let mut src: &[u8] = b"hello world";
+    assert_eq!( b.fill_from(&mut src, 5).unwrap(), 5);
+    assert_eq!( b.as_slice(), b"hello");
+    assert!( b.fill_from(&mut src, 5).is_err() );
+    b.consume(2);
+    assert_eq!( b.as_slice(), b"llo");
+    assert_eq!( b.fill_from(&mut src, 5).unwrap(), 2);
+    assert_eq!( b.as_slice(), b"llo w");
+    b.consume(5);
+    assert!( b.is_empty() );
+    b.extend(b"abc");
+    assert_eq!( b.as_slice(), b"abc");
+    assert_eq!( b.fill_from(&mut src, 64).unwrap(), 4);
+    assert_eq!( b.as_slice(), b"abcorld");
+    assert_eq!( b.fill_from(&mut src, 64).unwrap(), 0);
+}
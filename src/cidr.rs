@@ -0,0 +1,111 @@
+
+use std::net::{
+    IpAddr,
+    Ipv4Addr,
+    Ipv6Addr
+};
+use std::str::FromStr;
+
+///A block of addresses such as `10.0.0.0/8` or `2001:db8::/32`. IPv4 addresses are kept as
+///IPv4 mapped IPv6 addresses so both families compare the same way.
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub struct Cidr {
+    addr: [u8;16],
+    prefix: u8
+}
+impl Cidr {
+
+    ///Build a block. The prefix is in bits for the address family given.
+    pub fn new(addr: IpAddr, prefix: u8) -> Option<Cidr> {
+        let (addr,prefix) = match addr {
+            IpAddr::V4(ip) if prefix <= 32 => (ip.to_ipv6_mapped().octets(), prefix+96),
+            IpAddr::V6(ip) if prefix <= 128 => (ip.octets(), prefix),
+            _ => return None
+        };
+        Some(Cidr {
+            addr: addr,
+            prefix: prefix
+        })
+    }
+
+    ///Check if an address is inside this block
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        let ip = to_bytes(ip);
+        let full = (self.prefix / 8) as usize;
+        if self.addr[..full] != ip[..full] {
+            return false;
+        }
+        let bits = self.prefix % 8;
+        if bits == 0 {
+            return true;
+        }
+        let mask = 0xffu8 << (8 - bits);
+        (self.addr[full] & mask) == (ip[full] & mask)
+    }
+}
+
+///Check if an address is inside any of the blocks
+pub fn any_contains(v: &[Cidr], ip: &IpAddr) -> bool {
+    v.iter().any(|c| c.contains(ip))
+}
+
+#[inline(always)]
+fn to_bytes(ip: &IpAddr) -> [u8;16] {
+    match ip {
+        &IpAddr::V4(ref ip) => ip.to_ipv6_mapped().octets(),
+        &IpAddr::V6(ref ip) => ip.octets()
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = ();
+
+    ///Parse `address/prefix`. A bare address is a block of one.
+    fn from_str(s: &str) -> Result<Cidr,()> {
+        let mut parts = s.splitn(2, '/');
+        let addr = parts.next().unwrap_or("");
+        let prefix = parts.next();
+        match (Ipv4Addr::from_str(addr), Ipv6Addr::from_str(addr)) {
+            (Ok(ip),_) => {
+                let p = match prefix {
+                    Option::None => 32,
+                    Option::Some(p) => u8::from_str(p).map_err(|_| ())?
+                };
+                Cidr::new(IpAddr::V4(ip), p).ok_or(())
+            },
+            (_,Ok(ip)) => {
+                let p = match prefix {
+                    Option::None => 128,
+                    Option::Some(p) => u8::from_str(p).map_err(|_| ())?
+                };
+                Cidr::new(IpAddr::V6(ip), p).ok_or(())
+            },
+            _ => Err(())
+        }
+    }
+}
+#[test]
+fn test_cidr() {
+    let ip = |s: &str| IpAddr::from_str(s).unwrap();
+
+    let c = Cidr::from_str("10.0.0.0/8").unwrap();
+    assert!( c.contains(&ip("10.1.2.3")) );
+    assert!( ! c.contains(&ip("11.0.0.1")) );
+    assert!( c.contains(&ip("::ffff:10.9.9.9")) );
+
+    let c = Cidr::from_str("192.168.4.0/22").unwrap();
+    assert!( c.contains(&ip("192.168.7.255")) );
+    assert!( ! c.contains(&ip("192.168.8.0")) );
+
+    let c = Cidr::from_str("2001:db8::/32").unwrap();
+    assert!( c.contains(&ip("2001:db8:1::1")) );
+    assert!( ! c.contains(&ip("2001:db9::1")) );
+
+    let c = Cidr::from_str("127.0.0.1").unwrap();
+    assert!( c.contains(&ip("127.0.0.1")) );
+    assert!( ! c.contains(&ip("127.0.0.2")) );
+
+    assert!( Cidr::from_str("0.0.0.0/0").unwrap().contains(&ip("8.8.8.8")) );
+    assert!( Cidr::from_str("10.0.0.0/33").is_err() );
+    assert!( Cidr::from_str("nope/8").is_err() );
+}
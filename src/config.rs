@@ -1,5 +1,6 @@
 
 use std::sync::atomic::{AtomicUsize,Ordering};
+use std::time::Duration;
 
 lazy_static! {
     static ref WORKERS: AtomicUsize = AtomicUsize::new(0);
@@ -14,3 +15,119 @@ pub fn set_workers(x: usize) {
 pub fn get_workers() -> usize {
     WORKERS.load(Ordering::Relaxed)
 }
+
+///What the event loop does with new clients when every token is in use
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum Overload {
+    ///Stop listening until a connection closes and frees a token
+    Pause,
+    ///Accept the client, send it a TLS alert, and close it
+    Reject
+}
+
+lazy_static! {
+    static ref OVERLOAD: AtomicUsize = AtomicUsize::new(0);
+    static ref PENDING_CONNECTS: AtomicUsize = AtomicUsize::new(1024);
+}
+
+///Set the overload policy
+pub fn set_overload(x: Overload) {
+    OVERLOAD.store(x as usize, Ordering::SeqCst);
+}
+
+///Get the overload policy
+pub fn get_overload() -> Overload {
+    match OVERLOAD.load(Ordering::Relaxed) {
+        0 => Overload::Pause,
+        _ => Overload::Reject
+    }
+}
+
+///Set how many backend connection requests may wait for a free token
+pub fn set_pending_connects(x: usize) {
+    PENDING_CONNECTS.store(x, Ordering::SeqCst);
+}
+
+///Get how many backend connection requests may wait for a free token
+pub fn get_pending_connects() -> usize {
+    PENDING_CONNECTS.load(Ordering::Relaxed)
+}
+
+lazy_static! {
+    static ref ACCEPT_BATCH: AtomicUsize = AtomicUsize::new(64);
+}
+
+///Set how many clients may be accepted for a single listener event
+pub fn set_accept_batch(x: usize) {
+    ACCEPT_BATCH.store(x, Ordering::SeqCst);
+}
+
+///Get how many clients may be accepted for a single listener event. Never less than 1.
+pub fn get_accept_batch() -> usize {
+    match ACCEPT_BATCH.load(Ordering::Relaxed) {
+        0 => 1,
+        x => x
+    }
+}
+
+lazy_static! {
+    static ref IDLE_BACKENDS: AtomicUsize = AtomicUsize::new(16);
+    static ref IDLE_TIMEOUT_MS: AtomicUsize = AtomicUsize::new(30_000);
+    static ref UPGRADED_TIMEOUT_MS: AtomicUsize = AtomicUsize::new(600_000);
+    static ref BACKEND_TIMEOUT_MS: AtomicUsize = AtomicUsize::new(60_000);
+    static ref CACHE_BUDGET: AtomicUsize = AtomicUsize::new(64 << 20);
+}
+
+///Set how many idle backend connections a worker keeps for each pool
+pub fn set_idle_backends(x: usize) {
+    IDLE_BACKENDS.store(x, Ordering::SeqCst);
+}
+
+///Get how many idle backend connections a worker keeps for each pool
+pub fn get_idle_backends() -> usize {
+    IDLE_BACKENDS.load(Ordering::Relaxed)
+}
+
+///Set how long an idle backend connection is kept before it is closed
+pub fn set_idle_timeout(x: Duration) {
+    let ms = x.as_secs() as usize * 1000 + (x.subsec_nanos() / 1_000_000) as usize;
+    IDLE_TIMEOUT_MS.store(ms, Ordering::SeqCst);
+}
+
+///Get how long an idle backend connection is kept before it is closed
+pub fn get_idle_timeout() -> Duration {
+    Duration::from_millis(IDLE_TIMEOUT_MS.load(Ordering::Relaxed) as u64)
+}
+
+///Set how long an upgraded connection, such as WebSocket, may sit with nothing sent either way before it is closed
+pub fn set_upgraded_timeout(x: Duration) {
+    let ms = x.as_secs() as usize * 1000 + (x.subsec_nanos() / 1_000_000) as usize;
+    UPGRADED_TIMEOUT_MS.store(ms, Ordering::SeqCst);
+}
+
+///Get how long an upgraded connection, such as WebSocket, may sit with nothing sent either way before it is closed
+pub fn get_upgraded_timeout() -> Duration {
+    Duration::from_millis(UPGRADED_TIMEOUT_MS.load(Ordering::Relaxed) as u64)
+}
+
+///Set how long a backend may leave a request unanswered, with nothing sent either way, before
+///its client is sent a 504
+pub fn set_backend_timeout(x: Duration) {
+    let ms = x.as_secs() as usize * 1000 + (x.subsec_nanos() / 1_000_000) as usize;
+    BACKEND_TIMEOUT_MS.store(ms, Ordering::SeqCst);
+}
+
+///Get how long a backend may leave a request unanswered before its client is sent a 504
+pub fn get_backend_timeout() -> Duration {
+    Duration::from_millis(BACKEND_TIMEOUT_MS.load(Ordering::Relaxed) as u64)
+}
+
+///Set how many bytes of responses the cache may hold, shared by every worker
+pub fn set_cache_budget(x: usize) {
+    CACHE_BUDGET.store(x, Ordering::SeqCst);
+}
+
+///Get how many bytes of responses the cache may hold
+pub fn get_cache_budget() -> usize {
+    CACHE_BUDGET.load(Ordering::Relaxed)
+}
@@ -0,0 +1,46 @@
+
+use super::super::native_tls::TlsAcceptor;
+use std::sync::atomic::{
+    AtomicPtr,
+    Ordering
+};
+
+///Handle to a `TlsAcceptor` shared between the event loop and the workers. This is what the
+///event loop hands to a worker so the worker can perform the handshake.
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub struct AcceptorID(pub usize);
+
+lazy_static! {
+    static ref ACCEPTORS: AtomicPtr<Vec<TlsAcceptor>> =
+        AtomicPtr::new(
+            Box::into_raw(
+                Box::new(
+                    Vec::with_capacity(4))));
+}
+
+///function to get the raw pointer to the array
+#[inline(always)]
+fn raw_ptr<'a>() -> &'a mut Vec<TlsAcceptor> {
+    let ptr: *mut Vec<TlsAcceptor> = ACCEPTORS.load(Ordering::Acquire);
+    unsafe {
+        match ptr.as_mut() {
+            Option::Some(x) => x,
+            Option::None => unreachable!()
+        }
+    }
+}
+
+///Store an acceptor so workers can use it. This must only be called during start up, before
+///any worker threads exist.
+pub fn add_acceptor(a: TlsAcceptor) -> AcceptorID {
+    let v = raw_ptr();
+    v.push(a);
+    AcceptorID(v.len()-1)
+}
+
+///Look up an acceptor
+#[inline(always)]
+pub fn get_acceptor<'a>(id: AcceptorID) -> Option<&'a TlsAcceptor> {
+    let v: &'a mut Vec<TlsAcceptor> = raw_ptr();
+    v.get(id.0)
+}
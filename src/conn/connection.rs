@@ -14,10 +14,40 @@ use super::stream::{
     StreamType
 };
 use super::fault::Fault;
+use super::hello::{
+    Hello,
+    TlsInfo,
+    MAX_HELLO_WIRE,
+    parse
+};
+use super::super::listener::{
+    Accepting,
+    ListenerID
+};
+use super::super::proxy::{
+    Parsed,
+    V2_MAX,
+    parse as parse_proxy
+};
 use super::super::workerid::WorkerID;
+use super::super::ipc::get_poll;
+use super::super::native_tls::TlsAcceptor;
+use super::super::buffer::Buffer;
+use super::super::http::session::Session;
+use super::super::http::files::FileBody;
+use std::net::SocketAddr;
+use std::os::unix::fs::FileExt;
+use std::cmp::min;
+
+///Most of a file read into memory at once, when it can't be sent with `sendfile`
+const FILE_CHUNK: u64 = 16 * 1024;
 
-///Represents a single connection. Should be the size of 3 cache lines.
-#[repr(C)]
+///Most of a file handed to `sendfile` in one call
+const MAX_SENDFILE: u64 = 1 << 20;
+
+///Represents a single connection. Aligned so it takes whole cache lines, 5 of them, and
+///neighbours in the slab never share one.
+#[repr(C,align(64))]
 #[allow(dead_code)]
 pub struct Connection {
     lock: Lock,
@@ -28,14 +58,27 @@ pub struct Connection {
     pub other: Token, 
     pub err: Fault,
     pub action: Ready,
-    pad: u64
+    pub accepting: Option<Accepting>,
+    //cache line
+    pub peer: Option<SocketAddr>,
+    pub tls: Option<Box<TlsInfo>>,
+    pub pending: Vec<u8>,
+    //cache line
+    pub buf: Buffer,
+    pub http: Option<Box<Session>>,
+    ///Listener a client was accepted on
+    pub listener: Option<ListenerID>,
+    ///Bytes an L4 or passthrough client has sent toward its backend
+    pub sent: u64
 }
 unsafe impl Sync for Connection { }
 #[test]
 fn test_connection_size() {
     use std::mem::size_of;
 
-    assert_eq!( size_of::<Connection>(), 192);
+    //the exact size depends on the compiler's enum layout, the alignment is what matters
+    assert_eq!( size_of::<Connection>() % 64, 0);
+    assert!( size_of::<Connection>() <= 5 * 64 );
 }
 
 impl Connection {
@@ -49,14 +92,32 @@ impl Connection {
             other: Token(0),
             err: Fault::None,
             action: Ready::none(),
-            pad: 0u64
+            accepting: None,
+            peer: None,
+            tls: None,
+            pending: Vec::new(),
+            buf: Buffer::new(),
+            http: None,
+            listener: None,
+            sent: 0
         }
     }
 
-    ///Set up a stream
-    pub fn setup(&mut self, x: Stream, w: WorkerID) -> Result<(),Stream> {
+    ///Set up a stream. `peer` is the client's address, backends don't have one.
+    pub fn setup(&mut self, x: Stream, w: WorkerID, peer: Option<SocketAddr>) -> Result<(),Stream> {
         let x = replace( &mut self.data, x );
         self.lock.manual_set(w);
+        //streams are registered readable when they're created
+        self.action = Ready::readable();
+        self.other = Token(0);
+        self.accepting = None;
+        self.peer = peer;
+        self.tls = None;
+        self.pending.clear();
+        self.buf.clear();
+        self.http = None;
+        self.listener = None;
+        self.sent = 0;
         if ! x.is_uninitialized() {
             Err(x)
         } else {
@@ -65,6 +126,24 @@ impl Connection {
     
     }
 
+    ///Close the stream and forget everything about the connection, so its token can be handed
+    ///out again. No worker owns it afterwards.
+    pub fn close(&mut self) {
+        drop(replace( &mut self.data, Stream::Uninitialized ));
+        self.action = Ready::none();
+        self.other = Token(0);
+        self.err = Fault::None;
+        self.accepting = None;
+        self.peer = None;
+        self.tls = None;
+        self.pending.clear();
+        self.buf.clear();
+        self.http = None;
+        self.listener = None;
+        self.sent = 0;
+        self.lock.unlock();
+    }
+
     ///Attempt to replace a stream. Returns a Err(Stream) if a
     ///an actual initialized stream is replaced. This _shouldn't_
     ///ever happen, but yeah how the Stream sum type works it
@@ -86,11 +165,186 @@ impl Connection {
             Ok(x) => {
                 let flag = x.is_tls();
                 let _ = replace(&mut self.data, x);
+                if flag {
+                    self.record_negotiated();
+                }
                 Ok(flag)
             },
             Err(e) => Err(e)
         }
     }
+
+    ///Add what the finished handshake agreed to what the ClientHello offered
+    fn record_negotiated(&mut self) {
+        match (self.data.negotiated(), self.tls.as_mut()) {
+            (Option::Some((alpn, version)), Option::Some(info)) => {
+                info.agreed_alpn = alpn;
+                info.agreed_version = Some(version);
+            },
+            _ => { }
+        };
+    }
+    ///Look at the ClientHello before TLS is started, recording what the client asked for.
+    ///Returns false if the ClientHello hasn't fully arrived yet. Clients which don't send a
+    ///ClientHello are left for the TLS library to reject.
+    pub fn peek_hello(&mut self) -> Result<bool,Fault> {
+        let mut buf = vec![0u8; MAX_HELLO_WIRE];
+        let n = match self.data.peek(&mut buf) {
+            Ok(0) => return Err(Fault::from(io::Error::new(io::ErrorKind::UnexpectedEof, "client closed before sending a ClientHello"))),
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(e) => return Err(Fault::from(e))
+        };
+        match parse(&buf[..n]) {
+            Hello::Done(info) => {
+                self.tls = Some(Box::new(info));
+                Ok(true)
+            },
+            Hello::More if n < MAX_HELLO_WIRE => Ok(false),
+            _ => Ok(true)
+        }
+    }
+
+    ///Read the ClientHello into the connection's buffer without terminating TLS, for
+    ///passthrough. The bytes stay buffered so they can be replayed to the backend. Returns
+    ///false if the ClientHello hasn't fully arrived yet.
+    pub fn read_hello(&mut self) -> Result<bool,Fault> {
+        match self.fill(MAX_HELLO_WIRE) {
+            Ok(0) => return Err(Fault::from(io::Error::new(io::ErrorKind::UnexpectedEof, "client closed before sending a ClientHello"))),
+            Ok(_) => { },
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(e) => return Err(Fault::from(e))
+        };
+        match parse(self.buf.as_slice()) {
+            Hello::Done(info) => {
+                self.tls = Some(Box::new(info));
+                Ok(true)
+            },
+            Hello::More if self.buf.len() < MAX_HELLO_WIRE => Ok(false),
+            _ => Err(Fault::from(io::Error::new(io::ErrorKind::InvalidData, "client did not send a valid ClientHello")))
+        }
+    }
+
+    ///Read and strip a PROXY protocol header, recording the real client address. Returns false
+    ///if the header hasn't fully arrived yet. Only the header is consumed, whatever follows is
+    ///left in the socket.
+    pub fn read_proxy_header(&mut self) -> Result<bool,Fault> {
+        let mut buf = vec![0u8; V2_MAX];
+        let n = match self.data.peek(&mut buf) {
+            Ok(0) => return Err(Fault::Proxy("client closed before sending a PROXY header")),
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(false),
+            Err(e) => return Err(Fault::from(e))
+        };
+        let (len,src) = match parse_proxy(&buf[..n]) {
+            Parsed::Done{ len, src, .. } => (len,src),
+            Parsed::More => return Ok(false),
+            Parsed::Invalid(why) => return Err(Fault::Proxy(why))
+        };
+        //the bytes were peeked, so they are all in the socket buffer already
+        let mut read = 0;
+        while read < len {
+            match self.data.read(&mut buf[read..len]) {
+                Ok(0) => return Err(Fault::Proxy("client closed while sending a PROXY header")),
+                Ok(x) => read += x,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(Fault::from(e))
+            };
+        }
+        match src {
+            //health checks from the upstream don't describe a client
+            Option::Some(addr) => self.peer = Some(addr),
+            Option::None => { }
+        };
+        Ok(true)
+    }
+
+    ///Read what is avalible into the connection's buffer, holding at most `max` bytes. Returns
+    ///the bytes read, 0 meaning EOF.
+    #[inline(always)]
+    pub fn fill(&mut self, max: usize) -> io::Result<usize> {
+        self.buf.fill_from(&mut self.data, max)
+    }
+
+    ///Check an idle backend has closed, or sent something nobody asked for. Either way it
+    ///can't be reused.
+    pub fn is_stale(&self) -> bool {
+        let mut b = [0u8;1];
+        match self.data.peek(&mut b) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => false,
+            _ => true
+        }
+    }
+
+    ///Stop writing to the connection, see `Stream::shutdown_write`
+    #[inline(always)]
+    pub fn shutdown_write(&self) -> io::Result<()> {
+        self.data.shutdown_write()
+    }
+
+    ///The address the client connected too
+    #[inline(always)]
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.data.local_addr()
+    }
+
+    ///Write out bytes which must be sent before anything else, such as a PROXY protocol
+    ///header. Returns a `WouldBlock` error if they couldn't all be written.
+    pub fn write_pending(&mut self) -> io::Result<()> {
+        if self.is_uninitialized() {
+            return Ok(());
+        }
+        while ! self.pending.is_empty() {
+            let n = self.data.write(&self.pending)?;
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write pending bytes"));
+            }
+            self.pending.drain(..n);
+        }
+        Ok(())
+    }
+
+    ///Send as much of a file as the client will take, after anything pending. Plain TCP uses
+    ///`sendfile`, otherwise the file is copied a chunk at a time through `pending`. Returns
+    ///true once all of it is sent, or a `WouldBlock` error.
+    pub fn send_file(&mut self, f: &mut FileBody) -> io::Result<bool> {
+        self.write_pending()?;
+        while f.remaining > 0 {
+            let n = if self.is_tcp() {
+                self.data.send_file(&f.file, f.offset, min(f.remaining, MAX_SENDFILE) as usize)?
+            } else {
+                let mut chunk = vec![0u8; min(f.remaining, FILE_CHUNK) as usize];
+                let n = f.file.read_at(&mut chunk, f.offset)?;
+                self.pending.extend_from_slice(&chunk[..n]);
+                n
+            };
+            if n == 0 {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "file is shorter than expected"));
+            }
+            f.offset += n as u64;
+            f.remaining -= n as u64;
+            self.write_pending()?;
+        }
+        Ok(true)
+    }
+
+    ///Start a TLS handshake on a TCP stream. Returns a flag if the handshake is already
+    ///complete. On failure the stream is dropped, leaving the connection uninitialized.
+    pub fn start_tls(&mut self, a: &TlsAcceptor) -> Result<bool,Fault> {
+        let x = replace(&mut self.data, Stream::Uninitialized);
+        match x.start_tls(a) {
+            Ok(x) => {
+                let flag = x.is_tls();
+                let _ = replace(&mut self.data, x);
+                if flag {
+                    self.record_negotiated();
+                }
+                Ok(flag)
+            },
+            Err(e) => Err(e)
+        }
+    }
+
     ///Checks if the token is nonzero
     #[inline(always)]
     pub fn token_valid(&self) -> bool {
@@ -108,6 +362,20 @@ impl Connection {
     pub fn wants_read(&self) -> bool {
         self.action == Ready::readable()
     }
+
+    ///Have the event loop's `Poll` report `r` for this connection, if it isn't already. The
+    ///interest is recorded in `action`.
+    pub fn set_interest(&mut self, r: Ready) -> io::Result<()> {
+        if r == self.action {
+            return Ok(());
+        }
+        match get_poll() {
+            Option::Some(p) => self.data.reregister(p, self.token, r)?,
+            Option::None => { }
+        };
+        self.action = r;
+        Ok(())
+    }
 }
 #[test]
 fn test_connection() {
@@ -116,6 +384,16 @@ fn test_connection() {
     assert!( ! x.token_valid() );
     assert!( x.is_uninitialized() );
     assert_eq!( x.action, Ready::none() );
+
+    //closing hands the connection back
+    let mut x = Connection::new();
+    x.lock.manual_set(WorkerID(3));
+    x.other = Token(12);
+    x.pending.extend_from_slice(b"unsent");
+    x.close();
+    assert_eq!( x.worker(), None);
+    assert!( ! x.has_partner() );
+    assert!( x.pending.is_empty() );
 }
 impl Locky for Connection {
 
@@ -186,10 +464,13 @@ impl Read for Connection {
 impl Write for Connection {
     ///Uninitialized streams return a usize::MAX value. As returning 0 may cause
     ///some systems to think and EOF has occured. 
+    ///Pending bytes are always written first.
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_pending()?;
         self.data.write(buf)
     }
     fn flush(&mut self) -> io::Result<()> {
+        self.write_pending()?;
         self.data.flush()
     }
 }
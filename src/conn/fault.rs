@@ -1,11 +1,22 @@
 
 use super::super::native_tls::Error as TLSError;
 use std::io::Error as OSFault;
+use super::super::http::HttpError;
 
-///Unified Error Handling for IO errors and TLS errors
+///Unified Error Handling for IO errors and TLS errors. Proxy is a malformed or untrusted
+///PROXY protocol header from a client. Http is a message which couldn't be parsed in HTTP mode.
+///BadRequest is a client's request which was refused, such as one over a size limit. Smuggling
+///is a request refused with a 400 because it could be framed differently by the backend. Both
+///are closed with `worker::linger`, so the client gets to read its refusal.
+///NoRoute is a client the listener has no backend pool for.
 pub enum Fault {
 	TLS(TLSError),
 	OS(OSFault),
+    Proxy(&'static str),
+    Http(HttpError),
+    BadRequest(HttpError),
+    Smuggling(HttpError),
+    NoRoute,
     None
 }
 impl Fault {
@@ -24,6 +35,13 @@ impl From<OSFault> for Fault {
 		Fault::OS(x)
 	}
 }
+impl From<HttpError> for Fault {
+
+    ///From implemented to support ? notation
+	fn from(x: HttpError) -> Fault {
+		Fault::Http(x)
+	}
+}
 impl From<TLSError> for Fault {
 
     ///From implemented to support ? notation
@@ -38,10 +56,12 @@ fn test_fault() {
 
     let x = unsafe{Fault::OS(uninitialized())};
     let y = unsafe{Fault::TLS(uninitialized())};
+    let p = Fault::Proxy("bad header");
     let z = Fault::None;
 
     assert!( x.exists() );
     assert!( y.exists() );
+    assert!( p.exists() );
     assert!( !z.exists());
 
     forget(x);
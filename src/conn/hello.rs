@@ -0,0 +1,317 @@
+
+///What a client said about itself in its TLS ClientHello
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct TlsInfo {
+    ///The host name from the server_name extension
+    pub sni: Option<String>,
+    ///Protocols from the ALPN extension, in the client's order of preference
+    pub alpn: Vec<Vec<u8>>,
+    ///Highest TLS version offered, as its wire value. `0x0303` is TLS1.2
+    pub version: u16,
+    ///ALPN protocol agreed once TLS is terminated here. `None` before then, if nothing was
+    ///agreed, or if TLS is passed through.
+    pub agreed_alpn: Option<Vec<u8>>,
+    ///TLS version agreed once TLS is terminated here, as openssl names it, such as `TLSv1.2`
+    pub agreed_version: Option<&'static str>
+}
+
+///If a name is a DNS host name, dot separated labels of letters, digits and inner hyphens.
+///Anything else can't be a real server name, and mustn't be copied into headers.
+pub fn is_hostname(s: &str) -> bool {
+    let s = if s.ends_with('.') { &s[..s.len()-1] } else { s };
+    ! s.is_empty() && s.len() <= 253 && s.split('.').all(|label| {
+        ! label.is_empty() && label.len() <= 63 &&
+            ! label.starts_with('-') && ! label.ends_with('-') &&
+            label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+    })
+}
+
+///Result of looking at the first bytes a client sent
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum Hello {
+    ///A full ClientHello was read
+    Done(TlsInfo),
+    ///Looks like TLS, but the ClientHello isn't all here yet
+    More,
+    ///This isn't a TLS ClientHello
+    Invalid
+}
+
+const HANDSHAKE: u8 = 0x16;
+const CLIENT_HELLO: u8 = 0x01;
+const EXT_SERVER_NAME: u16 = 0x0000;
+const EXT_ALPN: u16 = 0x0010;
+const EXT_SUPPORTED_VERSIONS: u16 = 0x002b;
+
+///Minimal cursor over a byte slice. Every read is bounds checked, running off the end
+///returns `None`
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize
+}
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Reader<'a> {
+        Reader {
+            buf: buf,
+            pos: 0
+        }
+    }
+    fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+    fn bytes(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.buf.len() - self.pos < n {
+            return None;
+        }
+        let s = &self.buf[self.pos..self.pos+n];
+        self.pos += n;
+        Some(s)
+    }
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|x| x[0])
+    }
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|x| ((x[0] as u16) << 8) | x[1] as u16)
+    }
+    fn u24(&mut self) -> Option<usize> {
+        self.bytes(3).map(|x| ((x[0] as usize) << 16) | ((x[1] as usize) << 8) | x[2] as usize)
+    }
+    ///Read a length prefixed (by `n` bytes) vector
+    fn vector(&mut self, n: usize) -> Option<&'a [u8]> {
+        let len = match n {
+            1 => self.u8()? as usize,
+            2 => self.u16()? as usize,
+            _ => self.u24()?
+        };
+        self.bytes(len)
+    }
+}
+
+///GREASE values (RFC 8701) are sent to keep servers honest and must be ignored
+#[inline(always)]
+fn is_grease(x: u16) -> bool {
+    (x & 0x0f0f) == 0x0a0a && (x >> 8) == (x & 0xff)
+}
+
+///Largest ClientHello handshake message accepted, its 4 byte header included
+pub const MAX_HELLO_LEN: usize = 16384;
+
+///Most records a ClientHello may be split across
+pub const MAX_HELLO_RECORDS: usize = 16;
+
+///Most bytes a complete ClientHello can take on the wire, record headers included
+pub const MAX_HELLO_WIRE: usize = MAX_HELLO_LEN + 5 * MAX_HELLO_RECORDS;
+
+///Largest record payload TLS allows
+const MAX_RECORD: usize = 16384;
+
+///Parse a ClientHello from the start of what a client sent. The message may be fragmented
+///over several handshake records, which are put back together. A message larger than
+///`MAX_HELLO_LEN`, or split over more than `MAX_HELLO_RECORDS` records, is invalid.
+pub fn parse(buf: &[u8]) -> Hello {
+    let mut msg: Vec<u8> = Vec::new();
+    let mut rest = buf;
+    for _ in 0..MAX_HELLO_RECORDS {
+        if rest.is_empty() {
+            return Hello::More;
+        }
+        if rest[0] != HANDSHAKE {
+            return Hello::Invalid;
+        }
+        if rest.len() < 5 {
+            return Hello::More;
+        }
+        let len = ((rest[3] as usize) << 8) | rest[4] as usize;
+        if len == 0 || len > MAX_RECORD {
+            return Hello::Invalid;
+        }
+        if rest.len() < 5 + len {
+            return Hello::More;
+        }
+        msg.extend_from_slice(&rest[5..5+len]);
+        rest = &rest[5+len..];
+
+        if msg.len() < 4 {
+            continue;
+        }
+        if msg[0] != CLIENT_HELLO {
+            return Hello::Invalid;
+        }
+        let total = 4 + (((msg[1] as usize) << 16) | ((msg[2] as usize) << 8) | msg[3] as usize);
+        if total > MAX_HELLO_LEN {
+            return Hello::Invalid;
+        }
+        if msg.len() >= total {
+            return match parse_handshake(&msg[..total]) {
+                Option::Some(info) => Hello::Done(info),
+                Option::None => Hello::Invalid
+            };
+        }
+    }
+    Hello::Invalid
+}
+
+///Parse the body of a ClientHello handshake message
+fn parse_handshake(buf: &[u8]) -> Option<TlsInfo> {
+    let mut r = Reader::new(buf);
+    if r.u8()? != CLIENT_HELLO {
+        return None;
+    }
+    let body = r.vector(3)?;
+    let mut r = Reader::new(body);
+    let mut info = TlsInfo {
+        sni: None,
+        alpn: Vec::new(),
+        version: r.u16()?,
+        agreed_alpn: None,
+        agreed_version: None
+    };
+    //random
+    r.bytes(32)?;
+    //session id
+    r.vector(1)?;
+    //cipher suites
+    r.vector(2)?;
+    //compression methods
+    r.vector(1)?;
+    if r.is_empty() {
+        //no extensions
+        return Some(info);
+    }
+    let mut exts = Reader::new(r.vector(2)?);
+    while ! exts.is_empty() {
+        let kind = exts.u16()?;
+        let mut data = Reader::new(exts.vector(2)?);
+        match kind {
+            EXT_SERVER_NAME => {
+                let mut list = Reader::new(data.vector(2)?);
+                while ! list.is_empty() {
+                    let name_type = list.u8()?;
+                    let name = list.vector(2)?;
+                    if name_type == 0 {
+                        //a name which isn't a host name is refused, it would be copied
+                        //into routing and headers
+                        match ::std::str::from_utf8(name) {
+                            Ok(s) if is_hostname(s) => info.sni = Some(s.to_ascii_lowercase()),
+                            _ => return None
+                        };
+                    }
+                }
+            },
+            EXT_ALPN => {
+                let mut list = Reader::new(data.vector(2)?);
+                while ! list.is_empty() {
+                    info.alpn.push(list.vector(1)?.to_vec());
+                }
+            },
+            EXT_SUPPORTED_VERSIONS => {
+                let mut list = Reader::new(data.vector(1)?);
+                while ! list.is_empty() {
+                    let v = list.u16()?;
+                    if ! is_grease(v) && v > info.version {
+                        info.version = v;
+                    }
+                }
+            },
+            _ => { }
+        };
+    }
+    Some(info)
+}
+
+///Build a ClientHello record for tests
+#[cfg(test)]
+pub fn test_hello(sni: &str, alpn: &[&[u8]]) -> Vec<u8> {
+    fn push_u16(v: &mut Vec<u8>, x: usize) {
+        v.push((x >> 8) as u8);
+        v.push(x as u8);
+    }
+    let mut exts = Vec::new();
+    //server_name
+    push_u16(&mut exts, 0x0000);
+    push_u16(&mut exts, sni.len() + 5);
+    push_u16(&mut exts, sni.len() + 3);
+    exts.push(0);
+    push_u16(&mut exts, sni.len());
+    exts.extend_from_slice(sni.as_bytes());
+    //alpn
+    let mut list = Vec::new();
+    for p in alpn {
+        list.push(p.len() as u8);
+        list.extend_from_slice(p);
+    }
+    push_u16(&mut exts, 0x0010);
+    push_u16(&mut exts, list.len() + 2);
+    push_u16(&mut exts, list.len());
+    exts.extend_from_slice(&list);
+    //supported_versions, with a GREASE value
+    push_u16(&mut exts, 0x002b);
+    push_u16(&mut exts, 5);
+    exts.extend_from_slice(&[4, 0x3a, 0x3a, 0x03, 0x04]);
+
+    let mut body = Vec::new();
+    body.extend_from_slice(&[0x03, 0x03]);
+    body.extend_from_slice(&[0u8;32]);
+    body.push(0);
+    body.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]);
+    body.extend_from_slice(&[0x01, 0x00]);
+    push_u16(&mut body, exts.len());
+    body.extend_from_slice(&exts);
+
+    let mut hs = vec![CLIENT_HELLO, 0, 0, 0];
+    hs[2] = (body.len() >> 8) as u8;
+    hs[3] = body.len() as u8;
+    hs.extend_from_slice(&body);
+
+    let mut rec = vec![HANDSHAKE, 0x03, 0x01];
+    push_u16(&mut rec, hs.len());
+    rec.extend_from_slice(&hs);
+    rec
+}
+#[test]
+fn test_parse_hello() {
+    let rec = test_hello("Example.COM", &[b"h2", b"http/1.1"]);
+    let info = match parse(&rec) {
+        Hello::Done(x) => x,
+        x => panic!("{:?}", x)
+    };
+    assert_eq!( info.sni, Some("example.com".to_string()));
+    assert_eq!( info.alpn, vec![b"h2".to_vec(), b"http/1.1".to_vec()]);
+    assert_eq!( info.version, 0x0304);
+
+    //partial records ask for more
+    for i in 0..rec.len() {
+        assert_eq!( parse(&rec[..i]), Hello::More);
+    }
+    //plain text is not tls
+    assert_eq!( parse(b"GET / HTTP/1.1\r\n"), Hello::Invalid);
+
+    //too many records
+    let mut frag = Vec::new();
+    for chunk in rec[5..].chunks(3) {
+        frag.extend_from_slice(&[HANDSHAKE, 0x03, 0x01, 0, chunk.len() as u8]);
+        frag.extend_from_slice(chunk);
+    }
+    assert_eq!( parse(&frag), Hello::Invalid);
+    //the same message split over a few records
+    let mut frag = Vec::new();
+    for chunk in rec[5..].chunks(rec.len() / 3) {
+        frag.extend_from_slice(&[HANDSHAKE, 0x03, 0x01, 0, chunk.len() as u8]);
+        frag.extend_from_slice(chunk);
+    }
+    match parse(&frag) {
+        Hello::Done(x) => assert_eq!( x, info),
+        x => panic!("{:?}", x)
+    };
+    for i in 0..frag.len() {
+        assert_eq!( parse(&frag[..i]), Hello::More);
+    }
+
+    //a server name which isn't a host name
+    assert_eq!( parse(&test_hello("a\r\nb", &[])), Hello::Invalid);
+    assert_eq!( parse(&test_hello("-x.example", &[])), Hello::Invalid);
+
+    //a message claiming to be larger than the limit
+    let huge = [HANDSHAKE, 0x03, 0x01, 0x00, 0x04, CLIENT_HELLO, 0x01, 0x00, 0x00];
+    assert_eq!( parse(&huge), Hello::Invalid);
+}
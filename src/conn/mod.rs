@@ -1,4 +1,6 @@
 pub mod stream;
 pub mod connection;
 pub mod fault;
+pub mod acceptor;
+pub mod hello;
 
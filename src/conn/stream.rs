@@ -21,8 +21,22 @@ use super::super::native_tls::{
     HandshakeError,
     TlsAcceptor
 };
+use super::super::native_tls::backend::openssl::TlsStreamExt;
+use super::super::libc::{
+    recv,
+    sendfile,
+    off_t,
+    c_void,
+    c_int
+};
+use std::fs::File;
 use std::io;
 use std::io::prelude::*;
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
+
+///Not exported by the libc version we use, it is the same on every unix.
+const MSG_PEEK: c_int = 0x2;
 
 
 ///Determines the type of stream
@@ -94,23 +108,99 @@ impl Stream {
             }
         }
     }
-    ///Build a new TLS Stream. Well start the handshaking. The code flow
-    ///exists for this to _potentially_ finish. But this is likely impossible
-    ///as a `WOULDBLOCK` error _should_ occur before that.
-    pub fn create_tls(x: TcpStream, poll: &Poll, t: Token, a: &TlsAcceptor) -> Result<Stream,Fault> {
-        let r = Ready::readable();
-        match poll.register(&x, t, r, PollOpt::level()) {
-            Ok(_) => { },
-            Err(e) => {
-                let _ = poll.deregister(&x); 
-                let _ = x.shutdown(Shutdown::Both);
-                return Err(Fault::from(e));
-            }
+    ///Change what a registered stream is polled for
+    pub fn reregister(&self, poll: &Poll, t: Token, r: Ready) -> io::Result<()> {
+        match self {
+            &Stream::Tcp(ref x) => poll.reregister(x, t, r, PollOpt::level()),
+            &Stream::Tls(ref x) => poll.reregister(x.get_ref(), t, r, PollOpt::level()),
+            &Stream::TlsHandShake(ref x) => poll.reregister(x.get_ref(), t, r, PollOpt::level()),
+            &Stream::Unix(ref x) => poll.reregister(x, t, r, PollOpt::level()),
+            &Stream::Uninitialized => Ok(())
+        }
+    }
+
+    ///Stop writing, the peer reads EOF once what was sent has arrived. Reading carries on.
+    pub fn shutdown_write(&self) -> io::Result<()> {
+        match self {
+            &Stream::Tcp(ref x) => x.shutdown(Shutdown::Write),
+            &Stream::Tls(ref x) => x.get_ref().shutdown(Shutdown::Write),
+            &Stream::TlsHandShake(ref x) => x.get_ref().shutdown(Shutdown::Write),
+            &Stream::Unix(ref x) => x.shutdown(UnixShutdown::Write).map(|_| ()),
+            &Stream::Uninitialized => Ok(())
+        }
+    }
+
+    ///Read bytes without consuming them. Only plain streams can be peeked. This is used to look
+    ///at what a client sends before handing the stream to the TLS library, and to check idle
+    ///backends are still open.
+    pub fn peek(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let fd = match self {
+            &Stream::Tcp(ref x) => x.as_raw_fd(),
+            &Stream::Unix(ref x) => x.as_raw_fd(),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "only plain streams can be peeked"))
+        };
+        let ptr = buf.as_mut_ptr() as *mut c_void;
+        let r = unsafe{ recv(fd, ptr, buf.len(), MSG_PEEK) };
+        if r < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(r as usize)
+        }
+    }
+
+    ///Send `len` bytes of a file from `offset` without copying them through user space. Only
+    ///plain TCP streams can do this, TLS has to encrypt what it sends.
+    pub fn send_file(&self, file: &File, offset: u64, len: usize) -> io::Result<usize> {
+        let fd = match self {
+            &Stream::Tcp(ref x) => x.as_raw_fd(),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "only TCP streams can send files"))
         };
-        match a.accept(x) {
-            Ok(x) => Ok(Stream::Tls(x)),
-            Err(HandshakeError::Failure(e)) => Err(Fault::from(e)),
-            Err(HandshakeError::Interrupted(x)) => Ok(Stream::TlsHandShake(x))
+        let mut off = offset as off_t;
+        let r = unsafe{ sendfile(fd, file.as_raw_fd(), &mut off, len) };
+        if r < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(r as usize)
+        }
+    }
+
+    ///The local address of a network stream. This is the address a client connected too.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            &Stream::Tcp(ref x) => x.local_addr().ok(),
+            &Stream::Tls(ref x) => x.get_ref().local_addr().ok(),
+            &Stream::TlsHandShake(ref x) => x.get_ref().local_addr().ok(),
+            &Stream::Unix(_) => None,
+            &Stream::Uninitialized => None
+        }
+    }
+
+    ///What a finished TLS handshake agreed, the ALPN protocol if there was one and the version
+    ///as openssl names it. `None` for every other stream type.
+    pub fn negotiated(&self) -> Option<(Option<Vec<u8>>,&'static str)> {
+        match self {
+            &Stream::Tls(ref x) => {
+                let ssl = x.raw_stream().ssl();
+                Some((ssl.selected_alpn_protocol().map(|p| p.to_vec()), ssl.version()))
+            },
+            _ => None
+        }
+    }
+
+    ///Consumes self and starts a TLS handshake on a TCP stream. This is done by the worker
+    ///which owns the connection, so the event loop never spends time on handshakes. Other
+    ///stream types are returned unmodified.
+    ///
+    ///The code flow exists for the handshake to finish here. But this is unlikely
+    ///as a `WOULDBLOCK` error _should_ occur before that.
+    pub fn start_tls(self, a: &TlsAcceptor) -> Result<Stream,Fault> {
+        match self {
+            Stream::Tcp(x) => match a.accept(x) {
+                Ok(x) => Ok(Stream::Tls(x)),
+                Err(HandshakeError::Failure(e)) => Err(Fault::from(e)),
+                Err(HandshakeError::Interrupted(x)) => Ok(Stream::TlsHandShake(x))
+            },
+            x => Ok(x)
         }
     }
 }
@@ -0,0 +1,157 @@
+
+use super::http::route::{
+    Routes,
+    Target,
+    set_routes
+};
+use super::http::files::get_site;
+use super::http::policy::get_policy;
+use super::http::pages::get_pages;
+use super::http::compress::get_compress;
+use super::http::cache::get_cache;
+use super::http::rewrite::{
+    Rules,
+    set_rules
+};
+use super::pool::get_pool;
+use std::os::unix::net::{
+    UnixListener,
+    UnixStream
+};
+use std::io::prelude::*;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+///How long a control client has to send its command. Clients are served one at a time, so
+///this is kept short.
+const CONTROL_TIMEOUT_MS: u64 = 1000;
+
+///Largest command accepted, body included
+const MAX_COMMAND: u64 = 1 << 20;
+
+///Serve a client of the control socket. The client writes a command line followed by any
+///body, shuts down its write half, and reads back a single `ok ...` or `error: ...` line.
+///
+/// - `routes` replaces the HTTP routing table with the body. See `Routes::from_str`.
+/// - `rewrites` replaces the header rewrite rules with the body. See `Rules::from_str`.
+/// - `maintenance <pool> on|off` answers HTTP requests for a pool with a 503 page, or stops.
+/// - `purge <key>` drops cached responses, such as `example.com/index.html`, whichever route
+///   stored them. A key ending in `*` drops everything it's a prefix of.
+pub fn serve(mut s: UnixStream) {
+    let reply = match read_command(&mut s) {
+        Ok(text) => run(&text),
+        Err(e) => Err(format!("{}", e))
+    };
+    let line = match reply {
+        Ok(msg) => format!("ok {}\n", msg),
+        Err(msg) => format!("error: {}\n", msg)
+    };
+    let _ = s.write_all(line.as_bytes());
+}
+
+///Serve the control socket at `path` on a thread of its own, so a slow or stuck client never
+///holds up the event loop
+pub fn start(path: &Path) -> io::Result<thread::JoinHandle<()>> {
+    let l = UnixListener::bind(path)?;
+    thread::Builder::new().name("control".to_string()).spawn(move || {
+        for s in l.incoming() {
+            match s {
+                Ok(s) => serve(s),
+                //likely out of descriptors, wait rather than spin
+                Err(_) => thread::sleep(Duration::from_millis(CONTROL_TIMEOUT_MS))
+            };
+        }
+    })
+}
+
+///Read everything the client sends
+fn read_command(s: &mut UnixStream) -> io::Result<String> {
+    s.set_read_timeout(Some(Duration::from_millis(CONTROL_TIMEOUT_MS)))?;
+    s.set_write_timeout(Some(Duration::from_millis(CONTROL_TIMEOUT_MS)))?;
+    let mut text = String::new();
+    s.take(MAX_COMMAND).read_to_string(&mut text)?;
+    Ok(text)
+}
+
+///Carry out a command
+pub fn run(text: &str) -> Result<String,String> {
+    let (cmd, body) = match text.find('\n') {
+        Option::Some(i) => (text[..i].trim(), &text[i+1..]),
+        Option::None => (text.trim(), "")
+    };
+    match cmd {
+        "routes" => {
+            let routes = Routes::from_str(body).map_err(|e| format!("{}", e))?;
+            for r in routes.iter() {
+                match r.target {
+                    Target::Pool(i) if get_pool(i).is_none() => return Err(format!("no pool {}", i)),
+                    Target::Files(i) if get_site(i).is_none() => return Err(format!("no site {}", i)),
+                    _ => { }
+                };
+                match r.policy {
+                    Option::Some(i) if get_policy(i).is_none() => return Err(format!("no policy {}", i)),
+                    _ => { }
+                };
+                match r.pages {
+                    Option::Some(i) if get_pages(i).is_none() => return Err(format!("no error pages {}", i)),
+                    _ => { }
+                };
+                match r.compress {
+                    Option::Some(i) if get_compress(i).is_none() => return Err(format!("no compression {}", i)),
+                    _ => { }
+                };
+            }
+            let n = routes.iter().count();
+            set_routes(routes);
+            Ok(format!("{} routes", n))
+        },
+        "rewrites" => {
+            let rules = Rules::from_str(body).map_err(|e| format!("{}", e))?;
+            let n = rules.len();
+            set_rules(rules);
+            Ok(format!("{} rewrites", n))
+        },
+        x if x.starts_with("maintenance ") => {
+            let mut args = x[12..].split_whitespace();
+            let (pool, on) = match (args.next().map(usize::from_str), args.next(), args.next()) {
+                (Some(Ok(i)),Some("on"),None) => (i, true),
+                (Some(Ok(i)),Some("off"),None) => (i, false),
+                _ => return Err("expected `maintenance <pool> on|off`".to_string())
+            };
+            match get_pool(pool) {
+                Option::Some(p) => p.set_maintenance(on),
+                Option::None => return Err(format!("no pool {}", pool))
+            };
+            Ok(format!("pool {} maintenance {}", pool, if on { "on" } else { "off" }))
+        },
+        x if x.starts_with("purge ") => {
+            let key = x[6..].trim();
+            let n = if key.ends_with('*') {
+                get_cache().purge_prefix(&key[..key.len()-1])
+            } else if key.is_empty() {
+                return Err("expected `purge <key>`".to_string());
+            } else {
+                get_cache().purge(key)
+            };
+            Ok(format!("{} purged", n))
+        },
+        "" => Err("empty command".to_string()),
+        x => Err(format!("unknown command `{}`", x))
+    }
+}
+#[test]
+fn test_control_run() {
+    assert_eq!( run(""), Err("empty command".to_string()));
+    assert_eq!( run("reboot\n"), Err("unknown command `reboot`".to_string()));
+    assert_eq!( run("routes\n* bad 0\n"), Err("line 1: bad path `bad`".to_string()));
+    //routes must name pools which exist
+    assert_eq!( run("routes\n* * 999999\n"), Err("no pool 999999".to_string()));
+    assert_eq!( run("routes\n* * files:999999\n"), Err("no site 999999".to_string()));
+    assert_eq!( run("maintenance 999999 on\n"), Err("no pool 999999".to_string()));
+    assert!( run("maintenance 0 maybe\n").is_err() );
+    assert_eq!( run("purge nowhere.example/*\n"), Ok("0 purged".to_string()));
+    assert_eq!( run("rewrites\nrequest add X $nope\n"), Err("line 1: unknown variable `$nope`".to_string()));
+}
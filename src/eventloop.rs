@@ -9,6 +9,9 @@ use super::ipc::{
     Requests,
     Events,
     build_ipc,
+    build_waker,
+    reset_waker,
+    share_poll,
     send_event,
     send_futfillment,
 };
@@ -22,61 +25,55 @@ use super::mio::{
 use super::mio::tcp::{
     TcpListener,
     TcpStream,
-};
-use super::mio::deprecated::{
-    UnixStream,
-    UnixListener,
+    Shutdown,
 };
 use super::conn::stream::Stream;
-use std::str::FromStr;
+use super::worker::run as run_worker;
+use super::control::start as start_control;
+use super::pool::{
+    Pool,
+    add_pool,
+    get_pool,
+};
 use std::path::PathBuf;
 use std::io::prelude::*;
-use std::net::SocketAddr;
 use super::conn::fault::Fault;
-use super::native_tls::TlsAcceptor;
-use std::collections::BinaryHeap;
-
-///What we forward connections too
-pub enum Forward {
-    Unix(PathBuf),
-    Network(SocketAddr)
-}
-impl Forward {
-
-    ///Build a forwarding address. If it returns NONE then a invalid socket address was given AND
-    ///in the event it WAS a path. The file path points to nothing
-    pub fn build(s: &str) -> Option<Forward> {
-        match SocketAddr::from_str(s) {
-            Ok(x) => Some(Forward::Network(x)),
-            Err(_) => {
-                let p = PathBuf::from(s);
-                if p.exists() {
-                    Some(Forward::Unix(p))
-                } else {
-                    None
-                }
-            }
-        }
-    }
-
-    ///Setup up a new connection
-    pub fn connect(&self, p: &Poll, t: Token) -> Result<Stream,Fault> {
-        match self {
-            &Forward::Network(ref socket) => {
-                let tcp = TcpStream::connect(socket)?;
-                let stream = Stream::create_tcp(tcp,p,t)?;
-                Ok(stream)
-            },
-            &Forward::Unix(ref path) => {
-                let unix = UnixStream::connect(path)?;
-                let stream = Stream::create_unix(unix,p,t)?;
-                Ok(stream)
-            }
-        }
-    }
-}
+use super::listener::{
+    Listener,
+    ListenerID,
+    Mode,
+    add_listener,
+};
+use super::http::route::Target;
+use super::http::files::get_site;
+use super::config::{
+    Overload,
+    get_overload,
+    get_pending_connects,
+    get_accept_batch,
+};
+use super::metrics::{
+    Metric,
+    incr,
+};
+use std::collections::{
+    BinaryHeap,
+    VecDeque,
+};
+use std::io::{
+    Error as IOError,
+    ErrorKind,
+};
+use std::thread;
+use std::time::{
+    Duration,
+    Instant,
+};
 
-const SOCK: Token = Token(0);
+const WAKE: Token = Token(1);
+///Listening sockets get the tokens from here up to the first connection token
+const FIRST_SOCK: usize = 2;
+const MAX_LISTENERS: usize = 8;
 
 ///Intentionally returns 1 > index to convert for WorkID as 0 means unallocated/unused
 #[inline(always)]
@@ -100,11 +97,202 @@ fn test_smallest_index() {
     assert_eq!(4, find_smallest_index(&x));
 }
 
-///Construct the main loop
+const MIN_BACKOFF_MS: u64 = 10;
+const MAX_BACKOFF_MS: u64 = 1000;
+
+///Tracks if the listener is registered with the `Poll`. It is taken out when there are no
+///tokens to hand out, or when accepting is failing for reasons like EMFILE. In the latter case
+///it is put back after a delay that doubles each failure, and resets after a success.
+struct ListenState {
+    token: Token,
+    paused: bool,
+    backoff: Option<Instant>,
+    delay: u64
+}
+impl ListenState {
+
+    fn new(token: Token) -> ListenState {
+        ListenState {
+            token: token,
+            paused: false,
+            backoff: None,
+            delay: MIN_BACKOFF_MS
+        }
+    }
+
+    ///If the listener should be registered
+    #[inline(always)]
+    fn registered(&self) -> bool {
+        ! self.paused && self.backoff.is_none()
+    }
+
+    ///How long the event loop may block before the backoff expires
+    fn timeout(&self, now: Instant) -> Option<Duration> {
+        match self.backoff {
+            Option::None => None,
+            Option::Some(until) if until > now => Some(until.duration_since(now)),
+            Option::Some(_) => Some(Duration::from_millis(0))
+        }
+    }
+
+    ///Apply a state change, (de)registering the listener if it changed
+    fn update<F: FnOnce(&mut ListenState)>(&mut self, poll: &Poll, sock: &TcpListener, f: F) {
+        let was = self.registered();
+        f(self);
+        let now = self.registered();
+        if was && !now {
+            let _ = poll.deregister(sock);
+        } else if !was && now {
+            if poll.register(sock,self.token,Ready::readable(),PollOpt::level()).is_err() {
+                //try again shortly
+                self.backoff = Some(Instant::now() + Duration::from_millis(self.delay));
+            }
+        }
+    }
+
+    ///Stop listening as there are no tokens
+    fn pause(&mut self, poll: &Poll, sock: &TcpListener) {
+        self.update(poll, sock, |s| s.paused = true);
+    }
+
+    ///Tokens are avalible again
+    fn unpause(&mut self, poll: &Poll, sock: &TcpListener) {
+        self.update(poll, sock, |s| s.paused = false);
+    }
+
+    ///Accepting failed, stop listening for a while
+    fn back_off(&mut self, poll: &Poll, sock: &TcpListener, now: Instant) {
+        self.update(poll, sock, |s| {
+            s.backoff = Some(now + Duration::from_millis(s.delay));
+            s.delay = ::std::cmp::min(s.delay * 2, MAX_BACKOFF_MS);
+        });
+    }
+
+    ///Start listening again if the backoff is over
+    fn tick(&mut self, poll: &Poll, sock: &TcpListener, now: Instant) {
+        match self.backoff {
+            Option::Some(until) if until <= now => self.update(poll, sock, |s| s.backoff = None),
+            _ => { }
+        };
+    }
+
+    ///An accept worked so the next failure starts with a short delay
+    #[inline(always)]
+    fn accepted(&mut self) {
+        self.delay = MIN_BACKOFF_MS;
+    }
+}
+#[test]
+fn test_listen_state() {
+    let now = Instant::now();
+    let mut s = ListenState::new(Token(FIRST_SOCK));
+    assert!( s.registered() );
+    assert_eq!( s.timeout(now), None);
+
+    s.backoff = Some(now + Duration::from_millis(s.delay));
+    assert!( ! s.registered() );
+    assert_eq!( s.timeout(now), Some(Duration::from_millis(MIN_BACKOFF_MS)));
+    assert_eq!( s.timeout(now + Duration::from_secs(1)), Some(Duration::from_millis(0)));
+
+    s.backoff = None;
+    s.paused = true;
+    assert!( ! s.registered() );
+    assert_eq!( s.timeout(now), None);
+}
+
+///Accept errors which are about a single client, rather than the process or system being
+///out of something. These don't cause the listener to back off.
+#[inline(always)]
+fn transient_accept_error(kind: ErrorKind) -> bool {
+    match kind {
+        ErrorKind::Interrupted |
+        ErrorKind::ConnectionAborted |
+        ErrorKind::ConnectionReset |
+        ErrorKind::TimedOut => true,
+        _ => false
+    }
+}
+
+///A fatal `internal_error` TLS alert record. It is valid before any handshake messages have
+///been exchanged, so clients turned away while overloaded get a clean failure.
+const OVERLOAD_ALERT: [u8;7] = [0x15, 0x03, 0x01, 0x00, 0x02, 0x02, 0x50];
+
+///Sent instead of the alert to clients of listeners speaking plain HTTP
+const OVERLOAD_503: &'static [u8] = b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n";
+
+///Turn away a client there is no token for. Best effort, the socket is non-blocking and the
+///alert or 503 is simply lost if it can't be written right away.
+fn reject(mut x: TcpStream, plain_http: bool) {
+    if plain_http {
+        let _ = x.write(OVERLOAD_503);
+    } else {
+        let _ = x.write(&OVERLOAD_ALERT);
+    }
+    let _ = x.shutdown(Shutdown::Both);
+}
+
+///A socket clients connect to
+struct Socket {
+    sock: TcpListener,
+    id: ListenerID,
+    ///Clients speak HTTP without TLS, so are turned away with a 503 rather than an alert
+    plain_http: bool,
+    ///If the socket is registered with the poll
+    state: ListenState
+}
+
+///Connect to a backend on behalf of a worker, and tell the worker how it went. The token is
+///returned to the heap on failure. The worker's load counts the connection until it's closed.
+fn connect_backend(f: &Pool, poll: &Poll, heap: &mut BinaryHeap<Token>, t: Token, w: WorkerID, workload: &mut [usize]) {
+    //connect to the stream (and register it)
+    let stream = match f.connect(poll,t) {
+        Ok(s) => s,
+        Err(e) => {
+            //TODO log this event
+            heap.push(t);
+            send_futfillment(w,Events::Failure);
+            return;
+        }
+    };
+    //assign it to a client
+    match assign_stream(&t,stream,w,None) {
+        Ok(_) => { },
+        Err(e) => { 
+            //TODO log this event
+            heap.push(t);
+            send_futfillment(w,Events::Failure);
+            return;
+        }
+    };
+    //alert client work is done
+    send_futfillment(w,Events::Open(t));
+    workload[w.0-1] += 1;
+}
+
+///Check a listener only sends clients to pools and sites which exist, as the control socket
+///does for routes. Pools must be added first.
+fn check_listener(l: &Listener) -> Result<(),Fault> {
+    let missing = |what: &str, i: usize| Fault::OS(IOError::new(ErrorKind::InvalidInput, format!("no {} {}", what, i)));
+    match l.mode {
+        Mode::Http => match l.http.target {
+            Target::Pool(i) if get_pool(i).is_none() => return Err(missing("pool", i)),
+            Target::Files(i) if get_site(i).is_none() => return Err(missing("site", i)),
+            _ => { }
+        },
+        Mode::L4 | Mode::Passthrough => for i in l.sni.pools() {
+            if get_pool(i).is_none() {
+                return Err(missing("pool", i));
+            }
+        },
+        Mode::Redirect => { }
+    };
+    Ok(())
+}
+
+///Construct the main loop. There may be up to `MAX_LISTENERS` listeners.
 pub fn main_loop(
-    to: Vec<Forward>,
-    accept: TlsAcceptor,
-    listen: SocketAddr,
+    to: Vec<Pool>,
+    listen: Vec<Listener>,
     cli: PathBuf,
     worker_count: usize
 ) -> Result<(),Fault>
@@ -113,6 +301,23 @@ pub fn main_loop(
     build_ipc(worker_count);
     build_connections();
 
+    //workers need to know how to talk to backends
+    for pool in to {
+        add_pool(pool);
+    }
+
+    //workers do the handshakes, so they need the listeners' settings too
+    if listen.len() > MAX_LISTENERS {
+        return Err(Fault::OS(IOError::new(ErrorKind::InvalidInput, format!("at most {} listeners", MAX_LISTENERS))));
+    }
+    let mut listeners = Vec::with_capacity(listen.len());
+    for l in listen {
+        check_listener(&l)?;
+        let addr = l.addr;
+        let plain_http = l.acceptor.is_none() && (l.mode == Mode::Http || l.mode == Mode::Redirect);
+        listeners.push((addr, plain_http, add_listener(l)));
+    }
+
     //keep track of worker thread workload
     let mut workload = Vec::<usize>::with_capacity(worker_count);
     for _ in 0..worker_count {
@@ -121,6 +326,10 @@ pub fn main_loop(
 
     //allocate room to hear about worker requests
     let mut incoming = Vec::<PresentRequests>::with_capacity(256);
+
+    //backend connections waiting on a free token
+    let mut pending = VecDeque::<(WorkerID,usize)>::with_capacity(256);
+
     
     //allocate room for events
     let mut events = EventBuff::with_capacity(256);
@@ -131,75 +340,145 @@ pub fn main_loop(
         heap.push(Token(t));
     }
 
-    //build the epoll
-    let poll = Poll::new()?;
+    //build the epoll, workers change what their connections are polled for
+    let poll = share_poll(Poll::new()?);
 
-    //listen for CLI args
-    let cmd = UnixListener::bind(&cli)?;
+    //listen for CLI args, on a thread of its own
+    let _control = start_control(&cli)?;
     
-    //listen for connects
-    let sock = TcpListener::bind(&listen)?;
-   
-    //register listeners
-    poll.register(&sock,SOCK,Ready::readable(),PollOpt::level())?;
+    //listen for connects, and register the listeners
+    let mut socks = Vec::<Socket>::with_capacity(listeners.len());
+    for (i, (addr, plain_http, id)) in listeners.into_iter().enumerate() {
+        let sock = TcpListener::bind(&addr)?;
+        let state = ListenState::new(Token(FIRST_SOCK + i));
+        poll.register(&sock,state.token,Ready::readable(),PollOpt::level())?;
+        socks.push(Socket {
+            sock: sock,
+            id: id,
+            plain_http: plain_http,
+            state: state
+        });
+    }
+
+    //workers wake the loop when they send requests
+    let _waker = build_waker(poll, WAKE);
+
+    //start the workers, they're numbered from 1
+    for id in 1..=worker_count {
+        thread::spawn(move || run_worker(id));
+    }
 
     //main loop
     loop {
     
-        //listen for events
-        poll.poll(&mut events, None);
+        //listen for events, waking up in time to end any backoff
+        let now = Instant::now();
+        let timeout = socks.iter().filter_map(|s| s.state.timeout(now)).min();
+        poll.poll(&mut events, timeout);
+        let now = Instant::now();
+        for s in socks.iter_mut() {
+            s.state.tick(poll, &s.sock, now);
+        }
 
         //loop over events
         for event in events.iter().filter_map(send_event) {
             match event.token() {
 
                 //extern listener events
-                SOCK => {
+                Token(t) if t >= FIRST_SOCK && t < FIRST_SOCK + socks.len() => {
+                    let s = &mut socks[t - FIRST_SOCK];
+                    for _ in 0..get_accept_batch() {
 
-                    //see if there is a token avalible
-                    let new_token = match heap.pop() {
-                        Option::None => continue,
-                        Option::Some(t) => t
-                    };
+                        //see if there is a token avalible
+                        let new_token = match heap.pop() {
+                            Option::None => {
+                                incr(Metric::OverloadAccept);
+                                match get_overload() {
+                                    //resumed when a token is returned
+                                    Overload::Pause => {
+                                        s.state.pause(poll, &s.sock);
+                                        break;
+                                    },
+                                    Overload::Reject => match s.sock.accept() {
+                                        Ok((x,_)) => {
+                                            reject(x, s.plain_http);
+                                            continue;
+                                        },
+                                        Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+                                        Err(ref e) if transient_accept_error(e.kind()) => continue,
+                                        Err(_) => {
+                                            //out of descriptors, the listener stays readable
+                                            //so it must be taken out or this spins
+                                            incr(Metric::AcceptBackoff);
+                                            s.state.back_off(poll, &s.sock, Instant::now());
+                                            break;
+                                        }
+                                    }
+                                };
+                            },
+                            Option::Some(t) => t
+                        };
 
-                    //attempt to get the connection
-                    let new_conn = match sock.accept() {
-                        Ok((x,_)) => x,
-                        Err(e) => {
-                            //TODO LOGGING
-                            heap.push(new_token);
-                            continue;
-                        }
-                    };
+                        //attempt to get the connection
+                        let (new_conn,peer) = match s.sock.accept() {
+                            Ok((x,addr)) => {
+                                s.state.accepted();
+                                (x,addr)
+                            },
+                            Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
+                                //listener is drained
+                                heap.push(new_token);
+                                break;
+                            },
+                            Err(ref e) if transient_accept_error(e.kind()) => {
+                                //TODO LOGGING
+                                heap.push(new_token);
+                                continue;
+                            },
+                            Err(e) => {
+                                //TODO LOGGING
+                                //likely EMFILE/ENFILE, nothing will accept for a while
+                                incr(Metric::AcceptBackoff);
+                                heap.push(new_token);
+                                s.state.back_off(poll, &s.sock, Instant::now());
+                                break;
+                            }
+                        };
 
-                    //start TLS handshake + register with epoll
-                    let new_stream = match Stream::create_tls(new_conn,&poll, new_token, &accept) {
-                        Ok(x) => x,
-                        Err(e) => {
-                            //TODO logging
-                            heap.push(new_token);
-                            continue;
-                        }
-                    };
+                        //register with epoll, the worker starts the TLS handshake
+                        let new_stream = match Stream::create_tcp(new_conn,poll, new_token) {
+                            Ok(x) => x,
+                            Err(e) => {
+                                //TODO logging
+                                heap.push(new_token);
+                                continue;
+                            }
+                        };
                     
-                    //assign to worker with lightest load
-                    let i = find_smallest_index(workload.as_slice());
-                    let w = WorkerID(i);
+                        //assign to worker with lightest load
+                        let i = find_smallest_index(workload.as_slice());
+                        let w = WorkerID(i);
                 
-                    //lock stream so only 1 thing can read it
-                    match assign_stream(&new_token, new_stream, w) {
-                        Ok(_) => { },
-                        Err(e) => {
-                            //TODO logging
-                            heap.push(new_token);
-                            continue;
-                        }
-                    };
-                    //alert worker of new connection
-                    send_futfillment(w, Events::Open(new_token));
+                        //lock stream so only 1 thing can read it
+                        match assign_stream(&new_token, new_stream, w, Some(peer)) {
+                            Ok(_) => { },
+                            Err(e) => {
+                                //TODO logging
+                                heap.push(new_token);
+                                continue;
+                            }
+                        };
+                        //alert worker of new connection
+                        send_futfillment(w, Events::Accept(new_token, s.id));
+
+                        //mark the worker has a larger load
+                        workload[i-1] += 1;
+                    }
+                },
 
-                    //mark the worker has a larger load
-                    workload[i-1] += 1;
+                //a worker has sent requests, they're read below
+                WAKE => {
+                    reset_waker();
                 },
                 _ => {
                     //TODO logging
@@ -214,37 +493,43 @@ pub fn main_loop(
             match &req.1 {
                 //worker wants a new connection
                 &Requests::New(i) => {
-                    if i >= to.len() {
-                        //TODO log this event
-                        continue;
-                    }
-                    //get a token
-                    let t = match heap.pop() {
-                        Option::None => unreachable!(),
-                        Option::Some(x) => x,
-                    };
-                    //connect to the stream (and register it)
-                    let stream = match to[i].connect(&poll,t) {
-                        Ok(s) => s,
-                        Err(e) => {
+                    let pool = match get_pool(i) {
+                        Option::Some(p) => p,
+                        Option::None => {
                             //TODO log this event
-                            heap.push(t);
+                            //the worker still needs an answer, they're matched up in order
                             send_futfillment(req.0,Events::Failure);
                             continue;
                         }
                     };
-                    //assign it to a client
-                    match assign_stream(&t,stream,req.0) {
-                        Ok(_) => { },
-                        Err(e) => { 
-                            //TODO log this event
-                            heap.push(t);
-                            send_futfillment(req.0,Events::Failure);
-                            continue;
+                    //get a token, or wait for one. Workers match answers to their requests in
+                    //order, so nothing goes ahead of those already waiting.
+                    let token = if pending.is_empty() {
+                        heap.pop()
+                    } else {
+                        None
+                    };
+                    match token {
+                        Option::Some(t) => connect_backend(pool, poll, &mut heap, t, req.0, &mut workload),
+                        Option::None => {
+                            incr(Metric::OverloadConnect);
+                            if pending.len() < get_pending_connects() {
+                                pending.push_back((req.0,i));
+                            } else {
+                                incr(Metric::OverloadDropped);
+                                //the worker's oldest waiting request is the one refused, so
+                                //the answer still matches up
+                                match pending.iter().position(|x| x.0 == req.0) {
+                                    Option::Some(j) => {
+                                        pending.remove(j);
+                                        pending.push_back((req.0,i));
+                                    },
+                                    Option::None => { }
+                                };
+                                send_futfillment(req.0,Events::Failure);
+                            }
                         }
                     };
-                    //alert client work is done
-                    send_futfillment(req.0,Events::Open(t));
                 },
                 //worker has closed a connection
                 &Requests::Close(t) => {
@@ -257,5 +542,28 @@ pub fn main_loop(
             };
         }
         incoming.clear();
+
+        //tokens may have been returned, service waiting connections first
+        while ! heap.is_empty() {
+            let (w,i) = match pending.pop_front() {
+                Option::None => break,
+                Option::Some(x) => x
+            };
+            let t = match heap.pop() {
+                Option::None => unreachable!(),
+                Option::Some(x) => x
+            };
+            match get_pool(i) {
+                Option::Some(pool) => connect_backend(pool, poll, &mut heap, t, w, &mut workload),
+                Option::None => unreachable!()
+            };
+        }
+
+        //start listening again
+        if ! heap.is_empty() {
+            for s in socks.iter_mut() {
+                s.state.unpause(poll, &s.sock);
+            }
+        }
     }
 }
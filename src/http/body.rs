@@ -0,0 +1,355 @@
+
+use super::{
+    Span,
+    Header,
+    Version,
+    HttpError
+};
+use super::parse::{
+    RequestHead,
+    ResponseHead,
+    trim
+};
+
+///How the end of a message body is found
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum Body {
+    ///There is no body
+    None,
+    ///Exactly this many bytes
+    Length(u64),
+    ///Chunked transfer coding
+    Chunked,
+    ///Everything until the connection closes. Only responses can do this.
+    Close
+}
+
+///Parse a Content-Length value. Only a single length is allowed, repeats are rejected even
+///when they agree, as not every server reads them the same way.
+fn content_length(headers: &[Header], buf: &[u8]) -> Result<Option<u64>,HttpError> {
+    let mut len: Option<u64> = None;
+    for h in headers.iter().filter(|h| h.is(buf, "content-length")) {
+        if len.is_some() {
+            return Err(HttpError::DuplicateContentLength);
+        }
+        let x = h.value.get(buf);
+        if x.contains(&b',') {
+            return Err(HttpError::DuplicateContentLength);
+        }
+        if x.is_empty() || ! x.iter().all(|b| (*b as char).is_ascii_digit()) || x.len() > 18 {
+            return Err(HttpError::BadContentLength);
+        }
+        len = Some(x.iter().fold(0u64, |acc,b| acc*10 + (*b - b'0') as u64));
+    }
+    Ok(len)
+}
+
+///If the last transfer coding is chunked. `None` if there is no Transfer-Encoding.
+fn chunked(headers: &[Header], buf: &[u8]) -> Option<bool> {
+    let mut last: Option<&[u8]> = None;
+    for h in headers.iter().filter(|h| h.is(buf, "transfer-encoding")) {
+        for x in h.value.get(buf).split(|b| *b == b',') {
+            last = Some(trim(x));
+        }
+    }
+    last.map(|x| x.eq_ignore_ascii_case(b"chunked"))
+}
+
+///Find how a request's body is delimited. A request which uses a transfer coding other than
+///chunked last can't be delimited, so is an error. So is one with both Transfer-Encoding and
+///Content-Length, which is how requests are smuggled past proxies.
+pub fn request_body(head: &RequestHead, buf: &[u8]) -> Result<Body,HttpError> {
+    let length = content_length(&head.headers, buf)?;
+    match chunked(&head.headers, buf) {
+        Option::Some(_) if length.is_some() => return Err(HttpError::LengthAndChunked),
+        Option::Some(true) if head.version == Version::Http11 => return Ok(Body::Chunked),
+        Option::Some(_) => return Err(HttpError::BadTransferEncoding),
+        Option::None => { }
+    };
+    match length {
+        Option::Some(0) | Option::None => Ok(Body::None),
+        Option::Some(n) => Ok(Body::Length(n))
+    }
+}
+
+///Find how a response's body is delimited. `head_request` is if the request was a HEAD, whose
+///responses never have a body whatever their headers say.
+pub fn response_body(head: &ResponseHead, buf: &[u8], head_request: bool) -> Result<Body,HttpError> {
+    if head_request || head.status < 200 || head.status == 204 || head.status == 304 {
+        return Ok(Body::None);
+    }
+    match chunked(&head.headers, buf) {
+        Option::Some(true) => return Ok(Body::Chunked),
+        Option::Some(false) => return Ok(Body::Close),
+        Option::None => { }
+    };
+    match content_length(&head.headers, buf)? {
+        Option::Some(0) => Ok(Body::None),
+        Option::Some(n) => Ok(Body::Length(n)),
+        Option::None => Ok(Body::Close)
+    }
+}
+
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+enum ChunkState {
+    ///Reading the hex chunk size
+    Size,
+    ///Skipping a chunk extension
+    Ext,
+    ///Expecting the LF ending the size line
+    SizeLF,
+    ///Reading this many data bytes
+    Data(u64),
+    ///Expecting the CRLF after data
+    DataCR,
+    DataLF,
+    ///Start of a trailer line, or the final CRLF
+    Trailer,
+    ///Inside a trailer field
+    TrailerLine,
+    TrailerLF,
+    EndLF,
+    Done
+}
+
+///Incremental chunked transfer coding decoder. Bytes can be fed in any sized pieces.
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub struct ChunkDecoder {
+    state: ChunkState,
+    size: u64,
+    digits: u8
+}
+
+///Most hex digits allowed in a chunk size. 15 keeps the size well inside a u64.
+const MAX_CHUNK_DIGITS: u8 = 15;
+
+impl ChunkDecoder {
+
+    pub fn new() -> ChunkDecoder {
+        ChunkDecoder {
+            state: ChunkState::Size,
+            size: 0,
+            digits: 0
+        }
+    }
+
+    #[inline(always)]
+    pub fn is_done(&self) -> bool {
+        self.state == ChunkState::Done
+    }
+
+    ///Decode as much of `buf` as possible. Returns how many bytes were part of the chunked
+    ///body. The data inside the chunks is pushed to `data` as spans of `buf`. Once the final
+    ///chunk and trailers are read nothing more is consumed.
+    pub fn decode(&mut self, buf: &[u8], data: &mut Vec<Span>) -> Result<usize,HttpError> {
+        let mut i = 0;
+        while i < buf.len() {
+            let b = buf[i];
+            self.state = match self.state {
+                ChunkState::Done => return Ok(i),
+                ChunkState::Size => match (b as char).to_digit(16) {
+                    Option::Some(d) => {
+                        if self.digits == MAX_CHUNK_DIGITS {
+                            return Err(HttpError::BadChunk);
+                        }
+                        self.digits += 1;
+                        self.size = (self.size << 4) | d as u64;
+                        ChunkState::Size
+                    },
+                    Option::None if self.digits == 0 => return Err(HttpError::BadChunk),
+                    Option::None => match b {
+                        b';' => ChunkState::Ext,
+                        b'\r' => ChunkState::SizeLF,
+                        _ => return Err(HttpError::BadChunk)
+                    }
+                },
+                ChunkState::Ext => match b {
+                    b'\r' => ChunkState::SizeLF,
+                    b'\t' => ChunkState::Ext,
+                    b if b < 0x20 || b == 0x7f => return Err(HttpError::BadChunk),
+                    _ => ChunkState::Ext
+                },
+                ChunkState::SizeLF => match b {
+                    b'\n' if self.size == 0 => ChunkState::Trailer,
+                    b'\n' => ChunkState::Data(self.size),
+                    _ => return Err(HttpError::BadChunk)
+                },
+                ChunkState::Data(left) => {
+                    let n = ::std::cmp::min(left, (buf.len() - i) as u64) as usize;
+                    data.push(Span{ start: i, end: i+n });
+                    i += n;
+                    if left - n as u64 == 0 {
+                        self.state = ChunkState::DataCR;
+                    } else {
+                        self.state = ChunkState::Data(left - n as u64);
+                    }
+                    continue;
+                },
+                ChunkState::DataCR => match b {
+                    b'\r' => ChunkState::DataLF,
+                    _ => return Err(HttpError::BadChunk)
+                },
+                ChunkState::DataLF => match b {
+                    b'\n' => {
+                        self.size = 0;
+                        self.digits = 0;
+                        ChunkState::Size
+                    },
+                    _ => return Err(HttpError::BadChunk)
+                },
+                ChunkState::Trailer => match b {
+                    b'\r' => ChunkState::EndLF,
+                    b'\n' => return Err(HttpError::BadChunk),
+                    _ => ChunkState::TrailerLine
+                },
+                ChunkState::TrailerLine => match b {
+                    b'\r' => ChunkState::TrailerLF,
+                    b'\n' => return Err(HttpError::BadChunk),
+                    _ => ChunkState::TrailerLine
+                },
+                ChunkState::TrailerLF => match b {
+                    b'\n' => ChunkState::Trailer,
+                    _ => return Err(HttpError::BadChunk)
+                },
+                ChunkState::EndLF => match b {
+                    b'\n' => ChunkState::Done,
+                    _ => return Err(HttpError::BadChunk)
+                }
+            };
+            i += 1;
+        }
+        Ok(i)
+    }
+}
+
+///Tracks a body as it passes through
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum Framing {
+    ///This many bytes are left
+    Length(u64),
+    Chunked(ChunkDecoder),
+    ///Runs until the connection closes
+    Close,
+    Done
+}
+impl Framing {
+
+    pub fn new(b: Body) -> Framing {
+        match b {
+            Body::None => Framing::Done,
+            Body::Length(n) => Framing::Length(n),
+            Body::Chunked => Framing::Chunked(ChunkDecoder::new()),
+            Body::Close => Framing::Close
+        }
+    }
+
+    #[inline(always)]
+    pub fn is_done(&self) -> bool {
+        match self {
+            &Framing::Done => true,
+            &Framing::Chunked(ref c) => c.is_done(),
+            _ => false
+        }
+    }
+
+    ///Work out how much of `buf` belongs to the body. The data is pushed to `data` as spans of
+    ///`buf`, for chunked bodies this skips the chunk framing. Returns the bytes consumed.
+    pub fn advance(&mut self, buf: &[u8], data: &mut Vec<Span>) -> Result<usize,HttpError> {
+        let (n,next) = match self {
+            &mut Framing::Done => (0, Framing::Done),
+            &mut Framing::Close => (buf.len(), Framing::Close),
+            &mut Framing::Length(left) => {
+                let n = ::std::cmp::min(left, buf.len() as u64) as usize;
+                if left == n as u64 {
+                    (n, Framing::Done)
+                } else {
+                    (n, Framing::Length(left - n as u64))
+                }
+            },
+            &mut Framing::Chunked(ref mut c) => {
+                let n = c.decode(buf, data)?;
+                return Ok(n);
+            }
+        };
+        if n > 0 {
+            data.push(Span{ start: 0, end: n });
+        }
+        *self = next;
+        Ok(n)
+    }
+}
+#[test]
+fn test_chunk_decoder() {
+    let body = b"4;ext=1\r\nWiki\r\n5\r\npedia\r\nE\r\n in\r\n\r\nchunks.\r\n0\r\nTrailer: x\r\n\r\nNEXT";
+    let expect = b"Wikipedia in\r\n\r\nchunks.";
+
+    //all at once
+    let mut c = ChunkDecoder::new();
+    let mut data = Vec::new();
+    let n = c.decode(body, &mut data).unwrap();
+    assert!( c.is_done() );
+    assert_eq!( &body[n..], b"NEXT");
+    let out: Vec<u8> = data.iter().flat_map(|s| s.get(body).iter().cloned()).collect();
+    assert_eq!( out.as_slice(), &expect[..]);
+
+    //a byte at a time
+    let mut c = ChunkDecoder::new();
+    let mut out = Vec::new();
+    let mut used = 0;
+    for i in 0..body.len() {
+        let mut data = Vec::new();
+        used += c.decode(&body[i..i+1], &mut data).unwrap();
+        for s in data {
+            out.extend_from_slice(s.get(&body[i..i+1]));
+        }
+    }
+    assert_eq!( used, n);
+    assert_eq!( out.as_slice(), &expect[..]);
+
+    let bad: &[&[u8]] = &[
+        b"x\r\n",
+        b"4\nWiki\r\n",
+        b"4\r\nWikiX\r\n",
+        b"1000000000000000\r\n",
+        b"0\r\nTrailer\n",
+    ];
+    for b in bad {
+        assert!( ChunkDecoder::new().decode(b, &mut Vec::new()).is_err() );
+    }
+}
+#[test]
+fn test_body_framing() {
+    use super::{Limits,Status};
+    use super::parse::{parse_request,parse_response};
+
+    let limits = Limits::new();
+    let req = |b: &[u8]| match parse_request(b, &limits).unwrap() {
+        Status::Done(h) => request_body(&h, b),
+        Status::More => panic!("incomplete")
+    };
+    assert_eq!( req(b"GET / HTTP/1.1\r\n\r\n"), Ok(Body::None));
+    assert_eq!( req(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\n"), Ok(Body::Length(10)));
+    assert_eq!( req(b"POST / HTTP/1.1\r\nContent-Length: 10, 10\r\n\r\n"), Err(HttpError::DuplicateContentLength));
+    assert_eq!( req(b"POST / HTTP/1.1\r\nContent-Length: 10\r\nContent-Length: 10\r\n\r\n"), Err(HttpError::DuplicateContentLength));
+    assert_eq!( req(b"POST / HTTP/1.1\r\nContent-Length: 10\r\nContent-Length: 11\r\n\r\n"), Err(HttpError::DuplicateContentLength));
+    assert_eq!( req(b"POST / HTTP/1.1\r\nContent-Length: 3\r\nTransfer-Encoding: chunked\r\n\r\n"), Err(HttpError::LengthAndChunked));
+    assert_eq!( req(b"POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n"), Err(HttpError::BadContentLength));
+    assert_eq!( req(b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n"), Ok(Body::Chunked));
+    assert_eq!( req(b"POST / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n"), Err(HttpError::BadTransferEncoding));
+
+    let resp = |b: &[u8], head_req: bool| match parse_response(b, &limits).unwrap() {
+        Status::Done(h) => response_body(&h, b, head_req),
+        Status::More => panic!("incomplete")
+    };
+    assert_eq!( resp(b"HTTP/1.1 200 OK\r\n\r\n", false), Ok(Body::Close));
+    assert_eq!( resp(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n", true), Ok(Body::None));
+    assert_eq!( resp(b"HTTP/1.1 304 Not Modified\r\nContent-Length: 5\r\n\r\n", false), Ok(Body::None));
+    assert_eq!( resp(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n", false), Ok(Body::Chunked));
+
+    let mut f = Framing::new(Body::Length(3));
+    let mut data = Vec::new();
+    assert_eq!( f.advance(b"ab", &mut data), Ok(2));
+    assert!( ! f.is_done() );
+    assert_eq!( f.advance(b"cdef", &mut data), Ok(1));
+    assert!( f.is_done() );
+}
@@ -0,0 +1,524 @@
+
+use super::head::{
+    Headers,
+    Request,
+    Response
+};
+use super::files::Reply;
+use super::route::normalize_path;
+use super::date;
+use super::super::config::get_cache_budget;
+use super::super::metrics::{
+    Metric,
+    incr
+};
+use std::collections::{
+    BTreeMap,
+    HashMap
+};
+use std::sync::{
+    Arc,
+    Mutex,
+    MutexGuard
+};
+
+///Headers which only mean something on the connection they arrived on
+const HOP_BY_HOP: [&'static str;6] = ["Connection", "Keep-Alive", "Transfer-Encoding", "Upgrade", "Proxy-Connection", "Trailer"];
+
+///Value of a `Cache-Control` directive. `Some("")` for one without a value.
+fn directive(h: &Headers, name: &str) -> Option<String> {
+    for d in h.get_all("Cache-Control").flat_map(|v| v.split(',')) {
+        let mut kv = d.splitn(2, '=');
+        let k = kv.next().unwrap_or("").trim();
+        if k.eq_ignore_ascii_case(name) {
+            return Some(kv.next().unwrap_or("").trim().trim_matches('"').to_string());
+        }
+    }
+    None
+}
+
+///Seconds from a directive such as `max-age`
+#[inline(always)]
+fn seconds(h: &Headers, name: &str) -> Option<u64> {
+    directive(h, name).and_then(|x| x.parse::<u64>().ok())
+}
+
+///The key for a request, its host and normalized target then `route`, if it may be answered
+///from the cache. A request without a `Host` is keyed by `scope` instead, such as the server
+///name it was sent to, so it can't be given another site's responses. `route` is where the
+///request was routed, so requests for the same URL sent to different backends, say by a
+///header, don't share responses. Only GET and HEAD requests without credentials, and which
+///don't forbid storing, are looked up.
+pub fn key(req: &Request, scope: &str, route: &str) -> Option<String> {
+    if req.method != "GET" && req.method != "HEAD" {
+        return None;
+    }
+    if req.headers.contains("Authorization") || directive(&req.headers, "no-store").is_some() {
+        return None;
+    }
+    let host = match req.headers.get("Host").map(|x| x.trim()) {
+        Option::Some(h) if ! h.is_empty() => h.to_ascii_lowercase(),
+        _ => scope.to_ascii_lowercase()
+    };
+    let (path, query) = match req.target.find('?') {
+        Option::Some(i) => req.target.split_at(i),
+        Option::None => (req.target.as_str(), "")
+    };
+    //a target can't hold a space, so the route can't run into it
+    Some(format!("{}{}{} {}", host, normalize_path(path), query, route))
+}
+
+///If a response was marked as fit for any client, even one which sent a cookie
+#[inline(always)]
+fn is_public(resp: &Response) -> bool {
+    directive(&resp.headers, "public").is_some()
+}
+
+///If the client insists on the backend being asked
+fn wants_revalidation(req: &Request) -> bool {
+    directive(&req.headers, "no-cache").is_some() ||
+        seconds(&req.headers, "max-age") == Some(0) ||
+        req.headers.get_all("Pragma").any(|v| v.trim().eq_ignore_ascii_case("no-cache"))
+}
+
+///How long a response may be served for, if it may be stored at all. Only 200 responses, not
+///marked `no-store` or `private`, not setting cookies, and with a lifetime or a validator to
+///check them with.
+pub fn lifetime(resp: &Response, now: u64) -> Option<u64> {
+    let h = &resp.headers;
+    if resp.status != 200 ||
+        directive(h, "no-store").is_some() ||
+        directive(h, "private").is_some() ||
+        h.contains("Set-Cookie") ||
+        h.get_all("Vary").flat_map(|v| v.split(',')).any(|x| x.trim() == "*") {
+        return None;
+    }
+    let validated = h.contains("ETag") || h.contains("Last-Modified");
+    let age = h.get("Age").and_then(|x| x.trim().parse::<u64>().ok()).unwrap_or(0);
+    let explicit = if directive(h, "no-cache").is_some() {
+        Some(0)
+    } else {
+        match (seconds(h, "s-maxage"), seconds(h, "max-age"), h.get("Expires")) {
+            (Option::Some(x),_,_) | (_,Option::Some(x),_) => Some(x),
+            (_,_,Option::Some(e)) => {
+                //an Expires which can't be read is in the past
+                let date = h.get("Date").and_then(date::parse).unwrap_or(now);
+                Some(date::parse(e).map(|e| e.saturating_sub(date)).unwrap_or(0))
+            },
+            _ => None
+        }
+    };
+    match explicit {
+        Option::Some(x) if x > age => Some(x - age),
+        Option::Some(_) | Option::None if validated => Some(0),
+        _ => None
+    }
+}
+
+///A stored response, or one to send from the cache. The body is shared with the cache, so it
+///isn't copied while the cache is locked.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct Stored {
+    pub head: Response,
+    pub body: Arc<Vec<u8>>
+}
+impl Stored {
+
+    ///Answer with it
+    pub fn into_reply(self) -> Reply {
+        let body = match Arc::try_unwrap(self.body) {
+            Ok(b) => b,
+            Err(shared) => (*shared).clone()
+        };
+        Reply { head: self.head, body: body, file: None }
+    }
+}
+
+///A response in the cache
+struct Entry {
+    head: Response,
+    body: Arc<Vec<u8>>,
+    ///When it was stored, less any age it already had
+    stored: u64,
+    ///Served without asking the backend until then
+    expires: u64,
+    ///Position in the LRU order
+    tick: u64
+}
+impl Entry {
+
+    ///Make an entry for a response the backend sent. `lifetime` is from `lifetime`.
+    fn new(mut head: Response, body: Arc<Vec<u8>>, lifetime: u64, now: u64) -> Entry {
+        for h in HOP_BY_HOP.iter() {
+            head.headers.remove(h);
+        }
+        head.headers.set("Content-Length", &body.len().to_string());
+        let age = head.headers.get("Age").and_then(|x| x.trim().parse::<u64>().ok()).unwrap_or(0);
+        Entry {
+            head: head,
+            body: body,
+            stored: now.saturating_sub(age),
+            expires: now + lifetime,
+            tick: 0
+        }
+    }
+
+    #[inline(always)]
+    fn size(&self) -> usize {
+        self.body.len() + self.head.headers.iter().map(|x| x.0.len() + x.1.len() + 4).sum::<usize>() + 64
+    }
+
+    ///Answer a request with `headers`. A client revalidating its own copy gets a 304.
+    fn reply(&self, headers: &Headers, head_request: bool, now: u64) -> Stored {
+        let mut head = self.head.clone();
+        head.headers.set("Age", &now.saturating_sub(self.stored).to_string());
+        let etag = head.headers.get("ETag").map(|x| x.trim().trim_left_matches("W/").to_string());
+        let matched = match etag {
+            Option::Some(ref t) => headers.get_all("If-None-Match")
+                .flat_map(|v| v.split(','))
+                .any(|x| x.trim() == "*" || x.trim().trim_left_matches("W/") == t),
+            Option::None => false
+        };
+        if matched {
+            head.status = 304;
+            head.reason = b"Not Modified".to_vec();
+            head.headers.remove("Content-Length");
+            return Stored { head: head, body: Arc::new(Vec::new()) };
+        }
+        let body = if head_request { Arc::new(Vec::new()) } else { self.body.clone() };
+        Stored { head: head, body: body }
+    }
+}
+
+///What the cache has for a request
+pub enum Found {
+    ///A fresh response
+    Hit(Stored),
+    ///A stale response, the backend should be asked with these conditional headers. The
+    ///response is kept to answer with if the backend says it's still good.
+    Stale(Vec<(&'static str,String)>,Stored),
+    Miss
+}
+impl Found {
+    #[inline(always)]
+    pub fn is_hit(&self) -> bool {
+        match self {
+            &Found::Hit(_) => true,
+            _ => false
+        }
+    }
+}
+
+///A request which went to the backend, whose response may be stored
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct Pending {
+    pub key: String,
+    ///The request's headers, for matching `Vary`
+    pub headers: Headers,
+    ///Conditional headers were added for this stale response, so a 304 means it can be sent
+    pub stale: Option<Stored>
+}
+impl Pending {
+
+    ///How long the response may be stored, see `lifetime`. A request with a cookie may have
+    ///been answered for that client alone, so its response is only stored if it is `public`.
+    pub fn lifetime(&self, resp: &Response, now: u64) -> Option<u64> {
+        if self.headers.contains("Cookie") && ! is_public(resp) {
+            return None;
+        }
+        lifetime(resp, now)
+    }
+}
+
+///Responses kept in memory, up to a budget in bytes. The least recently used are dropped to
+///make room. Responses which `Vary` are stored once for each set of request header values.
+pub struct Cache {
+    ///By key and the values of the headers it varies on
+    entries: HashMap<String,Entry>,
+    ///Headers each key varies on
+    vary: HashMap<String,Vec<String>>,
+    lru: BTreeMap<u64,String>,
+    tick: u64,
+    size: usize
+}
+impl Cache {
+
+    pub fn new() -> Cache {
+        Cache {
+            entries: HashMap::new(),
+            vary: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            size: 0
+        }
+    }
+
+    ///Bytes held
+    #[inline(always)]
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    ///The key of the variant a request would get
+    fn variant(&self, key: &str, headers: &Headers) -> String {
+        let mut k = key.to_string();
+        match self.vary.get(key) {
+            Option::Some(names) => for n in names.iter() {
+                k.push('\n');
+                k.push_str(&headers.joined(n).unwrap_or(String::new()));
+            },
+            Option::None => { }
+        };
+        k
+    }
+
+    ///Mark an entry as just used
+    fn touch(&mut self, k: &str) {
+        self.tick += 1;
+        let tick = self.tick;
+        match self.entries.get_mut(k) {
+            Option::Some(e) => {
+                self.lru.remove(&e.tick);
+                e.tick = tick;
+                self.lru.insert(tick, k.to_string());
+            },
+            Option::None => { }
+        };
+    }
+
+    ///Find a response for a request. `key` is from `key`, `now` in seconds since the epoch.
+    ///A request with a cookie is only answered with a `public` response.
+    pub fn lookup(&mut self, key: &str, req: &Request, now: u64) -> Found {
+        let k = self.variant(key, &req.headers);
+        let cookie = req.headers.contains("Cookie");
+        let found = match self.entries.get(&k) {
+            Option::Some(e) if cookie && ! is_public(&e.head) => Found::Miss,
+            Option::Some(e) if now < e.expires && ! wants_revalidation(req) => Found::Hit(e.reply(&req.headers, req.method == "HEAD", now)),
+            Option::Some(e) => {
+                let mut cond = Vec::new();
+                match e.head.headers.get("ETag") {
+                    Option::Some(t) => cond.push(("If-None-Match", t.to_string())),
+                    Option::None => { }
+                };
+                match e.head.headers.get("Last-Modified") {
+                    Option::Some(t) => cond.push(("If-Modified-Since", t.to_string())),
+                    Option::None => { }
+                };
+                if cond.is_empty() {
+                    Found::Miss
+                } else {
+                    Found::Stale(cond, Stored { head: e.head.clone(), body: e.body.clone() })
+                }
+            },
+            Option::None => Found::Miss
+        };
+        match found {
+            Found::Hit(_) => self.touch(&k),
+            _ => { }
+        };
+        found
+    }
+
+    ///Store a response the backend sent for `p`. `lifetime` is from `Pending::lifetime`.
+    pub fn store(&mut self, p: &Pending, head: Response, body: Vec<u8>, lifetime: u64, now: u64) {
+        self.insert(p, Entry::new(head, Arc::new(body), lifetime, now));
+    }
+
+    fn insert(&mut self, p: &Pending, e: Entry) {
+        let names: Vec<String> = e.head.headers.get_all("Vary")
+            .flat_map(|v| v.split(','))
+            .map(|x| x.trim().to_string())
+            .filter(|x| ! x.is_empty())
+            .collect();
+        if self.vary.get(&p.key) != Some(&names) {
+            //what it varies on changed, the old variants can't be found
+            self.purge(&p.key);
+            self.vary.insert(p.key.clone(), names);
+        }
+        let k = self.variant(&p.key, &p.headers);
+        self.remove(&k);
+        let size = e.size();
+        let budget = get_cache_budget();
+        //one response may not take more than an eighth of the cache
+        if size > budget / 8 {
+            return;
+        }
+        while self.size + size > budget {
+            let oldest = match self.lru.iter().next() {
+                Option::Some((_, k)) => k.clone(),
+                Option::None => break
+            };
+            self.remove(&oldest);
+            incr(Metric::CacheEvict);
+        }
+        self.size += size;
+        self.entries.insert(k.clone(), e);
+        self.touch(&k);
+    }
+
+    ///The backend says the stale response `p` was sent with is still good. The 304's headers
+    ///replace the stored ones, except `Set-Cookie` which was meant for the client which
+    ///asked, and its lifetime is renewed. If the response can no longer be stored it is
+    ///dropped, but the client is still answered with it. Returns `None` if `p` wasn't
+    ///revalidating.
+    pub fn refresh(&mut self, p: &Pending, not_modified: &Response, head_request: bool, now: u64) -> Option<Stored> {
+        let stale = match p.stale {
+            Option::Some(ref x) => x,
+            Option::None => return None
+        };
+        let mut head = stale.head.clone();
+        for &(ref n, ref v) in not_modified.headers.iter() {
+            if ! n.eq_ignore_ascii_case("Content-Length") && ! n.eq_ignore_ascii_case("Set-Cookie") {
+                head.headers.set_raw(n, v);
+            }
+        }
+        incr(Metric::CacheRevalidate);
+        let lifetime = p.lifetime(&head, now);
+        let e = Entry::new(head, stale.body.clone(), lifetime.unwrap_or(0), now);
+        let answer = e.reply(&p.headers, head_request, now);
+        match lifetime {
+            Option::Some(_) => self.insert(p, e),
+            Option::None => {
+                let k = self.variant(&p.key, &p.headers);
+                self.remove(&k);
+            }
+        };
+        Some(answer)
+    }
+
+    ///Drop one stored response
+    fn remove(&mut self, k: &str) {
+        match self.entries.remove(k) {
+            Option::Some(e) => {
+                self.size -= e.size();
+                self.lru.remove(&e.tick);
+            },
+            Option::None => { }
+        };
+    }
+
+    ///Drop every variant stored for a key. A key without a route, such as
+    ///`example.com/index.html`, drops what's stored for it by every route. Returns how many
+    ///responses went.
+    pub fn purge(&mut self, key: &str) -> usize {
+        let variant = format!("{}\n", key);
+        let routed = format!("{} ", key);
+        self.purge_matching(|k| k == key || k.starts_with(&variant) || (! key.contains(' ') && k.starts_with(&routed)))
+    }
+
+    ///Drop everything whose key starts with `prefix`, such as `example.com/static/`
+    pub fn purge_prefix(&mut self, prefix: &str) -> usize {
+        self.vary.retain(|k, _| ! k.starts_with(prefix));
+        self.purge_matching(|k| k.starts_with(prefix))
+    }
+
+    fn purge_matching<F: Fn(&str) -> bool>(&mut self, f: F) -> usize {
+        let gone: Vec<String> = self.entries.keys().filter(|k| f(k)).cloned().collect();
+        for k in gone.iter() {
+            self.remove(k);
+        }
+        gone.len()
+    }
+}
+
+lazy_static! {
+    static ref CACHE: Mutex<Cache> = Mutex::new(Cache::new());
+}
+
+///Get the cache shared by every worker. Hold it briefly.
+pub fn get_cache() -> MutexGuard<'static,Cache> {
+    match CACHE.lock() {
+        Ok(g) => g,
+        Err(poison) => poison.into_inner()
+    }
+}
+#[test]
+fn test_cache() {
+    use super::Version;
+
+    let req = |target: &str, lang: &str| {
+        let mut h = Headers::new();
+        h.append("Host", "Example.com");
+        h.append("Accept-Language", lang);
+        Request {
+            method: "GET".to_string(),
+            target: target.to_string(),
+            version: Version::Http11,
+            headers: h
+        }
+    };
+    let resp = |cc: &str| {
+        let mut r = Response::new(200, "OK");
+        r.headers.append("Cache-Control", cc);
+        r.headers.append("ETag", "\"v1\"");
+        r.headers.append("Vary", "Accept-Language");
+        r.headers.append("Connection", "keep-alive");
+        r
+    };
+    let now = 1_000_000;
+    assert_eq!( lifetime(&resp("max-age=60"), now), Some(60));
+    assert_eq!( lifetime(&resp("no-cache"), now), Some(0));
+    assert_eq!( lifetime(&resp("private, max-age=60"), now), None);
+
+    let mut c = Cache::new();
+    let en = req("/a", "en");
+    let k = key(&en, "", "0").unwrap();
+    assert_eq!( k, "example.com/a 0");
+    match c.lookup(&k, &en, now) { Found::Miss => { }, _ => panic!("expected a miss") };
+    let p = Pending { key: k.clone(), headers: en.headers.clone(), stale: None };
+    c.store(&p, resp("max-age=60"), b"hello".to_vec(), 60, now);
+
+    match c.lookup(&k, &en, now + 1) {
+        Found::Hit(r) => {
+            assert_eq!( r.body.as_slice(), b"hello");
+            assert_eq!( r.head.headers.get("Age"), Some("1"));
+            assert_eq!( r.head.headers.get("Connection"), None);
+        },
+        _ => panic!("expected a hit")
+    };
+    //another language is another variant
+    match c.lookup(&k, &req("/a", "fr"), now) { Found::Miss => { }, _ => panic!("expected a miss") };
+    //stale, so the backend is asked if it changed
+    let stale = match c.lookup(&k, &en, now + 61) {
+        Found::Stale(cond, s) => {
+            assert_eq!( cond, vec![("If-None-Match", "\"v1\"".to_string())]);
+            s
+        },
+        _ => panic!("expected a stale response")
+    };
+    assert_eq!( c.refresh(&p, &resp("max-age=120"), false, now + 61), None);
+    let p = Pending { key: k.clone(), headers: en.headers.clone(), stale: Some(stale) };
+    let mut not_modified = resp("max-age=120");
+    not_modified.headers.append("Set-Cookie", "id=1");
+    let r = c.refresh(&p, &not_modified, false, now + 61).unwrap();
+    assert_eq!( r.body.as_slice(), b"hello");
+    assert_eq!( r.head.headers.get("Set-Cookie"), None);
+    match c.lookup(&k, &en, now + 100) { Found::Hit(_) => { }, _ => panic!("expected a hit") };
+
+    //the client is still answered when the response may no longer be kept. Purging the URL
+    //drops it from every route.
+    assert_eq!( c.purge("example.com/a"), 1);
+    let r = c.refresh(&p, &resp("no-store"), false, now + 100).unwrap();
+    assert_eq!( r.into_reply().body.as_slice(), b"hello");
+    match c.lookup(&k, &en, now + 100) { Found::Miss => { }, _ => panic!("expected a miss") };
+    c.store(&p, resp("max-age=60"), b"hello".to_vec(), 60, now);
+
+    //a cookie means the response may be for that client alone
+    let mut jar = req("/a", "en");
+    jar.headers.append("Cookie", "id=2");
+    let q = Pending { key: k.clone(), headers: jar.headers.clone(), stale: None };
+    assert_eq!( q.lifetime(&resp("max-age=60"), now), None);
+    assert_eq!( q.lifetime(&resp("public, max-age=60"), now), Some(60));
+    match c.lookup(&k, &jar, now) { Found::Miss => { }, _ => panic!("expected a miss") };
+
+    //the same resource, and no Host
+    let mut odd = req("/b/../a?x=1", "en");
+    assert_eq!( key(&odd, "", "0").unwrap(), "example.com/a?x=1 0");
+    odd.headers.remove("Host");
+    assert_eq!( key(&odd, "Other.org", "0").unwrap(), "other.org/a?x=1 0");
+    //routed elsewhere, stored apart
+    assert_eq!( key(&odd, "Other.org", "2 canary").unwrap(), "other.org/a?x=1 2 canary");
+
+    assert_eq!( c.purge_prefix("example.com/"), 1);
+    assert_eq!( c.size(), 0);
+}
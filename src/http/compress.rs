@@ -0,0 +1,340 @@
+
+use super::{
+    Span,
+    Version
+};
+use super::body::Body;
+use super::head::{
+    Request,
+    Response
+};
+use super::super::flate2::Compression;
+use super::super::flate2::write::GzEncoder;
+use super::super::brotli2::write::BrotliEncoder;
+use std::io::prelude::*;
+use std::sync::atomic::{
+    AtomicPtr,
+    Ordering
+};
+
+///A content coding responses can be compressed with
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum Coding {
+    Gzip,
+    Brotli
+}
+impl Coding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            &Coding::Gzip => "gzip",
+            &Coding::Brotli => "br"
+        }
+    }
+}
+
+///Which responses are compressed, and how hard
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct Compress {
+    ///Media types which are compressed, without parameters
+    types: Vec<String>,
+    ///Responses with a smaller `Content-Length` are sent as they are
+    min_size: u64,
+    ///0 to 9, higher is smaller and slower
+    level: u32,
+    ///Offer brotli as well as gzip
+    brotli: bool
+}
+impl Compress {
+
+    ///Compress text, JSON, JavaScript and XML of at least 1KiB, at level 6
+    pub fn new() -> Compress {
+        let types = ["text/html", "text/plain", "text/css", "text/javascript", "application/javascript",
+            "application/json", "application/xml", "image/svg+xml"];
+        Compress {
+            types: types.iter().map(|x| x.to_string()).collect(),
+            min_size: 1024,
+            level: 6,
+            brotli: true
+        }
+    }
+
+    ///Replace the media types which are compressed
+    pub fn types(mut self, v: Vec<String>) -> Compress {
+        self.types = v;
+        self
+    }
+
+    ///Leave responses with a `Content-Length` under `n` bytes as they are. Chunked responses
+    ///are compressed whatever their size.
+    pub fn min_size(mut self, n: u64) -> Compress {
+        self.min_size = n;
+        self
+    }
+
+    ///Compression level from 0 to 9, higher is smaller and slower. Larger values are taken as 9.
+    pub fn level(mut self, n: u32) -> Compress {
+        self.level = if n > 9 { 9 } else { n };
+        self
+    }
+
+    ///Offer brotli to clients which accept it, rather than only gzip
+    pub fn brotli(mut self, b: bool) -> Compress {
+        self.brotli = b;
+        self
+    }
+
+    ///Pick a coding from a request's `Accept-Encoding`, brotli first. A coding with `q=0`
+    ///isn't acceptable. HEAD and HTTP/1.0 requests aren't compressed for, as the compressed
+    ///body is sent chunked.
+    pub fn negotiate(&self, req: &Request) -> Option<Coding> {
+        if req.version != Version::Http11 || req.method == "HEAD" {
+            return None;
+        }
+        let mut gzip = false;
+        let mut br = false;
+        for item in req.headers.get_all("Accept-Encoding").flat_map(|v| v.split(',')) {
+            let mut parts = item.split(';').map(|x| x.trim());
+            let name = parts.next().unwrap_or("").to_ascii_lowercase();
+            let zero = parts.any(|p| match p.find('=') {
+                Option::Some(i) if p[..i].trim().eq_ignore_ascii_case("q") => {
+                    p[i+1..].trim().parse::<f32>().map(|q| q <= 0.0).unwrap_or(true)
+                },
+                _ => false
+            });
+            if zero {
+                continue;
+            }
+            match name.as_str() {
+                "gzip" | "x-gzip" | "*" => gzip = true,
+                "br" if self.brotli => br = true,
+                _ => { }
+            };
+        }
+        if br {
+            Some(Coding::Brotli)
+        } else if gzip {
+            Some(Coding::Gzip)
+        } else {
+            None
+        }
+    }
+
+    ///If a response should be compressed. Only whole 200 responses of an allowed type, which
+    ///aren't encoded already or marked `no-transform`. Bodies ended by closing are left alone,
+    ///as there'd be no way to finish the compressed stream.
+    pub fn applies(&self, resp: &Response, body: Body) -> bool {
+        let sized = match body {
+            Body::Length(n) => n >= self.min_size,
+            Body::Chunked => true,
+            Body::None | Body::Close => false
+        };
+        let encoded = resp.headers.get_all("Content-Encoding").any(|v| ! v.trim().eq_ignore_ascii_case("identity"));
+        let no_transform = resp.headers.get_all("Cache-Control")
+            .flat_map(|v| v.split(','))
+            .any(|x| x.trim().eq_ignore_ascii_case("no-transform"));
+        let allowed = match resp.headers.get("Content-Type") {
+            Option::Some(t) => {
+                let t = t.split(';').next().unwrap_or("").trim();
+                self.types.iter().any(|x| x.eq_ignore_ascii_case(t))
+            },
+            Option::None => false
+        };
+        resp.status == 200 && sized && ! encoded && ! no_transform && allowed
+    }
+
+    ///Rewrite a response's head for a compressed body, and make the encoder for it
+    pub fn start(&self, resp: &mut Response, c: Coding) -> Encoder {
+        resp.headers.remove("Content-Length");
+        resp.headers.remove("Content-Encoding");
+        resp.headers.set("Transfer-Encoding", "chunked");
+        resp.headers.set("Content-Encoding", c.as_str());
+        let varies = resp.headers.get_all("Vary")
+            .flat_map(|v| v.split(','))
+            .any(|x| x.trim() == "*" || x.trim().eq_ignore_ascii_case("accept-encoding"));
+        if ! varies {
+            resp.headers.append("Vary", "Accept-Encoding");
+        }
+        //the bytes differ, so a strong validator would be wrong
+        let etag = match resp.headers.get("ETag") {
+            Option::Some(t) if ! t.starts_with("W/") => Some(format!("W/{}", t)),
+            _ => None
+        };
+        match etag {
+            Option::Some(t) => resp.headers.set("ETag", &t),
+            Option::None => { }
+        };
+        Encoder::new(c, self.level)
+    }
+
+    ///Compress a whole body at once, for a response which isn't passing through such as one
+    ///from the cache. The head is rewritten as `start` does, and the body returned as chunks.
+    pub fn encode(&self, resp: &mut Response, c: Coding, body: &[u8]) -> Vec<u8> {
+        let mut e = self.start(resp, c);
+        let mut out = Vec::with_capacity(body.len() / 2 + 16);
+        e.write(&[Span { start: 0, end: body.len() }], body, &mut out);
+        e.finish(&mut out);
+        out
+    }
+}
+
+///Compresses a response body as it passes, writing it out as chunks
+pub enum Encoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Brotli(BrotliEncoder<Vec<u8>>)
+}
+impl Encoder {
+
+    pub fn new(c: Coding, level: u32) -> Encoder {
+        match c {
+            Coding::Gzip => {
+                let level = match level {
+                    0 => Compression::None,
+                    1..=3 => Compression::Fast,
+                    4..=7 => Compression::Default,
+                    _ => Compression::Best
+                };
+                Encoder::Gzip(GzEncoder::new(Vec::new(), level))
+            },
+            Coding::Brotli => Encoder::Brotli(BrotliEncoder::new(Vec::new(), level))
+        }
+    }
+
+    ///Compress the body data in `spans` of `buf`, and write what comes out as a chunk
+    pub fn write(&mut self, spans: &[Span], buf: &[u8], out: &mut Vec<u8>) {
+        //the encoders write to a `Vec`, which can't fail
+        for s in spans {
+            let _ = match self {
+                &mut Encoder::Gzip(ref mut e) => e.write_all(s.get(buf)),
+                &mut Encoder::Brotli(ref mut e) => e.write_all(s.get(buf))
+            };
+        }
+        let pending = match self {
+            &mut Encoder::Gzip(ref mut e) => e.get_mut(),
+            &mut Encoder::Brotli(ref mut e) => e.get_mut()
+        };
+        write_chunk(pending, out);
+    }
+
+    ///End the body, with the last of the compressed data and the empty chunk
+    pub fn finish(self, out: &mut Vec<u8>) {
+        let rest = match self {
+            Encoder::Gzip(e) => e.finish(),
+            Encoder::Brotli(e) => e.finish()
+        };
+        match rest {
+            Ok(mut v) => write_chunk(&mut v, out),
+            Err(_) => { }
+        };
+        out.extend_from_slice(b"0\r\n\r\n");
+    }
+}
+
+///Move what's in `data` to `out` as a chunk
+#[inline(always)]
+fn write_chunk(data: &mut Vec<u8>, out: &mut Vec<u8>) {
+    if data.is_empty() {
+        return;
+    }
+    out.extend_from_slice(format!("{:x}\r\n", data.len()).as_bytes());
+    out.extend_from_slice(data);
+    out.extend_from_slice(b"\r\n");
+    data.clear();
+}
+
+lazy_static! {
+    static ref COMPRESS: AtomicPtr<Vec<Compress>> =
+        AtomicPtr::new(
+            Box::into_raw(
+                Box::new(
+                    Vec::with_capacity(4))));
+}
+
+///function to get the raw pointer to the array
+#[inline(always)]
+fn raw_ptr<'a>() -> &'a mut Vec<Compress> {
+    let ptr: *mut Vec<Compress> = COMPRESS.load(Ordering::Acquire);
+    unsafe {
+        match ptr.as_mut() {
+            Option::Some(x) => x,
+            Option::None => unreachable!()
+        }
+    }
+}
+
+///Store compression settings so routes can refer to them. This must only be called during
+///start up, before any worker threads exist. Returns the index.
+pub fn add_compress(c: Compress) -> usize {
+    let v = raw_ptr();
+    v.push(c);
+    v.len()-1
+}
+
+///Look up compression settings
+#[inline(always)]
+pub fn get_compress<'a>(i: usize) -> Option<&'a Compress> {
+    let v: &'a mut Vec<Compress> = raw_ptr();
+    v.get(i)
+}
+#[test]
+fn test_compress() {
+    use super::head::Headers;
+    use super::super::flate2::read::GzDecoder;
+
+    let req = |accept: &str| {
+        let mut h = Headers::new();
+        h.append("Accept-Encoding", accept);
+        Request {
+            method: "GET".to_string(),
+            target: "/".to_string(),
+            version: Version::Http11,
+            headers: h
+        }
+    };
+    let c = Compress::new().min_size(4);
+    assert_eq!( c.negotiate(&req("gzip, deflate, br")), Some(Coding::Brotli));
+    assert_eq!( c.negotiate(&req("br;q=0, gzip;q=0.5")), Some(Coding::Gzip));
+    assert_eq!( c.negotiate(&req("identity")), None);
+    assert_eq!( c.clone().brotli(false).negotiate(&req("br, *")), Some(Coding::Gzip));
+
+    let mut resp = Response::new(200, "OK");
+    resp.headers.append("Content-Type", "application/json; charset=utf-8");
+    resp.headers.append("Content-Length", "9");
+    resp.headers.append("ETag", "\"abc\"");
+    assert!( c.applies(&resp, Body::Length(9)) );
+    assert!( ! c.applies(&resp, Body::Length(3)) );
+    assert!( ! c.applies(&resp, Body::Close) );
+
+    let mut e = c.start(&mut resp, Coding::Gzip);
+    assert_eq!( resp.headers.get("Content-Length"), None);
+    assert_eq!( resp.headers.get("Vary"), Some("Accept-Encoding"));
+    assert_eq!( resp.headers.get("ETag"), Some("W/\"abc\""));
+    let mut out = Vec::new();
+    let body = b"{\"a\":[1]}";
+    e.write(&[Span { start: 0, end: body.len() }], body, &mut out);
+    e.finish(&mut out);
+    assert!( out.ends_with(b"\r\n0\r\n\r\n") );
+
+    //undo the chunking and check the data survives
+    let mut data = Vec::new();
+    let mut rest = out.as_slice();
+    loop {
+        let i = rest.windows(2).position(|w| w == b"\r\n").unwrap();
+        let len = usize::from_str_radix(::std::str::from_utf8(&rest[..i]).unwrap(), 16).unwrap();
+        if len == 0 {
+            break;
+        }
+        data.extend_from_slice(&rest[i+2..i+2+len]);
+        rest = &rest[i+4+len..];
+    }
+    let mut plain = Vec::new();
+    GzDecoder::new(data.as_slice()).unwrap().read_to_end(&mut plain).unwrap();
+    assert_eq!( plain.as_slice(), &body[..]);
+
+    //a whole body, as from the cache, comes out the same way
+    let mut cached = Response::new(200, "OK");
+    cached.headers.append("Content-Length", "9");
+    cached.headers.append("ETag", "\"abc\"");
+    assert_eq!( c.encode(&mut cached, Coding::Gzip, body), out);
+    assert_eq!( cached.headers.get("ETag"), Some("W/\"abc\""));
+    assert_eq!( cached.headers.get("Content-Encoding"), Some("gzip"));
+}
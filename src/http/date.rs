@@ -0,0 +1,99 @@
+
+use std::time::{
+    SystemTime,
+    UNIX_EPOCH
+};
+
+const DAYS: [&'static str;7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&'static str;12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+///Seconds since the epoch, times before it are treated as the epoch
+pub fn unix_secs(t: SystemTime) -> u64 {
+    match t.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs(),
+        Err(_) => 0
+    }
+}
+
+///Format a time as an HTTP-date, `Sun, 06 Nov 1994 08:49:37 GMT`
+pub fn format(secs: u64) -> String {
+    let days = secs / 86400;
+    let rem = secs % 86400;
+    let (y, m, d) = civil(days as i64);
+    format!("{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize], d, MONTHS[(m-1) as usize], y,
+        rem / 3600, (rem % 3600) / 60, rem % 60)
+}
+
+///The current time as an HTTP-date
+#[inline(always)]
+pub fn now() -> String {
+    format(unix_secs(SystemTime::now()))
+}
+
+///Parse an HTTP-date in the preferred IMF-fixdate form into seconds since the epoch. The
+///obsolete forms aren't accepted, a date which can't be read is treated as absent.
+pub fn parse(s: &str) -> Option<u64> {
+    let b = s.trim().as_bytes();
+    //"Sun, 06 Nov 1994 08:49:37 GMT"
+    if b.len() != 29 || &b[3..5] != b", " || &b[25..] != b" GMT" {
+        return None;
+    }
+    let num = |r: &[u8]| -> Option<u64> {
+        let mut x = 0u64;
+        for c in r {
+            if ! (*c as char).is_ascii_digit() {
+                return None;
+            }
+            x = x * 10 + (c - b'0') as u64;
+        }
+        Some(x)
+    };
+    let d = num(&b[5..7])?;
+    let m = MONTHS.iter().position(|x| x.as_bytes() == &b[8..11])? as u64 + 1;
+    let y = num(&b[12..16])?;
+    if b[7] != b' ' || b[11] != b' ' || b[16] != b' ' || b[19] != b':' || b[22] != b':' {
+        return None;
+    }
+    let (hh, mm, ss) = (num(&b[17..19])?, num(&b[20..22])?, num(&b[23..25])?);
+    if y < 1970 || d < 1 || d > 31 || hh > 23 || mm > 59 || ss > 60 {
+        return None;
+    }
+    let days = days_from_civil(y as i64, m as i64, d as i64);
+    Some(days as u64 * 86400 + hh * 3600 + mm * 60 + ss)
+}
+
+///Days since the epoch to a year, month and day. From Howard Hinnant's date algorithms.
+fn civil(z: i64) -> (i64, i64, i64) {
+    let z = z + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe/1460 + doe/36524 - doe/146096) / 365;
+    let doy = doe - (365*yoe + yoe/4 - yoe/100);
+    let mp = (5*doy + 2) / 153;
+    let d = doy - (153*mp + 2)/5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    (y, m, d)
+}
+
+///Year, month and day to days since the epoch
+fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = if y >= 0 { y } else { y - 399 } / 400;
+    let yoe = y - era * 400;
+    let mp = if m > 2 { m - 3 } else { m + 9 };
+    let doy = (153*mp + 2)/5 + d - 1;
+    let doe = yoe * 365 + yoe/4 - yoe/100 + doy;
+    era * 146097 + doe - 719468
+}
+#[test]
+fn test_http_date() {
+    assert_eq!( format(784111777), "Sun, 06 Nov 1994 08:49:37 GMT");
+    assert_eq!( format(0), "Thu, 01 Jan 1970 00:00:00 GMT");
+    assert_eq!( format(951782400), "Tue, 29 Feb 2000 00:00:00 GMT");
+    assert_eq!( parse("Sun, 06 Nov 1994 08:49:37 GMT"), Some(784111777));
+    assert_eq!( parse("Tue, 29 Feb 2000 00:00:00 GMT"), Some(951782400));
+    assert_eq!( parse("Sunday, 06-Nov-94 08:49:37 GMT"), None);
+    assert_eq!( parse("Sun, 06 Foo 1994 08:49:37 GMT"), None);
+}
@@ -0,0 +1,469 @@
+
+use super::head::{
+    Request,
+    Response
+};
+use super::date;
+use super::reason;
+use std::ffi::OsStr;
+use std::fs::{
+    File,
+    metadata
+};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{
+    Path,
+    PathBuf
+};
+use std::sync::atomic::{
+    AtomicPtr,
+    Ordering
+};
+
+///A directory served from disk without a backend
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct Site {
+    pub root: PathBuf,
+    ///Files tried, in order, when a directory is asked for
+    pub index: Vec<String>
+}
+impl Site {
+
+    ///Serve `root`, with `index.html` as the index file
+    pub fn new(root: PathBuf) -> Site {
+        Site {
+            root: root,
+            index: vec!["index.html".to_string()]
+        }
+    }
+
+    pub fn index(mut self, v: Vec<String>) -> Site {
+        self.index = v;
+        self
+    }
+}
+
+///Part of a file still to be sent to a client
+#[derive(Debug)]
+pub struct FileBody {
+    pub file: File,
+    pub offset: u64,
+    pub remaining: u64
+}
+
+///A response the proxy makes itself. The head and `body` are queued for the client together,
+///a `file` is sent after them as the client takes it.
+#[derive(Debug)]
+pub struct Reply {
+    pub head: Response,
+    pub body: Vec<u8>,
+    pub file: Option<FileBody>
+}
+impl Reply {
+
+    ///A short plain text response
+    pub fn text(status: u16, reason: &str, text: &str) -> Reply {
+        let mut head = Response::new(status, reason);
+        head.headers.set("Content-Type", "text/plain; charset=utf-8");
+        head.headers.set("Content-Length", &text.len().to_string());
+        Reply {
+            head: head,
+            body: text.as_bytes().to_vec(),
+            file: None
+        }
+    }
+
+    ///Drop the body, for a HEAD request. The headers still describe it.
+    pub fn without_body(mut self) -> Reply {
+        self.body.clear();
+        self.file = None;
+        self
+    }
+}
+
+///Answer a request from a site. Only GET and HEAD are supported.
+pub fn serve(site: &Site, req: &Request) -> Reply {
+    let is_head = req.method == "HEAD";
+    if req.method != "GET" && ! is_head {
+        let mut r = Reply::text(405, reason(405), "Method Not Allowed\n");
+        r.head.headers.set("Allow", "GET, HEAD");
+        return r;
+    }
+    let reply = match open(site, &req.target) {
+        Option::Some((path, file, len, mtime)) => file_reply(req, &path, file, len, mtime),
+        Option::None => Reply::text(404, reason(404), "Not Found\n")
+    };
+    if is_head {
+        reply.without_body()
+    } else {
+        reply
+    }
+}
+
+///Build the response for a file which exists, minding conditional and range headers
+fn file_reply(req: &Request, path: &Path, file: File, len: u64, mtime: u64) -> Reply {
+    let etag = format!("\"{:x}-{:x}\"", mtime, len);
+    let mut head = Response::new(200, reason(200));
+    head.headers.set("ETag", &etag);
+    head.headers.set("Last-Modified", &date::format(mtime));
+    head.headers.set("Accept-Ranges", "bytes");
+
+    if not_modified(req, &etag, mtime) {
+        head.status = 304;
+        head.reason = reason(304).as_bytes().to_vec();
+        return Reply {
+            head: head,
+            body: Vec::new(),
+            file: None
+        };
+    }
+
+    let range = match req.headers.get("Range") {
+        Option::Some(r) if if_range(req, &etag, mtime) => parse_range(r, len),
+        _ => Ok(None)
+    };
+    let (start, end) = match range {
+        Err(()) => {
+            let mut r = Reply::text(416, reason(416), "Range Not Satisfiable\n");
+            r.head.headers.set("Content-Range", &format!("bytes */{}", len));
+            return r;
+        },
+        Ok(Option::Some((start, end))) => {
+            head.status = 206;
+            head.reason = reason(206).as_bytes().to_vec();
+            head.headers.set("Content-Range", &format!("bytes {}-{}/{}", start, end, len));
+            (start, end + 1)
+        },
+        Ok(Option::None) => (0, len)
+    };
+    head.headers.set("Content-Type", mime_type(path));
+    head.headers.set("Content-Length", &(end - start).to_string());
+    let file = if end > start {
+        Some(FileBody {
+            file: file,
+            offset: start,
+            remaining: end - start
+        })
+    } else {
+        None
+    };
+    Reply {
+        head: head,
+        body: Vec::new(),
+        file: file
+    }
+}
+
+///Check `If-None-Match`, or `If-Modified-Since` when there isn't one
+fn not_modified(req: &Request, etag: &str, mtime: u64) -> bool {
+    match req.headers.joined("If-None-Match") {
+        Option::Some(list) => list.split(',')
+            .map(|x| x.trim())
+            .any(|x| x == "*" || x.trim_left_matches("W/") == etag),
+        Option::None => match req.headers.get("If-Modified-Since").and_then(date::parse) {
+            Option::Some(since) => mtime <= since,
+            Option::None => false
+        }
+    }
+}
+
+///Check `If-Range`. A range is only honoured if the validator still matches.
+fn if_range(req: &Request, etag: &str, mtime: u64) -> bool {
+    match req.headers.get("If-Range") {
+        Option::None => true,
+        Option::Some(v) if v.starts_with('"') => v == etag,
+        Option::Some(v) => date::parse(v) == Some(mtime)
+    }
+}
+
+///Read a `Range` header for a file of `len` bytes. Gives the first and last byte of a single
+///range. Multiple ranges, or anything malformed, are ignored and the whole file is sent.
+///`Err` means the range is outside the file.
+pub fn parse_range(value: &str, len: u64) -> Result<Option<(u64,u64)>,()> {
+    let value = value.trim();
+    if value.len() < 6 || ! value[..6].eq_ignore_ascii_case("bytes=") {
+        return Ok(None);
+    }
+    let spec = value[6..].trim();
+    if spec.contains(',') {
+        return Ok(None);
+    }
+    let dash = match spec.find('-') {
+        Option::Some(i) => i,
+        Option::None => return Ok(None)
+    };
+    let num = |s: &str| -> Option<u64> {
+        if s.is_empty() || ! s.bytes().all(|b| (b as char).is_ascii_digit()) {
+            None
+        } else {
+            s.parse::<u64>().ok()
+        }
+    };
+    let (first, last) = (&spec[..dash], &spec[dash+1..]);
+    if first.is_empty() {
+        //the last n bytes
+        return match num(last) {
+            Option::Some(0) => Err(()),
+            Option::Some(_) if len == 0 => Err(()),
+            Option::Some(n) => Ok(Some((len.saturating_sub(n), len - 1))),
+            Option::None => Ok(None)
+        };
+    }
+    let start = match num(first) {
+        Option::Some(x) => x,
+        Option::None => return Ok(None)
+    };
+    let end = if last.is_empty() {
+        None
+    } else {
+        match num(last) {
+            Option::Some(x) if x >= start => Some(x),
+            _ => return Ok(None)
+        }
+    };
+    if start >= len {
+        return Err(());
+    }
+    Ok(Some((start, end.map(|e| ::std::cmp::min(e, len - 1)).unwrap_or(len - 1))))
+}
+
+///Find and open the file a request target names. Returns its path, the file, its length and
+///when it was last modified. `..` segments, and symbolic links leading out of the root, find
+///nothing.
+fn open(site: &Site, target: &str) -> Option<(PathBuf, File, u64, u64)> {
+    let path = resolve(site, target)?;
+    let file = File::open(&path).ok()?;
+    let meta = file.metadata().ok()?;
+    if ! meta.is_file() {
+        return None;
+    }
+    let mtime = meta.modified().map(date::unix_secs).unwrap_or(0);
+    Some((path, file, meta.len(), mtime))
+}
+
+///Map a request target to a path under the site's root
+fn resolve(site: &Site, target: &str) -> Option<PathBuf> {
+    let end = target.find(|c| c == '?' || c == '#').unwrap_or(target.len());
+    let raw = &target[..end];
+    if ! raw.starts_with('/') {
+        return None;
+    }
+    let decoded = percent_decode(raw)?;
+    let mut path = site.root.clone();
+    for seg in decoded.split(|b| *b == b'/') {
+        match seg {
+            b"" | b"." => continue,
+            b".." => return None,
+            s if s.contains(&0) => return None,
+            s => path.push(OsStr::from_bytes(s))
+        };
+    }
+    //a symbolic link must not lead out of the root
+    let root = site.root.canonicalize().ok()?;
+    let path = path.canonicalize().ok()?;
+    if ! path.starts_with(&root) {
+        return None;
+    }
+    if metadata(&path).ok()?.is_dir() {
+        //the index file may be a link as well
+        return site.index.iter()
+            .filter_map(|i| path.join(i).canonicalize().ok())
+            .find(|p| p.starts_with(&root) && metadata(p).map(|m| m.is_file()).unwrap_or(false));
+    }
+    Some(path)
+}
+
+///Decode `%XX` escapes. A malformed escape fails.
+fn percent_decode(s: &str) -> Option<Vec<u8>> {
+    let b = s.as_bytes();
+    let mut out = Vec::with_capacity(b.len());
+    let mut i = 0;
+    while i < b.len() {
+        if b[i] == b'%' {
+            if i + 2 >= b.len() {
+                return None;
+            }
+            out.push((hex(b[i+1])? << 4) | hex(b[i+2])?);
+            i += 3;
+        } else {
+            out.push(b[i]);
+            i += 1;
+        }
+    }
+    Some(out)
+}
+
+#[inline(always)]
+fn hex(b: u8) -> Option<u8> {
+    match b {
+        b'0' ..= b'9' => Some(b - b'0'),
+        b'a' ..= b'f' => Some(b - b'a' + 10),
+        b'A' ..= b'F' => Some(b - b'A' + 10),
+        _ => None
+    }
+}
+
+///Content type for a file, by its extension
+pub fn mime_type(path: &Path) -> &'static str {
+    let ext = match path.extension().and_then(|x| x.to_str()) {
+        Option::Some(x) => x.to_ascii_lowercase(),
+        Option::None => return "application/octet-stream"
+    };
+    match ext.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" | "map" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "pdf" => "application/pdf",
+        "wasm" => "application/wasm",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "mp3" => "audio/mpeg",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        _ => "application/octet-stream"
+    }
+}
+
+lazy_static! {
+    static ref SITES: AtomicPtr<Vec<Site>> =
+        AtomicPtr::new(
+            Box::into_raw(
+                Box::new(
+                    Vec::with_capacity(4))));
+}
+
+///function to get the raw pointer to the array
+#[inline(always)]
+fn raw_ptr<'a>() -> &'a mut Vec<Site> {
+    let ptr: *mut Vec<Site> = SITES.load(Ordering::Acquire);
+    unsafe {
+        match ptr.as_mut() {
+            Option::Some(x) => x,
+            Option::None => unreachable!()
+        }
+    }
+}
+
+///Store a site so routes can refer to it. This must only be called during start up, before
+///any worker threads exist. Returns the site's index.
+pub fn add_site(s: Site) -> usize {
+    let v = raw_ptr();
+    v.push(s);
+    v.len()-1
+}
+
+///Look up a site
+#[inline(always)]
+pub fn get_site<'a>(i: usize) -> Option<&'a Site> {
+    let v: &'a mut Vec<Site> = raw_ptr();
+    v.get(i)
+}
+///A directory of files for tests, removed when dropped
+#[cfg(test)]
+pub struct TestDir {
+    pub root: PathBuf
+}
+#[cfg(test)]
+impl TestDir {
+
+    ///Create a directory for the test `name`, holding `files` as paths and contents
+    pub fn new(name: &str, files: &[(&str,&[u8])]) -> TestDir {
+        use std::fs::{create_dir_all,write};
+
+        let root = ::std::env::temp_dir().join(format!("tlsrp-{}-{}", name, ::std::process::id()));
+        for &(path, data) in files {
+            let path = root.join(path);
+            create_dir_all(path.parent().unwrap()).unwrap();
+            write(&path, data).unwrap();
+        }
+        create_dir_all(&root).unwrap();
+        TestDir {
+            root: root
+        }
+    }
+}
+#[cfg(test)]
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = ::std::fs::remove_dir_all(&self.root);
+    }
+}
+#[test]
+fn test_serve_files() {
+    use super::head::Headers;
+    use super::Version;
+    use std::os::unix::fs::symlink;
+
+    let dir = TestDir::new("files", &[
+        ("site/docs/index.html", b"<p>hi</p>"),
+        ("site/a b.txt", b"0123456789"),
+        ("secret.html", b"secret")
+    ]);
+    let root = dir.root.join("site");
+    ::std::fs::create_dir_all(root.join("linked")).unwrap();
+    symlink(dir.root.join("secret.html"), root.join("linked/index.html")).unwrap();
+    let site = Site::new(root.clone());
+    let req = |method: &str, target: &str, headers: &[(&str,&str)]| {
+        let mut h = Headers::new();
+        for &(n,v) in headers {
+            h.append(n, v);
+        }
+        Request {
+            method: method.to_string(),
+            target: target.to_string(),
+            version: Version::Http11,
+            headers: h
+        }
+    };
+
+    let r = serve(&site, &req("GET", "/docs/", &[]));
+    assert_eq!( r.head.status, 200);
+    assert_eq!( r.head.headers.get("Content-Type"), Some("text/html; charset=utf-8"));
+    assert_eq!( r.file.as_ref().map(|f| f.remaining), Some(9));
+
+    let r = serve(&site, &req("GET", "/a%20b.txt?x=1", &[]));
+    assert_eq!( r.head.headers.get("Content-Type"), Some("text/plain; charset=utf-8"));
+    let etag = r.head.headers.get("ETag").unwrap().to_string();
+    let modified = r.head.headers.get("Last-Modified").unwrap().to_string();
+
+    //conditional requests
+    assert_eq!( serve(&site, &req("GET", "/a%20b.txt", &[("If-None-Match", &etag)])).head.status, 304);
+    assert_eq!( serve(&site, &req("GET", "/a%20b.txt", &[("If-Modified-Since", &modified)])).head.status, 304);
+    assert_eq!( serve(&site, &req("GET", "/a%20b.txt", &[("If-None-Match", "\"nope\"")])).head.status, 200);
+
+    //ranges
+    let r = serve(&site, &req("GET", "/a%20b.txt", &[("Range", "bytes=2-4")]));
+    assert_eq!( r.head.status, 206);
+    assert_eq!( r.head.headers.get("Content-Range"), Some("bytes 2-4/10"));
+    assert_eq!( r.file.as_ref().map(|f| (f.offset, f.remaining)), Some((2, 3)));
+    let r = serve(&site, &req("GET", "/a%20b.txt", &[("Range", "bytes=-3")]));
+    assert_eq!( r.file.as_ref().map(|f| (f.offset, f.remaining)), Some((7, 3)));
+    assert_eq!( serve(&site, &req("GET", "/a%20b.txt", &[("Range", "bytes=10-")])).head.status, 416);
+    assert_eq!( serve(&site, &req("GET", "/a%20b.txt", &[("Range", "bytes=2-4"), ("If-Range", "\"old\"")])).head.status, 200);
+    assert_eq!( parse_range("bytes=0-1,4-5", 10), Ok(None));
+
+    //HEAD describes the body without sending it
+    let r = serve(&site, &req("HEAD", "/a%20b.txt", &[]));
+    assert_eq!( r.head.headers.get("Content-Length"), Some("10"));
+    assert!( r.file.is_none() );
+
+    //traversal
+    assert_eq!( serve(&site, &req("GET", "/../etc/passwd", &[])).head.status, 404);
+    assert_eq!( serve(&site, &req("GET", "/docs/%2e%2e/%2e%2e/etc/passwd", &[])).head.status, 404);
+    assert_eq!( serve(&site, &req("GET", "/a%2", &[])).head.status, 404);
+    //an index file linked out of the root
+    assert_eq!( serve(&site, &req("GET", "/linked/", &[])).head.status, 404);
+    assert_eq!( serve(&site, &req("POST", "/a%20b.txt", &[])).head.status, 405);
+}
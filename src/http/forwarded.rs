@@ -0,0 +1,180 @@
+
+use super::head::Request;
+use super::super::conn::hello::is_hostname;
+use super::super::cidr::{
+    Cidr,
+    any_contains
+};
+use std::net::{
+    IpAddr,
+    SocketAddr
+};
+
+///Headers a client could use to lie about where a request came from
+pub const FORWARDING: &'static [&'static str] = &[
+    "Forwarded",
+    "X-Forwarded-For",
+    "X-Forwarded-Proto",
+    "X-Forwarded-Host",
+    "X-Forwarded-Port",
+    "X-Real-IP"
+];
+
+///Which clients are believed when they send forwarding headers. Headers from a client which
+///isn't trusted are removed before the proxy adds its own, so the backend only sees the hops
+///the proxy vouches for.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum Trust {
+    ///Strip every client's forwarding headers. This is the default.
+    Nobody,
+    ///Keep and append to the headers of clients in these blocks
+    From(Vec<Cidr>),
+    ///Keep and append to every client's headers
+    Everyone
+}
+impl Trust {
+
+    ///Check if a client's forwarding headers should be kept
+    pub fn allows(&self, addr: Option<&SocketAddr>) -> bool {
+        match (self, addr) {
+            (&Trust::Nobody,_) => false,
+            (&Trust::Everyone,_) => true,
+            (&Trust::From(ref v),Option::Some(a)) => any_contains(v, &a.ip()),
+            (&Trust::From(_),Option::None) => false
+        }
+    }
+}
+
+///What the proxy knows about the client a request came from
+#[derive(Copy,Clone,Debug)]
+pub struct Peer<'a> {
+    ///The client's address, after any PROXY protocol header
+    pub addr: Option<&'a SocketAddr>,
+    ///Server name the client sent in its ClientHello
+    pub sni: Option<&'a str>,
+    ///The client connected over TLS
+    pub tls: bool
+}
+
+///Add `X-Forwarded-For`, `X-Forwarded-Proto`, `X-Forwarded-Host` and an RFC 7239 `Forwarded`
+///header describing the client. Values from a trusted client are appended to, anyone else's
+///are removed first. Without a `Host` header the SNI is used, if it is a proper host name.
+pub fn add_forwarded(req: &mut Request, trust: &Trust, peer: &Peer) {
+    if ! trust.allows(peer.addr) {
+        for name in FORWARDING {
+            req.headers.remove(name);
+        }
+    }
+    let proto = if peer.tls { "https" } else { "http" };
+    let host = req.headers.get("Host")
+        .map(|x| x.to_string())
+        .or_else(|| peer.sni.filter(|x| is_hostname(x)).map(|x| x.to_string()));
+
+    let ip = match peer.addr {
+        Option::Some(a) => a.ip().to_string(),
+        Option::None => "unknown".to_string()
+    };
+    let xff = match req.headers.joined("X-Forwarded-For") {
+        Option::Some(v) => format!("{}, {}", v, ip),
+        Option::None => ip
+    };
+    req.headers.set("X-Forwarded-For", &xff);
+
+    if ! req.headers.contains("X-Forwarded-Proto") {
+        req.headers.set("X-Forwarded-Proto", proto);
+    }
+    match host {
+        Option::Some(ref h) if ! req.headers.contains("X-Forwarded-Host") => req.headers.set("X-Forwarded-Host", h),
+        _ => { }
+    };
+
+    let mut element = format!("for={};proto={}", node(peer.addr.map(|a| a.ip())), proto);
+    match host {
+        Option::Some(ref h) => {
+            element.push_str(";host=");
+            element.push_str(&quote(h));
+        },
+        Option::None => { }
+    };
+    let forwarded = match req.headers.joined("Forwarded") {
+        Option::Some(v) => format!("{}, {}", v, element),
+        Option::None => element
+    };
+    req.headers.set("Forwarded", &forwarded);
+}
+
+///Format an address as an RFC 7239 node. IPv6 addresses must be bracketed and quoted.
+fn node(ip: Option<IpAddr>) -> String {
+    match ip {
+        Option::Some(IpAddr::V4(ip)) => ip.to_string(),
+        Option::Some(IpAddr::V6(ip)) => format!("\"[{}]\"", ip),
+        Option::None => "unknown".to_string()
+    }
+}
+
+///Make a quoted-string of a value
+fn quote(s: &str) -> String {
+    let mut out = String::with_capacity(s.len()+2);
+    out.push('"');
+    for c in s.chars() {
+        if c == '"' || c == '\\' {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out.push('"');
+    out
+}
+#[test]
+fn test_add_forwarded() {
+    use super::head::Headers;
+    use super::Version;
+    use std::str::FromStr;
+
+    let mut headers = Headers::new();
+    headers.append("Host", "example.com:8443");
+    headers.append("X-Forwarded-For", "10.9.9.9");
+    headers.append("X-Real-IP", "10.9.9.9");
+    headers.append("Forwarded", "for=10.9.9.9");
+    let req = Request {
+        method: "GET".to_string(),
+        target: "/".to_string(),
+        version: Version::Http11,
+        headers: headers
+    };
+    let addr = SocketAddr::from_str("192.0.2.1:5000").unwrap();
+    let peer = Peer {
+        addr: Some(&addr),
+        sni: Some("example.com"),
+        tls: true
+    };
+
+    //untrusted, the client's values are dropped
+    let mut r = req.clone();
+    add_forwarded(&mut r, &Trust::Nobody, &peer);
+    assert_eq!( r.headers.get("X-Forwarded-For"), Some("192.0.2.1"));
+    assert_eq!( r.headers.get("X-Forwarded-Proto"), Some("https"));
+    assert_eq!( r.headers.get("X-Forwarded-Host"), Some("example.com:8443"));
+    assert_eq!( r.headers.get("Forwarded"), Some("for=192.0.2.1;proto=https;host=\"example.com:8443\""));
+    assert!( ! r.headers.contains("X-Real-IP") );
+
+    //trusted, appended to
+    let mut r = req.clone();
+    add_forwarded(&mut r, &Trust::From(vec![Cidr::from_str("192.0.2.0/24").unwrap()]), &peer);
+    assert_eq!( r.headers.get("X-Forwarded-For"), Some("10.9.9.9, 192.0.2.1"));
+    assert_eq!( r.headers.get("Forwarded"), Some("for=10.9.9.9, for=192.0.2.1;proto=https;host=\"example.com:8443\""));
+
+    //IPv6 client, no Host header
+    let addr = SocketAddr::from_str("[2001:db8::1]:5000").unwrap();
+    let mut r = req.clone();
+    r.headers.remove("Host");
+    add_forwarded(&mut r, &Trust::Nobody, &Peer { addr: Some(&addr), sni: Some("example.com"), tls: true });
+    assert_eq!( r.headers.get("Forwarded"), Some("for=\"[2001:db8::1]\";proto=https;host=\"example.com\""));
+
+    //a server name which isn't a host name is never copied into headers
+    let mut r = req.clone();
+    r.headers.remove("Host");
+    add_forwarded(&mut r, &Trust::Nobody, &Peer { addr: Some(&addr), sni: Some("a\r\nX-Admin: 1"), tls: true });
+    assert_eq!( r.headers.get("X-Forwarded-Host"), None);
+    assert_eq!( r.headers.get("Forwarded"), Some("for=\"[2001:db8::1]\";proto=https"));
+}
@@ -0,0 +1,276 @@
+
+use super::{
+    Version,
+    HttpError
+};
+use super::parse::{
+    RequestHead,
+    ResponseHead
+};
+use super::super::http::Header;
+use std::str::from_utf8;
+
+///Headers of a message being rewritten. Names keep the case they arrived with, but are matched
+///without regard to case. Order is kept. Values are kept as the bytes they arrived as, so
+///obs-text is passed on untouched.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct Headers {
+    list: Vec<(String,Vec<u8>)>
+}
+impl Headers {
+
+    pub fn new() -> Headers {
+        Headers {
+            list: Vec::new()
+        }
+    }
+
+    ///Copy parsed headers out of the buffer
+    pub fn from_parsed(headers: &[Header], buf: &[u8]) -> Result<Headers,HttpError> {
+        let mut list = Vec::with_capacity(headers.len());
+        for h in headers {
+            let name = from_utf8(h.name.get(buf)).map_err(|_| HttpError::BadHeader)?;
+            list.push((name.to_string(), h.value.get(buf).to_vec()));
+        }
+        Ok(Headers {
+            list: list
+        })
+    }
+
+    ///First value of a header. `None` if that value holds obs-text, see `get_raw`.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.get_raw(name).and_then(|x| from_utf8(x).ok())
+    }
+
+    ///First value of a header as it arrived
+    pub fn get_raw(&self, name: &str) -> Option<&[u8]> {
+        self.list.iter()
+            .find(|x| x.0.eq_ignore_ascii_case(name))
+            .map(|x| x.1.as_slice())
+    }
+
+    ///Every value of a header, in order. Values holding obs-text are skipped.
+    pub fn get_all<'a>(&'a self, name: &'a str) -> Box<dyn Iterator<Item=&'a str> + 'a> {
+        Box::new(self.list.iter()
+            .filter(move |x| x.0.eq_ignore_ascii_case(name))
+            .filter_map(|x| from_utf8(&x.1).ok()))
+    }
+
+    ///Every value of a header joined into one comma separated list. `None` if it isn't set.
+    pub fn joined(&self, name: &str) -> Option<String> {
+        let v: Vec<&str> = self.get_all(name).collect();
+        if v.is_empty() {
+            None
+        } else {
+            Some(v.join(", "))
+        }
+    }
+
+    #[inline(always)]
+    pub fn contains(&self, name: &str) -> bool {
+        self.get_raw(name).is_some()
+    }
+
+    ///Remove every instance of a header. Returns how many were removed.
+    pub fn remove(&mut self, name: &str) -> usize {
+        let len = self.list.len();
+        self.list.retain(|x| ! x.0.eq_ignore_ascii_case(name));
+        len - self.list.len()
+    }
+
+    ///Add a header after the others. Nothing is added if the name or value holds a CR, LF or
+    ///NUL, which would end the header line early.
+    #[inline(always)]
+    pub fn append(&mut self, name: &str, value: &str) {
+        self.append_raw(name, value.as_bytes());
+    }
+
+    ///Add a header after the others, as `append` does, from bytes
+    pub fn append_raw(&mut self, name: &str, value: &[u8]) {
+        if ! is_safe(name.as_bytes()) || ! is_safe(value) {
+            return;
+        }
+        self.list.push((name.to_string(), value.to_vec()));
+    }
+
+    ///Replace every instance of a header with a single one. It keeps the position of the first
+    ///instance, or goes last if it wasn't set. A name or value holding a CR, LF or NUL just
+    ///removes the header.
+    #[inline(always)]
+    pub fn set(&mut self, name: &str, value: &str) {
+        self.set_raw(name, value.as_bytes());
+    }
+
+    ///Replace every instance of a header, as `set` does, from bytes
+    pub fn set_raw(&mut self, name: &str, value: &[u8]) {
+        if ! is_safe(name.as_bytes()) || ! is_safe(value) {
+            self.remove(name);
+            return;
+        }
+        match self.list.iter().position(|x| x.0.eq_ignore_ascii_case(name)) {
+            Option::Some(i) => {
+                self.list[i].1 = value.to_vec();
+                let mut n = 0;
+                self.list.retain(|x| {
+                    if x.0.eq_ignore_ascii_case(name) {
+                        n += 1;
+                        n == 1
+                    } else {
+                        true
+                    }
+                });
+            },
+            Option::None => self.append_raw(name, value)
+        };
+    }
+
+    ///Iterate the headers in order
+    pub fn iter(&self) -> ::std::slice::Iter<(String,Vec<u8>)> {
+        self.list.iter()
+    }
+
+    #[inline(always)]
+    pub fn len(&self) -> usize {
+        self.list.len()
+    }
+
+    ///Write the header lines and the blank line ending the head
+    pub fn write_to(&self, out: &mut Vec<u8>) {
+        for &(ref name, ref value) in self.list.iter() {
+            out.extend_from_slice(name.as_bytes());
+            out.extend_from_slice(b": ");
+            out.extend_from_slice(value);
+            out.extend_from_slice(b"\r\n");
+        }
+        out.extend_from_slice(b"\r\n");
+    }
+}
+
+///If a header name or value can be written as it is. CR and LF would end the line, NUL is
+///read differently by different servers.
+#[inline(always)]
+fn is_safe(s: &[u8]) -> bool {
+    ! s.iter().any(|&b| b == b'\r' || b == b'\n' || b == 0)
+}
+
+///A request head which can be edited before it is sent on
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct Request {
+    pub method: String,
+    pub target: String,
+    pub version: Version,
+    pub headers: Headers
+}
+impl Request {
+
+    ///Copy a parsed request out of the buffer
+    pub fn from_parsed(head: &RequestHead, buf: &[u8]) -> Result<Request,HttpError> {
+        Ok(Request {
+            method: from_utf8(head.method.get(buf)).map_err(|_| HttpError::BadRequestLine)?.to_string(),
+            target: from_utf8(head.target.get(buf)).map_err(|_| HttpError::BadRequestLine)?.to_string(),
+            version: head.version,
+            headers: Headers::from_parsed(&head.headers, buf)?
+        })
+    }
+
+    pub fn write_to(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self.method.as_bytes());
+        out.push(b' ');
+        out.extend_from_slice(self.target.as_bytes());
+        out.push(b' ');
+        out.extend_from_slice(self.version.as_str().as_bytes());
+        out.extend_from_slice(b"\r\n");
+        self.headers.write_to(out);
+    }
+}
+
+///A response head which can be edited before it is sent on
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct Response {
+    pub version: Version,
+    pub status: u16,
+    pub reason: Vec<u8>,
+    pub headers: Headers
+}
+impl Response {
+
+    ///Build a response with no headers
+    pub fn new(status: u16, reason: &str) -> Response {
+        Response {
+            version: Version::Http11,
+            status: status,
+            reason: reason.as_bytes().to_vec(),
+            headers: Headers::new()
+        }
+    }
+
+    ///Copy a parsed response out of the buffer. The reason phrase is kept as bytes, like header
+    ///values.
+    pub fn from_parsed(head: &ResponseHead, buf: &[u8]) -> Result<Response,HttpError> {
+        Ok(Response {
+            version: head.version,
+            status: head.status,
+            reason: head.reason.get(buf).to_vec(),
+            headers: Headers::from_parsed(&head.headers, buf)?
+        })
+    }
+
+    pub fn write_to(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self.version.as_str().as_bytes());
+        out.extend_from_slice(format!(" {:03} ", self.status).as_bytes());
+        out.extend_from_slice(&self.reason);
+        out.extend_from_slice(b"\r\n");
+        self.headers.write_to(out);
+    }
+}
+#[test]
+fn test_headers() {
+    use super::{Limits,Status};
+    use super::parse::{parse_request,parse_response};
+
+    let buf = b"GET /x HTTP/1.1\r\nHost: a\r\nAccept: 1\r\naccept: 2\r\n\r\n";
+    let mut req = match parse_request(buf, &Limits::new()).unwrap() {
+        Status::Done(h) => Request::from_parsed(&h, buf).unwrap(),
+        Status::More => panic!("incomplete")
+    };
+    assert_eq!( req.headers.get("ACCEPT"), Some("1"));
+    assert_eq!( req.headers.joined("accept"), Some("1, 2".to_string()));
+    assert_eq!( req.headers.joined("nope"), None);
+
+    let mut out = Vec::new();
+    req.write_to(&mut out);
+    assert_eq!( out.as_slice(), &buf[..]);
+
+    req.headers.set("accept", "3");
+    req.headers.append("X-New", "y");
+    assert_eq!( req.headers.remove("host"), 1);
+    let mut out = Vec::new();
+    req.write_to(&mut out);
+    assert_eq!( out.as_slice(), &b"GET /x HTTP/1.1\r\nAccept: 3\r\nX-New: y\r\n\r\n"[..]);
+
+    //nothing can start a header line of its own
+    req.headers.append("X-Bad", "a\r\nX-Admin: 1");
+    req.headers.set("X-New", "b\0");
+    assert_eq!( req.headers.get("X-Bad"), None);
+    assert_eq!( req.headers.get("X-New"), None);
+
+    let mut resp = Response::new(404, "Not Found");
+    resp.headers.set("Content-Length", "0");
+    let mut out = Vec::new();
+    resp.write_to(&mut out);
+    assert_eq!( out.as_slice(), &b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n"[..]);
+
+    //obs-text is kept byte for byte, but isn't read as text
+    let buf = b"HTTP/1.1 200 \xc4\r\nX-Name: caf\xe9\r\n\r\n";
+    let resp = match parse_response(buf, &Limits::new()).unwrap() {
+        Status::Done(h) => Response::from_parsed(&h, buf).unwrap(),
+        Status::More => panic!("incomplete")
+    };
+    assert_eq!( resp.reason, b"\xc4".to_vec());
+    assert_eq!( resp.headers.get_raw("X-Name"), Some(&b"caf\xe9"[..]));
+    assert_eq!( resp.headers.get("X-Name"), None);
+    assert!( resp.headers.contains("X-Name"));
+    let mut out = Vec::new();
+    resp.write_to(&mut out);
+    assert_eq!( out.as_slice(), &buf[..]);
+}
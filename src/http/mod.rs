@@ -0,0 +1,282 @@
+
+pub mod parse;
+pub mod body;
+pub mod session;
+pub mod head;
+pub mod forwarded;
+pub mod route;
+pub mod date;
+pub mod files;
+pub mod redirect;
+pub mod policy;
+pub mod rewrite;
+pub mod pages;
+pub mod websocket;
+pub mod compress;
+pub mod cache;
+
+use self::forwarded::Trust;
+use self::route::Target;
+use self::redirect::Redirect;
+use self::policy::HeaderPolicy;
+use self::pages::ErrorPages;
+use self::compress::Compress;
+
+///A range of bytes inside the buffer a message was parsed from. Parsing doesn't copy, so
+///everything is a `Span` which is resolved against the buffer when needed.
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize
+}
+impl Span {
+
+    ///Get the bytes this refers too
+    #[inline(always)]
+    pub fn get<'a>(&self, buf: &'a [u8]) -> &'a [u8] {
+        &buf[self.start..self.end]
+    }
+
+    #[inline(always)]
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+}
+
+///A header's name and value
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub struct Header {
+    pub name: Span,
+    pub value: Span
+}
+impl Header {
+
+    ///Case insensitive name comparison
+    #[inline(always)]
+    pub fn is(&self, buf: &[u8], name: &str) -> bool {
+        self.name.get(buf).eq_ignore_ascii_case(name.as_bytes())
+    }
+}
+
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum Version {
+    Http10,
+    Http11
+}
+impl Version {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            &Version::Http10 => "HTTP/1.0",
+            &Version::Http11 => "HTTP/1.1"
+        }
+    }
+}
+
+///How much a peer may send before it is rejected
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub struct Limits {
+    ///Bytes in a request line
+    pub max_line: usize,
+    ///Bytes in a request head, the request line and headers together
+    pub max_head: usize,
+    ///Number of headers in a message head
+    pub max_headers: usize,
+    ///Bytes in a request body, `None` for no limit. Routes may set their own.
+    pub max_body: Option<u64>,
+    ///Bytes in a response head from a backend
+    pub max_response_head: usize
+}
+impl Limits {
+    pub fn new() -> Limits {
+        Limits {
+            max_line: 4096,
+            max_head: 8192,
+            max_headers: 100,
+            max_body: None,
+            max_response_head: 32768
+        }
+    }
+}
+
+///How `Expect: 100-continue` is handled
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum Expect {
+    ///Sent on to the backend, which answers with its own `100 Continue`
+    Forward,
+    ///Answered here once the request is routed and within its size limit, if no earlier
+    ///response is still due. The backend never sees it.
+    Answer
+}
+
+///How a listener treats HTTP clients
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct HttpConfig {
+    pub limits: Limits,
+    ///Whose forwarding headers are passed on to backends
+    pub trust: Trust,
+    ///Where requests go while the routing table is empty
+    pub target: Target,
+    ///Answer every request with a redirect to HTTPS instead
+    pub redirect: Option<Redirect>,
+    ///Enforced on responses, unless their route has its own
+    pub policy: HeaderPolicy,
+    ///Sent when a backend can't answer, unless the route has its own
+    pub pages: ErrorPages,
+    ///What is done with `Expect: 100-continue`, forwarded by default
+    pub expect: Expect,
+    ///Compress responses, unless the route has its own settings. Off by default.
+    pub compress: Option<Compress>
+}
+impl HttpConfig {
+    pub fn new() -> HttpConfig {
+        HttpConfig {
+            limits: Limits::new(),
+            trust: Trust::Nobody,
+            target: Target::Pool(0),
+            redirect: None,
+            policy: HeaderPolicy::new(),
+            pages: ErrorPages::new(),
+            expect: Expect::Forward,
+            compress: None
+        }
+    }
+
+    ///Serve a site from disk, rather than the first pool, when there are no routes
+    pub fn target(mut self, t: Target) -> HttpConfig {
+        self.target = t;
+        self
+    }
+
+    pub fn redirect(mut self, r: Redirect) -> HttpConfig {
+        self.redirect = Some(r);
+        self
+    }
+
+    pub fn policy(mut self, p: HeaderPolicy) -> HttpConfig {
+        self.policy = p;
+        self
+    }
+
+    pub fn limits(mut self, l: Limits) -> HttpConfig {
+        self.limits = l;
+        self
+    }
+
+    pub fn pages(mut self, p: ErrorPages) -> HttpConfig {
+        self.pages = p;
+        self
+    }
+
+    ///Answer `Expect: 100-continue` here, or send it on to the backend
+    pub fn expect(mut self, e: Expect) -> HttpConfig {
+        self.expect = e;
+        self
+    }
+
+    pub fn compress(mut self, c: Compress) -> HttpConfig {
+        self.compress = Some(c);
+        self
+    }
+}
+
+///Reason phrase for a status the proxy sends itself
+pub fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        206 => "Partial Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        303 => "See Other",
+        304 => "Not Modified",
+        307 => "Temporary Redirect",
+        308 => "Permanent Redirect",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        414 => "URI Too Long",
+        416 => "Range Not Satisfiable",
+        417 => "Expectation Failed",
+        431 => "Request Header Fields Too Large",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Error"
+    }
+}
+
+///Why a message couldn't be parsed
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum HttpError {
+    ///The request line is longer than `Limits::max_line`
+    LineTooLong,
+    ///The head is larger than `Limits::max_head`, or `Limits::max_response_head`
+    HeadTooLarge,
+    ///More headers than `Limits::max_headers`
+    TooManyHeaders,
+    BadRequestLine,
+    BadStatusLine,
+    BadVersion,
+    BadHeader,
+    BadContentLength,
+    ///More than one Content-Length, even if they agree
+    DuplicateContentLength,
+    ///A request with both Content-Length and Transfer-Encoding
+    LengthAndChunked,
+    ///A header line continued from the one before
+    ObsFold,
+    ///Whitespace between a header's name and its colon
+    SpaceBeforeColon,
+    BadTransferEncoding,
+    BadChunk,
+    ///The request body is larger than `Limits::max_body`
+    BodyTooLarge,
+    ///An `Expect` header other than `100-continue`
+    ExpectationFailed,
+    ///A backend switched protocols when it wasn't asked to, or without a proper WebSocket
+    ///handshake
+    BadUpgrade,
+    ///No route matches the request
+    NoRoute
+}
+impl HttpError {
+
+    ///Status a client is sent when its request is refused for this
+    pub fn status(&self) -> (u16, &'static str) {
+        let status = match self {
+            &HttpError::LineTooLong => 414,
+            &HttpError::HeadTooLarge | &HttpError::TooManyHeaders => 431,
+            &HttpError::BodyTooLarge => 413,
+            &HttpError::ExpectationFailed => 417,
+            _ => 400
+        };
+        (status, reason(status))
+    }
+
+    ///If a request refused for this could have been read differently by the backend, so is
+    ///likely a request smuggling attempt
+    pub fn is_smuggling(&self) -> bool {
+        match self {
+            &HttpError::DuplicateContentLength |
+            &HttpError::LengthAndChunked |
+            &HttpError::ObsFold |
+            &HttpError::SpaceBeforeColon |
+            &HttpError::BadChunk => true,
+            _ => false
+        }
+    }
+}
+#[test]
+fn test_http_error() {
+    assert!( HttpError::LengthAndChunked.is_smuggling() );
+    assert!( HttpError::ObsFold.is_smuggling() );
+    assert!( ! HttpError::BodyTooLarge.is_smuggling() );
+    assert_eq!( HttpError::LengthAndChunked.status().0, 400);
+}
+
+///Result of parsing something which may not have fully arrived
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum Status<T> {
+    Done(T),
+    More
+}
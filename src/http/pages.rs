@@ -0,0 +1,150 @@
+use super::reason;
+use super::files::{
+    Reply,
+    mime_type
+};
+use super::forwarded::Peer;
+use super::rewrite::{
+    Context,
+    Template,
+    request_id
+};
+use std::fs::File;
+use std::io::prelude::*;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{
+    AtomicPtr,
+    Ordering
+};
+
+///An error page read from disk. The text is a `rewrite::Template`, `$status`, `$reason`,
+///`$request_id` and `$time` are filled in for each response. `$$` is a `$`.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct Page {
+    text: Template,
+    content_type: &'static str
+}
+impl Page {
+
+    ///Read a template. The content type comes from the file's extension. An unknown variable
+    ///is an `InvalidData` error.
+    pub fn load(path: &Path) -> io::Result<Page> {
+        let mut text = String::new();
+        File::open(path)?.read_to_string(&mut text)?;
+        let text = Template::from_str(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(Page {
+            text: text,
+            content_type: mime_type(path)
+        })
+    }
+
+    ///Fill in the variables. Pages don't describe the client, so `$client_ip` is `unknown`.
+    pub fn render(&self, status: u16, id: u64) -> String {
+        let peer = Peer { addr: None, sni: None, tls: false };
+        let cx = Context {
+            peer: &peer,
+            id: id,
+            route: None,
+            method: "",
+            pool: None,
+            status: Some(status)
+        };
+        self.text.expand(&cx)
+    }
+}
+
+///Pages sent when a request can't be answered by its backend, by status. Statuses without a
+///page get a line of plain text.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct ErrorPages {
+    pages: Vec<(u16,Page)>
+}
+impl ErrorPages {
+
+    pub fn new() -> ErrorPages {
+        ErrorPages {
+            pages: Vec::new()
+        }
+    }
+
+    ///Use a template for a status, such as 502, 503 or 504
+    pub fn page(mut self, status: u16, p: Page) -> ErrorPages {
+        self.pages.retain(|x| x.0 != status);
+        self.pages.push((status, p));
+        self
+    }
+
+    ///Build the response. `id` is the request's number.
+    pub fn reply(&self, status: u16, id: u64) -> Reply {
+        let reason = reason(status);
+        let mut r = match self.pages.iter().find(|x| x.0 == status) {
+            Option::Some(&(_, ref p)) => {
+                let mut r = Reply::text(status, reason, &p.render(status, id));
+                r.head.headers.set("Content-Type", p.content_type);
+                r
+            },
+            Option::None => Reply::text(status, reason, &format!("{} {}\nrequest {}\n", status, reason, request_id(id)))
+        };
+        r.head.headers.set("Cache-Control", "no-store");
+        r
+    }
+}
+
+lazy_static! {
+    static ref PAGES: AtomicPtr<Vec<ErrorPages>> =
+        AtomicPtr::new(
+            Box::into_raw(
+                Box::new(
+                    Vec::with_capacity(4))));
+}
+
+///function to get the raw pointer to the array
+#[inline(always)]
+fn raw_ptr<'a>() -> &'a mut Vec<ErrorPages> {
+    let ptr: *mut Vec<ErrorPages> = PAGES.load(Ordering::Acquire);
+    unsafe {
+        match ptr.as_mut() {
+            Option::Some(x) => x,
+            Option::None => unreachable!()
+        }
+    }
+}
+
+///Store a set of pages so routes can refer to them. This must only be called during start up,
+///before any worker threads exist. Returns the index.
+pub fn add_pages(p: ErrorPages) -> usize {
+    let v = raw_ptr();
+    v.push(p);
+    v.len()-1
+}
+
+///Look up a set of pages
+#[inline(always)]
+pub fn get_pages<'a>(i: usize) -> Option<&'a ErrorPages> {
+    let v: &'a mut Vec<ErrorPages> = raw_ptr();
+    v.get(i)
+}
+#[test]
+fn test_error_pages() {
+    use super::files::TestDir;
+
+    let dir = TestDir::new("pages", &[
+        ("502.html", b"<h1>$status $reason</h1><p>$request_id at $time, $$5</p>"),
+        ("bad.html", b"$other")
+    ]);
+    let pages = ErrorPages::new().page(502, Page::load(&dir.root.join("502.html")).unwrap());
+    assert_eq!( Page::load(&dir.root.join("bad.html")).map_err(|e| e.kind()), Err(io::ErrorKind::InvalidData));
+
+    let r = pages.reply(502, 7);
+    assert_eq!( r.head.status, 502);
+    assert_eq!( r.head.headers.get("Content-Type"), Some("text/html; charset=utf-8"));
+    let body = String::from_utf8(r.body).unwrap();
+    assert!( body.starts_with(&format!("<h1>502 Bad Gateway</h1><p>{} at ", request_id(7))) );
+    assert!( body.ends_with(" GMT, $5</p>") );
+
+    let r = pages.reply(504, 8);
+    assert_eq!( r.head.reason, b"Gateway Timeout".to_vec());
+    assert!( String::from_utf8(r.body).unwrap().contains(&request_id(8)) );
+}
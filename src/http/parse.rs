@@ -0,0 +1,371 @@
+
+use super::{
+    Span,
+    Header,
+    Version,
+    Limits,
+    HttpError,
+    Status
+};
+
+///A parsed request line and headers. Everything points into the buffer it was parsed from.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct RequestHead {
+    pub method: Span,
+    pub target: Span,
+    pub version: Version,
+    pub headers: Vec<Header>,
+    ///Bytes in the head, including the final blank line
+    pub len: usize
+}
+
+///A parsed status line and headers. Everything points into the buffer it was parsed from.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct ResponseHead {
+    pub version: Version,
+    pub status: u16,
+    pub reason: Span,
+    pub headers: Vec<Header>,
+    ///Bytes in the head, including the final blank line
+    pub len: usize
+}
+
+///Find the first value of a header
+pub fn find<'a>(headers: &[Header], buf: &'a [u8], name: &str) -> Option<&'a [u8]> {
+    headers.iter()
+        .find(|h| h.is(buf, name))
+        .map(|h| h.value.get(buf))
+}
+
+///Check if a comma separated header, such as `Connection`, lists a token. Every instance of
+///the header is searched.
+pub fn has_token(headers: &[Header], buf: &[u8], name: &str, token: &str) -> bool {
+    headers.iter()
+        .filter(|h| h.is(buf, name))
+        .flat_map(|h| h.value.get(buf).split(|b| *b == b','))
+        .any(|t| trim(t).eq_ignore_ascii_case(token.as_bytes()))
+}
+
+impl RequestHead {
+
+    #[inline(always)]
+    pub fn method<'a>(&self, buf: &'a [u8]) -> &'a [u8] {
+        self.method.get(buf)
+    }
+
+    #[inline(always)]
+    pub fn header<'a>(&self, buf: &'a [u8], name: &str) -> Option<&'a [u8]> {
+        find(&self.headers, buf, name)
+    }
+
+    ///If the connection stays open after this request
+    pub fn keep_alive(&self, buf: &[u8]) -> bool {
+        keep_alive(self.version, &self.headers, buf)
+    }
+}
+
+impl ResponseHead {
+
+    #[inline(always)]
+    pub fn header<'a>(&self, buf: &'a [u8], name: &str) -> Option<&'a [u8]> {
+        find(&self.headers, buf, name)
+    }
+
+    ///If the connection stays open after this response
+    pub fn keep_alive(&self, buf: &[u8]) -> bool {
+        keep_alive(self.version, &self.headers, buf)
+    }
+}
+
+///HTTP/1.1 is persistent unless closed, HTTP/1.0 is closed unless kept alive
+fn keep_alive(v: Version, headers: &[Header], buf: &[u8]) -> bool {
+    match v {
+        Version::Http11 => ! has_token(headers, buf, "connection", "close"),
+        Version::Http10 => has_token(headers, buf, "connection", "keep-alive")
+    }
+}
+
+///Strip optional whitespace from both ends
+#[inline(always)]
+pub fn trim(x: &[u8]) -> &[u8] {
+    let start = x.iter().position(|b| *b != b' ' && *b != b'\t').unwrap_or(x.len());
+    let end = x.iter().rposition(|b| *b != b' ' && *b != b'\t').map(|i| i+1).unwrap_or(start);
+    &x[start..end]
+}
+
+///RFC 7230 token characters, used by methods and header names
+#[inline(always)]
+pub fn is_tchar(b: u8) -> bool {
+    match b {
+        b'!' | b'#' | b'$' | b'%' | b'&' | b'\'' | b'*' | b'+' | b'-' | b'.' |
+        b'^' | b'_' | b'`' | b'|' | b'~' => true,
+        _ => (b as char).is_ascii_alphanumeric()
+    }
+}
+
+///Characters allowed in a header value. Control characters other than tab are not.
+#[inline(always)]
+fn is_value_char(b: u8) -> bool {
+    b == b'\t' || (b >= 0x20 && b != 0x7f)
+}
+
+///Find where the head ends. Returns the length of the head including the blank line.
+#[inline(always)]
+fn head_len(buf: &[u8]) -> Option<usize> {
+    buf.windows(4).position(|w| w == b"\r\n\r\n").map(|i| i+4)
+}
+
+///Split the head into CRLF terminated lines. The blank line isn't included.
+fn lines(buf: &[u8], len: usize) -> Vec<Span> {
+    let mut v = Vec::new();
+    let mut start = 0;
+    let end = len - 2;
+    while start < end {
+        let i = match buf[start..end].windows(2).position(|w| w == b"\r\n") {
+            Option::Some(i) => start + i,
+            Option::None => end
+        };
+        v.push(Span{ start: start, end: i });
+        start = i + 2;
+    }
+    v
+}
+
+fn parse_version(x: &[u8]) -> Result<Version,HttpError> {
+    match x {
+        b"HTTP/1.1" => Ok(Version::Http11),
+        b"HTTP/1.0" => Ok(Version::Http10),
+        _ => Err(HttpError::BadVersion)
+    }
+}
+
+///Parse the header lines. `name: value` with no whitespace before the colon. Folded lines
+///aren't allowed either, as they are read differently by different servers.
+fn parse_headers(buf: &[u8], lines: &[Span], limits: &Limits) -> Result<Vec<Header>,HttpError> {
+    if lines.len() > limits.max_headers {
+        return Err(HttpError::TooManyHeaders);
+    }
+    let mut headers = Vec::with_capacity(lines.len());
+    for line in lines {
+        let x = line.get(buf);
+        if x.starts_with(b" ") || x.starts_with(b"\t") {
+            return Err(HttpError::ObsFold);
+        }
+        let colon = match x.iter().position(|b| *b == b':') {
+            Option::Some(i) if i > 0 => i,
+            _ => return Err(HttpError::BadHeader)
+        };
+        if x[colon-1] == b' ' || x[colon-1] == b'\t' {
+            return Err(HttpError::SpaceBeforeColon);
+        }
+        if ! x[..colon].iter().all(|b| is_tchar(*b)) {
+            return Err(HttpError::BadHeader);
+        }
+        let raw = &x[colon+1..];
+        if ! raw.iter().all(|b| is_value_char(*b)) {
+            return Err(HttpError::BadHeader);
+        }
+        let value = trim(raw);
+        let offset = line.start + colon + 1 + (value.as_ptr() as usize - raw.as_ptr() as usize);
+        headers.push(Header {
+            name: Span{ start: line.start, end: line.start + colon },
+            value: Span{ start: offset, end: offset + value.len() }
+        });
+    }
+    Ok(headers)
+}
+
+///Find the head, or decide it is bigger than `max`
+#[inline(always)]
+fn find_head(buf: &[u8], max: usize) -> Result<Status<usize>,HttpError> {
+    match head_len(buf) {
+        Option::Some(len) if len > max => Err(HttpError::HeadTooLarge),
+        Option::Some(len) => Ok(Status::Done(len)),
+        Option::None if buf.len() >= max => Err(HttpError::HeadTooLarge),
+        Option::None => Ok(Status::More)
+    }
+}
+
+///Check the request line, whether or not it has all arrived, isn't too long
+#[inline(always)]
+fn check_line(buf: &[u8], max: usize) -> Result<(),HttpError> {
+    let len = buf.windows(2).position(|w| w == b"\r\n").unwrap_or(buf.len());
+    if len > max {
+        Err(HttpError::LineTooLong)
+    } else {
+        Ok(())
+    }
+}
+
+///Parse a request head from the front of `buf`. Returns `Status::More` until the whole head
+///has arrived, so this can be called again as more bytes are read. Bytes after the head, the
+///body or pipelined requests, are left alone. Empty lines before the request line are skipped
+///and counted in the head's length, and with the request line they must fit in
+///`Limits::max_line`.
+pub fn parse_request(buf: &[u8], limits: &Limits) -> Result<Status<RequestHead>,HttpError> {
+    let mut skip = 0;
+    while buf[skip..].starts_with(b"\r\n") {
+        skip += 2;
+        if skip > limits.max_line {
+            return Err(HttpError::LineTooLong);
+        }
+    }
+    if skip == buf.len() || (skip + 1 == buf.len() && buf[skip] == b'\r') {
+        return Ok(Status::More);
+    }
+    check_line(&buf[skip..], limits.max_line - skip)?;
+    let len = match find_head(&buf[skip..], limits.max_head)? {
+        Status::Done(len) => len,
+        Status::More => return Ok(Status::More)
+    };
+    let head = &buf[skip..skip+len];
+    let lines = lines(head, len);
+
+    //request line, exactly `method SP target SP version`
+    let first = lines[0].get(head);
+    let mut parts = first.split(|b| *b == b' ');
+    let (method,target,version) = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(m),Some(t),Some(v),None) => (m,t,v),
+        _ => return Err(HttpError::BadRequestLine)
+    };
+    if method.is_empty() || ! method.iter().all(|b| is_tchar(*b)) {
+        return Err(HttpError::BadRequestLine);
+    }
+    if target.is_empty() || ! target.iter().all(|b| *b > 0x20 && *b < 0x7f) {
+        return Err(HttpError::BadRequestLine);
+    }
+    let version = parse_version(version)?;
+    let mut headers = parse_headers(head, &lines[1..], limits)?;
+    for h in headers.iter_mut() {
+        h.name.start += skip;
+        h.name.end += skip;
+        h.value.start += skip;
+        h.value.end += skip;
+    }
+    let m = skip;
+    let t = m + method.len() + 1;
+    Ok(Status::Done(RequestHead {
+        method: Span{ start: m, end: m + method.len() },
+        target: Span{ start: t, end: t + target.len() },
+        version: version,
+        headers: headers,
+        len: skip + len
+    }))
+}
+
+///Parse a response head from the front of `buf`. Like `parse_request` this can be called
+///again as more bytes are read.
+pub fn parse_response(buf: &[u8], limits: &Limits) -> Result<Status<ResponseHead>,HttpError> {
+    let len = match find_head(buf, limits.max_response_head)? {
+        Status::Done(len) => len,
+        Status::More => return Ok(Status::More)
+    };
+    let lines = lines(buf, len);
+
+    //status line, `version SP code SP reason`, the reason may be empty
+    let first = lines[0].get(buf);
+    if first.len() < 12 || first[8] != b' ' || (first.len() > 12 && first[12] != b' ') {
+        return Err(HttpError::BadStatusLine);
+    }
+    let version = parse_version(&first[..8])?;
+    let code = &first[9..12];
+    if ! code.iter().all(|b| (*b as char).is_ascii_digit()) {
+        return Err(HttpError::BadStatusLine);
+    }
+    let status = code.iter().fold(0u16, |acc,b| acc*10 + (*b - b'0') as u16);
+    if status < 100 {
+        return Err(HttpError::BadStatusLine);
+    }
+    let reason = if first.len() > 13 {
+        Span{ start: 13, end: first.len() }
+    } else {
+        Span{ start: first.len(), end: first.len() }
+    };
+    if ! reason.get(buf).iter().all(|b| is_value_char(*b)) {
+        return Err(HttpError::BadStatusLine);
+    }
+    let headers = parse_headers(buf, &lines[1..], limits)?;
+    Ok(Status::Done(ResponseHead {
+        version: version,
+        status: status,
+        reason: reason,
+        headers: headers,
+        len: len
+    }))
+}
+#[test]
+fn test_parse_request() {
+    let limits = Limits::new();
+    let buf = b"\r\nGET /index.html?a=b HTTP/1.1\r\nHost: example.com\r\nAccept:  */* \r\nConnection: keep-alive, Upgrade\r\n\r\nGET / HTTP/1.1\r\n";
+    let head = match parse_request(buf, &limits).unwrap() {
+        Status::Done(x) => x,
+        Status::More => panic!("incomplete")
+    };
+    assert_eq!( head.method(buf), b"GET");
+    assert_eq!( head.target.get(buf), b"/index.html?a=b");
+    assert_eq!( head.version, Version::Http11);
+    assert_eq!( head.headers.len(), 3);
+    assert_eq!( head.header(buf, "HOST"), Some(&b"example.com"[..]));
+    assert_eq!( head.header(buf, "accept"), Some(&b"*/*"[..]));
+    assert!( has_token(&head.headers, buf, "connection", "upgrade") );
+    assert!( head.keep_alive(buf) );
+    assert_eq!( &buf[head.len..], b"GET / HTTP/1.1\r\n");
+
+    //every prefix is incomplete
+    for i in 0..head.len {
+        assert_eq!( parse_request(&buf[..i], &limits), Ok(Status::More));
+    }
+
+    let bad: &[&[u8]] = &[
+        b"GET  / HTTP/1.1\r\n\r\n",
+        b"GET / HTTP/2.0\r\n\r\n",
+        b"G(T / HTTP/1.1\r\n\r\n",
+        b"GET / HTTP/1.1\r\nHost : a\r\n\r\n",
+        b"GET / HTTP/1.1\r\n: a\r\n\r\n",
+        b"GET / HTTP/1.1\r\nHost: a\r\n b\r\n\r\n",
+        b"GET / HTTP/1.1\r\nHost: a\x01\r\n\r\n",
+    ];
+    for b in bad {
+        assert!( parse_request(b, &limits).is_err() );
+    }
+    assert_eq!( parse_request(b"GET / HTTP/1.1\r\nHost : a\r\n\r\n", &limits), Err(HttpError::SpaceBeforeColon));
+    assert_eq!( parse_request(b"GET / HTTP/1.1\r\nHost: a\r\n\tb\r\n\r\n", &limits), Err(HttpError::ObsFold));
+
+    let small = Limits{ max_line: 24, max_head: 32, max_headers: 1, ..Limits::new() };
+    assert_eq!( parse_request(b"GET /aaaaaaaaaaaaaaaaaaaa", &small), Err(HttpError::LineTooLong));
+    //empty lines can't be sent forever
+    assert_eq!( parse_request(&b"\r\n".repeat(13), &small), Err(HttpError::LineTooLong));
+    assert_eq!( parse_request(b"\r\n\r\nGET /aaaaaaaaaaaaaaaa", &small), Err(HttpError::LineTooLong));
+    assert_eq!( parse_request(b"GET /a HTTP/1.1\r\nHost: aaaaaaaaaaaaaaaaaa", &small), Err(HttpError::HeadTooLarge));
+    assert_eq!( parse_request(b"GET / HTTP/1.0\r\na: b\r\nc: d\r\n\r\n", &small), Err(HttpError::TooManyHeaders));
+    let head = match parse_request(b"GET / HTTP/1.0\r\na: b\r\n\r\n", &small).unwrap() {
+        Status::Done(x) => x,
+        Status::More => panic!("incomplete")
+    };
+    assert!( ! head.keep_alive(b"GET / HTTP/1.0\r\na: b\r\n\r\n") );
+}
+#[test]
+fn test_parse_response() {
+    let limits = Limits::new();
+    let buf = b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n";
+    let head = match parse_response(buf, &limits).unwrap() {
+        Status::Done(x) => x,
+        Status::More => panic!("incomplete")
+    };
+    assert_eq!( head.status, 404);
+    assert_eq!( head.reason.get(buf), b"Not Found");
+    assert_eq!( head.header(buf, "content-length"), Some(&b"0"[..]));
+    assert_eq!( head.len, buf.len());
+
+    let buf = b"HTTP/1.0 204\r\n\r\n";
+    let head = match parse_response(buf, &limits).unwrap() {
+        Status::Done(x) => x,
+        Status::More => panic!("incomplete")
+    };
+    assert_eq!( head.status, 204);
+    assert_eq!( head.reason.get(buf), b"");
+
+    assert!( parse_response(b"HTTP/1.1 2x0 OK\r\n\r\n", &limits).is_err() );
+    assert!( parse_response(b"HTTP/1.1 200OK\r\n\r\n", &limits).is_err() );
+    assert_eq!( parse_response(b"HTTP/1.1 200 OK\r\n", &limits), Ok(Status::More));
+}
@@ -0,0 +1,164 @@
+
+use super::head::Headers;
+use std::sync::atomic::{
+    AtomicPtr,
+    Ordering
+};
+
+///How a policy header meets one the backend sent
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum Apply {
+    ///Always add it, next to any the backend sent
+    Add,
+    ///Remove the backend's and use this
+    Replace,
+    ///Only add it if the backend didn't send one
+    IfAbsent
+}
+
+///Headers enforced on responses to clients, so every backend doesn't need to set them
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct HeaderPolicy {
+    set: Vec<(String,String,Apply)>,
+    ///Removed from every response
+    hide: Vec<String>
+}
+impl HeaderPolicy {
+
+    ///A policy which leaves responses alone
+    pub fn new() -> HeaderPolicy {
+        HeaderPolicy {
+            set: Vec::new(),
+            hide: Vec::new()
+        }
+    }
+
+    ///A reasonable starting point. A year of HSTS, no MIME sniffing, no full referrers sent to
+    ///other sites, and the `Server` header hidden. Backends may set their own HSTS and
+    ///referrer policy. There's no content security policy as it depends on the site.
+    pub fn secure() -> HeaderPolicy {
+        HeaderPolicy::new()
+            .hsts(31536000, true, false, Apply::IfAbsent)
+            .header("X-Content-Type-Options", "nosniff", Apply::Replace)
+            .referrer_policy("strict-origin-when-cross-origin", Apply::IfAbsent)
+            .hide("Server")
+    }
+
+    ///Enforce any header
+    pub fn header(mut self, name: &str, value: &str, a: Apply) -> HeaderPolicy {
+        self.set.push((name.to_string(), value.to_string(), a));
+        self
+    }
+
+    ///Set `Strict-Transport-Security`
+    pub fn hsts(self, max_age: u64, subdomains: bool, preload: bool, a: Apply) -> HeaderPolicy {
+        let mut v = format!("max-age={}", max_age);
+        if subdomains {
+            v.push_str("; includeSubDomains");
+        }
+        if preload {
+            v.push_str("; preload");
+        }
+        self.header("Strict-Transport-Security", &v, a)
+    }
+
+    pub fn referrer_policy(self, v: &str, a: Apply) -> HeaderPolicy {
+        self.header("Referrer-Policy", v, a)
+    }
+
+    pub fn content_security_policy(self, v: &str, a: Apply) -> HeaderPolicy {
+        self.header("Content-Security-Policy", v, a)
+    }
+
+    ///Remove a header from every response, such as `Server`
+    pub fn hide(mut self, name: &str) -> HeaderPolicy {
+        self.hide.push(name.to_string());
+        self
+    }
+
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.set.is_empty() && self.hide.is_empty()
+    }
+
+    ///Enforce the policy on a response's headers. `Strict-Transport-Security` is only sent to
+    ///clients which connected over TLS, RFC 6797 doesn't allow it over plain HTTP.
+    pub fn apply(&self, headers: &mut Headers, tls: bool) {
+        for name in self.hide.iter() {
+            headers.remove(name);
+        }
+        for &(ref name, ref value, a) in self.set.iter() {
+            if ! tls && name.eq_ignore_ascii_case("Strict-Transport-Security") {
+                continue;
+            }
+            match a {
+                Apply::Add => headers.append(name, value),
+                Apply::Replace => headers.set(name, value),
+                Apply::IfAbsent if ! headers.contains(name) => headers.append(name, value),
+                Apply::IfAbsent => { }
+            };
+        }
+    }
+}
+
+lazy_static! {
+    static ref POLICIES: AtomicPtr<Vec<HeaderPolicy>> =
+        AtomicPtr::new(
+            Box::into_raw(
+                Box::new(
+                    Vec::with_capacity(4))));
+}
+
+///function to get the raw pointer to the array
+#[inline(always)]
+fn raw_ptr<'a>() -> &'a mut Vec<HeaderPolicy> {
+    let ptr: *mut Vec<HeaderPolicy> = POLICIES.load(Ordering::Acquire);
+    unsafe {
+        match ptr.as_mut() {
+            Option::Some(x) => x,
+            Option::None => unreachable!()
+        }
+    }
+}
+
+///Store a policy so routes can refer to it. This must only be called during start up, before
+///any worker threads exist. Returns the policy's index.
+pub fn add_policy(p: HeaderPolicy) -> usize {
+    let v = raw_ptr();
+    v.push(p);
+    v.len()-1
+}
+
+///Look up a policy
+#[inline(always)]
+pub fn get_policy<'a>(i: usize) -> Option<&'a HeaderPolicy> {
+    let v: &'a mut Vec<HeaderPolicy> = raw_ptr();
+    v.get(i)
+}
+#[test]
+fn test_header_policy() {
+    let p = HeaderPolicy::secure()
+        .content_security_policy("default-src 'self'", Apply::Replace)
+        .header("X-Frame-Options", "DENY", Apply::Add);
+    let mut h = Headers::new();
+    h.append("Server", "nginx/1.0");
+    h.append("Referrer-Policy", "no-referrer");
+    h.append("Content-Security-Policy", "default-src *");
+    h.append("X-Content-Type-Options", "sniff-away");
+    let mut plain = h.clone();
+    p.apply(&mut h, true);
+
+    assert!( ! h.contains("Server") );
+    assert_eq!( h.get("Strict-Transport-Security"), Some("max-age=31536000; includeSubDomains"));
+    //the backend's own is kept
+    assert_eq!( h.get("Referrer-Policy"), Some("no-referrer"));
+    assert_eq!( h.get_all("Content-Security-Policy").collect::<Vec<_>>(), vec!["default-src 'self'"]);
+    assert_eq!( h.get_all("X-Content-Type-Options").collect::<Vec<_>>(), vec!["nosniff"]);
+    assert_eq!( h.get("X-Frame-Options"), Some("DENY"));
+    assert!( HeaderPolicy::new().is_empty() );
+
+    //no HSTS over plain HTTP
+    p.apply(&mut plain, false);
+    assert!( ! plain.contains("Strict-Transport-Security") );
+    assert_eq!( plain.get("X-Frame-Options"), Some("DENY"));
+}
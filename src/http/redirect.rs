@@ -0,0 +1,148 @@
+
+use super::reason;
+use super::head::Request;
+use super::files::{
+    Reply,
+    Site,
+    serve
+};
+use std::path::PathBuf;
+
+///Where ACME HTTP-01 challenges are asked for
+pub const ACME_PREFIX: &'static str = "/.well-known/acme-challenge/";
+
+///How a plain HTTP listener sends clients to HTTPS
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct Redirect {
+    ///301 by default. 308 keeps the method and body of a POST.
+    pub status: u16,
+    ///Port of the HTTPS listener, when it isn't 443
+    pub port: Option<u16>,
+    ///Serve ACME challenges from this directory rather than redirecting them. It holds the
+    ///`.well-known/acme-challenge` directory, as a webroot does.
+    pub acme: Option<Site>
+}
+impl Redirect {
+
+    ///Redirect with a 301 to port 443
+    pub fn new() -> Redirect {
+        Redirect {
+            status: 301,
+            port: None,
+            acme: None
+        }
+    }
+
+    ///Redirect with `s` rather than 301. Panics unless it's 301, 302, 303, 307 or 308.
+    pub fn status(mut self, s: u16) -> Redirect {
+        assert!(is_redirect(s), "{} isn't a redirect status", s);
+        self.status = s;
+        self
+    }
+
+    ///Send clients to the HTTPS listener on port `p`
+    pub fn port(mut self, p: u16) -> Redirect {
+        self.port = if p == 443 { None } else { Some(p) };
+        self
+    }
+
+    ///Serve ACME HTTP-01 challenges from the webroot `webroot`. Directories have no index.
+    pub fn acme(mut self, webroot: PathBuf) -> Redirect {
+        self.acme = Some(Site::new(webroot).index(Vec::new()));
+        self
+    }
+
+    ///Answer a request. The location is the same host and target over HTTPS, so a browser
+    ///which then sees `Strict-Transport-Security` applies it to the name it asked for.
+    pub fn answer(&self, req: &Request) -> Reply {
+        match self.acme {
+            Option::Some(ref site) if req.target.starts_with(ACME_PREFIX) => return serve(site, req),
+            _ => { }
+        };
+        let host = match req.headers.get("Host").map(|h| h.trim()) {
+            Option::Some(h) if is_host(h) => h,
+            _ => return Reply::text(400, reason(400), "Bad Request\n")
+        };
+        //only origin-form targets, an absolute one would choose the host itself
+        if ! req.target.starts_with('/') {
+            return Reply::text(400, reason(400), "Bad Request\n");
+        }
+        let name = match host.rfind(':') {
+            Option::Some(i) if ! host[i..].contains(']') => &host[..i],
+            _ => host
+        };
+        let location = match self.port {
+            Option::Some(p) => format!("https://{}:{}{}", name, p, req.target),
+            Option::None => format!("https://{}{}", name, req.target)
+        };
+        let mut r = Reply::text(self.status, reason(self.status), "Moved to HTTPS\n");
+        r.head.headers.set("Location", &location);
+        if req.method == "HEAD" {
+            r.without_body()
+        } else {
+            r
+        }
+    }
+}
+
+///Check a Host header is a name or address, and a port. Anything else could be used to
+///send the client somewhere unexpected.
+fn is_host(h: &str) -> bool {
+    ! h.is_empty() && h.bytes().all(|b| match b {
+        b'a' ..= b'z' | b'A' ..= b'Z' | b'0' ..= b'9' | b'-' | b'.' | b':' | b'[' | b']' => true,
+        _ => false
+    })
+}
+
+///Check a status is one a redirect may be sent with
+pub fn is_redirect(status: u16) -> bool {
+    match status {
+        301 ..= 303 | 307 | 308 => true,
+        _ => false
+    }
+}
+
+#[test]
+fn test_redirect() {
+    use super::head::Headers;
+    use super::files::TestDir;
+    use super::Version;
+
+    let req = |method: &str, target: &str, host: Option<&str>| {
+        let mut h = Headers::new();
+        match host {
+            Some(x) => h.append("Host", x),
+            None => { }
+        };
+        Request {
+            method: method.to_string(),
+            target: target.to_string(),
+            version: Version::Http11,
+            headers: h
+        }
+    };
+    let r = Redirect::new().answer(&req("GET", "/a?b=1", Some("Example.com:80")));
+    assert_eq!( r.head.status, 301);
+    assert_eq!( r.head.headers.get("Location"), Some("https://Example.com/a?b=1"));
+
+    let r = Redirect::new().status(308).port(8443).answer(&req("HEAD", "/", Some("[::1]:80")));
+    assert_eq!( r.head.status, 308);
+    assert_eq!( r.head.headers.get("Location"), Some("https://[::1]:8443/"));
+    assert!( r.body.is_empty() );
+
+    assert_eq!( Redirect::new().answer(&req("GET", "/", None)).head.status, 400);
+    assert_eq!( Redirect::new().answer(&req("GET", "/", Some("evil.com/x"))).head.status, 400);
+    assert_eq!( Redirect::new().answer(&req("GET", "http://x/", Some("x"))).head.status, 400);
+    //no webroot, so challenges are redirected too
+    assert_eq!( Redirect::new().answer(&req("GET", "/.well-known/acme-challenge/t", Some("x"))).head.status, 301);
+
+    let dir = TestDir::new("acme", &[(".well-known/acme-challenge/tok", b"tok.key")]);
+    let r = Redirect::new().acme(dir.root.clone()).answer(&req("GET", "/.well-known/acme-challenge/tok", None));
+    assert_eq!( r.head.status, 200);
+    assert_eq!( r.file.map(|f| f.remaining), Some(7));
+}
+#[test]
+#[should_panic]
+fn test_redirect_status() {
+    Redirect::new().status(200);
+}
@@ -0,0 +1,434 @@
+
+use super::reason;
+use super::date;
+use super::head::Headers;
+use super::forwarded::Peer;
+use super::route::RouteError;
+use super::super::regex::Regex;
+use std::str::FromStr;
+use std::sync::{
+    Arc,
+    RwLock
+};
+use std::sync::atomic::{
+    AtomicUsize,
+    Ordering
+};
+use std::time::{
+    SystemTime,
+    UNIX_EPOCH
+};
+
+///Which messages a rule rewrites
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum Side {
+    Request,
+    Response
+}
+
+///Something a value can refer to
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum Var {
+    ///`$client_ip`
+    ClientIp,
+    ///`$sni`, the server name the client asked for
+    Sni,
+    ///`$request_id`, unique to each request
+    RequestId,
+    ///`$backend`, the pool the request went to. Empty when it was answered by the proxy.
+    Backend,
+    ///`$status` of a response, empty for a request
+    Status,
+    ///`$reason`, the standard reason phrase for the status
+    Reason,
+    ///`$time`, now as an HTTP date
+    Time
+}
+
+///A header value with variables in it. A `$` followed by a digit or `{` is left alone, so
+///`replace` rules can refer to the regex's groups.
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct Template {
+    parts: Vec<Result<String,Var>>
+}
+impl Template {
+
+    ///Fill in the variables
+    pub fn expand(&self, cx: &Context) -> String {
+        let mut s = String::new();
+        for p in self.parts.iter() {
+            match p {
+                &Ok(ref x) => s.push_str(x),
+                &Err(Var::ClientIp) => match cx.peer.addr {
+                    Option::Some(a) => s.push_str(&a.ip().to_string()),
+                    Option::None => s.push_str("unknown")
+                },
+                &Err(Var::Sni) => s.push_str(cx.peer.sni.unwrap_or("")),
+                &Err(Var::RequestId) => s.push_str(&request_id(cx.id)),
+                &Err(Var::Backend) => match cx.pool {
+                    Option::Some(p) => s.push_str(&p.to_string()),
+                    Option::None => { }
+                },
+                &Err(Var::Status) => match cx.status {
+                    Option::Some(x) => s.push_str(&x.to_string()),
+                    Option::None => { }
+                },
+                &Err(Var::Reason) => match cx.status {
+                    Option::Some(x) => s.push_str(reason(x)),
+                    Option::None => { }
+                },
+                &Err(Var::Time) => s.push_str(&date::now())
+            };
+        }
+        s
+    }
+}
+impl FromStr for Template {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Template,String> {
+        let mut parts = Vec::new();
+        let mut lit = String::new();
+        let mut rest = s;
+        while let Option::Some(i) = rest.find('$') {
+            lit.push_str(&rest[..i]);
+            rest = &rest[i+1..];
+            let len = rest.find(|c: char| ! (c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len());
+            let var = match &rest[..len] {
+                "client_ip" => Var::ClientIp,
+                "sni" => Var::Sni,
+                "request_id" => Var::RequestId,
+                "backend" => Var::Backend,
+                "status" => Var::Status,
+                "reason" => Var::Reason,
+                "time" => Var::Time,
+                "" if rest.starts_with('$') => {
+                    //`$$` is a `$`
+                    lit.push('$');
+                    rest = &rest[1..];
+                    continue;
+                },
+                x if x.is_empty() || x.starts_with(|c: char| c.is_ascii_digit()) => {
+                    lit.push('$');
+                    continue;
+                },
+                x => return Err(format!("unknown variable `${}`", x))
+            };
+            if ! lit.is_empty() {
+                parts.push(Ok(lit.clone()));
+                lit.clear();
+            }
+            parts.push(Err(var));
+            rest = &rest[len..];
+        }
+        lit.push_str(rest);
+        if ! lit.is_empty() {
+            parts.push(Ok(lit));
+        }
+        Ok(Template {
+            parts: parts
+        })
+    }
+}
+
+///When a rule applies. Every condition of a rule must hold.
+#[derive(Clone,Debug)]
+pub enum Cond {
+    ///`route=<name>`, the request took the route with this name
+    Route(String),
+    ///`method=<METHOD>`
+    Method(String),
+    ///`status=404` or `status=4xx`. Request rules with one never apply.
+    Status(u16,u16),
+    ///`header=<Name>` the header is present, `header=<Name>~<regex>` a value of it matches
+    Header(String,Option<Regex>)
+}
+impl Cond {
+    fn holds(&self, headers: &Headers, cx: &Context) -> bool {
+        match self {
+            &Cond::Route(ref x) => cx.route == Some(x.as_str()),
+            &Cond::Method(ref x) => x == cx.method,
+            &Cond::Status(lo, hi) => cx.status.map(|s| s >= lo && s <= hi).unwrap_or(false),
+            &Cond::Header(ref n, Option::None) => headers.contains(n),
+            &Cond::Header(ref n, Option::Some(ref r)) => headers.get_all(n).any(|v| r.is_match(v))
+        }
+    }
+}
+
+///What a rule does
+#[derive(Clone,Debug)]
+pub enum Action {
+    ///`add <Name> <value>`, next to any already there
+    Add(String,Template),
+    ///`set <Name> <value>`, replacing any already there
+    Set(String,Template),
+    ///`remove <Name>`
+    Remove(String),
+    ///`rename <Name> <New-Name>`
+    Rename(String,String),
+    ///`replace <Name> <regex> <value>`, substituting every match in each value
+    Replace(String,Regex,Template)
+}
+
+///A single rewrite
+#[derive(Clone,Debug)]
+pub struct Rule {
+    pub side: Side,
+    pub conds: Vec<Cond>,
+    pub action: Action
+}
+impl Rule {
+
+    ///Rewrite `headers` if the rule applies
+    pub fn apply(&self, headers: &mut Headers, cx: &Context) {
+        if ! self.conds.iter().all(|c| c.holds(headers, cx)) {
+            return;
+        }
+        match &self.action {
+            &Action::Add(ref n, ref t) => headers.append(n, &t.expand(cx)),
+            &Action::Set(ref n, ref t) => headers.set(n, &t.expand(cx)),
+            &Action::Remove(ref n) => {
+                headers.remove(n);
+            },
+            &Action::Rename(ref from, ref to) => {
+                let values: Vec<String> = headers.get_all(from).map(|x| x.to_string()).collect();
+                headers.remove(from);
+                for v in values.iter() {
+                    headers.append(to, v);
+                }
+            },
+            &Action::Replace(ref n, ref r, ref t) => {
+                let rep = t.expand(cx);
+                let values: Vec<String> = headers.get_all(n).map(|x| r.replace_all(x, rep.as_str())).collect();
+                headers.remove(n);
+                for v in values.iter() {
+                    headers.append(n, v);
+                }
+            }
+        };
+    }
+}
+
+///What rules know about a message
+pub struct Context<'a> {
+    pub peer: &'a Peer<'a>,
+    ///Number of the request, see `next_request_id`
+    pub id: u64,
+    ///Name of the route the request took
+    pub route: Option<&'a str>,
+    pub method: &'a str,
+    ///Pool the request went to
+    pub pool: Option<usize>,
+    ///Status of a response
+    pub status: Option<u16>
+}
+
+///Rewrite rules, applied in the order they're listed
+#[derive(Clone,Debug)]
+pub struct Rules {
+    list: Vec<Rule>
+}
+impl Rules {
+
+    pub fn new(list: Vec<Rule>) -> Rules {
+        Rules {
+            list: list
+        }
+    }
+
+    #[inline(always)]
+    pub fn len(&self) -> usize {
+        self.list.len()
+    }
+
+    ///If any rules rewrite this side
+    pub fn has(&self, side: Side) -> bool {
+        self.list.iter().any(|r| r.side == side)
+    }
+
+    ///Apply every rule for `side`
+    pub fn apply(&self, side: Side, headers: &mut Headers, cx: &Context) {
+        for r in self.list.iter().filter(|r| r.side == side) {
+            r.apply(headers, cx);
+        }
+    }
+}
+
+///Split the next word off `s`
+#[inline(always)]
+fn next_word<'a>(s: &mut &'a str) -> Option<&'a str> {
+    let t = s.trim_left();
+    if t.is_empty() {
+        return None;
+    }
+    let end = t.find(char::is_whitespace).unwrap_or(t.len());
+    *s = &t[end..];
+    Some(&t[..end])
+}
+
+impl FromStr for Rules {
+    type Err = RouteError;
+
+    ///Read rewrite rules, one a line.
+    ///
+    ///`<request|response> [<condition>]... <action> <Name> [<args>]`
+    ///
+    ///Conditions are `route=<name>`, `method=<METHOD>`, `status=<code>` or `status=4xx`, and
+    ///`header=<Name>` or `header=<Name>~<regex>`. Actions are `add <Name> <value>`,
+    ///`set <Name> <value>`, `remove <Name>`, `rename <Name> <New-Name>` and
+    ///`replace <Name> <regex> <value>`. A value is the rest of the line, and may use
+    ///`$client_ip`, `$sni`, `$request_id`, `$backend`, `$status`, `$reason` and `$time`.
+    ///Blank lines and lines starting with `#` are ignored.
+    fn from_str(s: &str) -> Result<Rules,RouteError> {
+        let mut list = Vec::new();
+        for (i, line) in s.lines().enumerate() {
+            let err = |reason: String| RouteError { line: i+1, reason: reason };
+            let mut rest = line.trim();
+            if rest.is_empty() || rest.starts_with('#') {
+                continue;
+            }
+            let side = match next_word(&mut rest) {
+                Option::Some("request") => Side::Request,
+                Option::Some("response") => Side::Response,
+                x => return Err(err(format!("expected request or response, not `{}`", x.unwrap_or(""))))
+            };
+            let mut conds = Vec::new();
+            let verb = loop {
+                let w = match next_word(&mut rest) {
+                    Option::Some(w) => w,
+                    Option::None => return Err(err("expected an action".to_string()))
+                };
+                if w.starts_with("route=") {
+                    conds.push(Cond::Route(w[6..].to_string()));
+                } else if w.starts_with("method=") {
+                    conds.push(Cond::Method(w[7..].to_string()));
+                } else if w.starts_with("status=") {
+                    conds.push(parse_status(&w[7..]).ok_or_else(|| err(format!("bad status `{}`", w)))?);
+                } else if w.starts_with("header=") {
+                    let c = match w[7..].find('~') {
+                        Option::Some(j) => {
+                            let r = Regex::new(&w[7+j+1..]).map_err(|e| err(format!("bad regex: {}", e)))?;
+                            Cond::Header(w[7..7+j].to_string(), Some(r))
+                        },
+                        Option::None => Cond::Header(w[7..].to_string(), None)
+                    };
+                    conds.push(c);
+                } else {
+                    break w;
+                }
+            };
+            let name = match next_word(&mut rest) {
+                Option::Some(n) => n.to_string(),
+                Option::None => return Err(err("expected a header name".to_string()))
+            };
+            let rest = rest.trim();
+            let template = |s: &str| Template::from_str(s).map_err(|e| err(e));
+            let action = match verb {
+                "add" => Action::Add(name, template(rest)?),
+                "set" => Action::Set(name, template(rest)?),
+                "remove" if rest.is_empty() => Action::Remove(name),
+                "rename" if ! rest.is_empty() && ! rest.contains(char::is_whitespace) => Action::Rename(name, rest.to_string()),
+                "replace" => {
+                    let mut args = rest;
+                    let r = match next_word(&mut args) {
+                        Option::Some(r) => Regex::new(r).map_err(|e| err(format!("bad regex: {}", e)))?,
+                        Option::None => return Err(err("expected a regex".to_string()))
+                    };
+                    Action::Replace(name, r, template(args.trim())?)
+                },
+                x => return Err(err(format!("bad action `{}`", x)))
+            };
+            list.push(Rule {
+                side: side,
+                conds: conds,
+                action: action
+            });
+        }
+        Ok(Rules::new(list))
+    }
+}
+
+///Read `404`, or a class such as `4xx`
+fn parse_status(s: &str) -> Option<Cond> {
+    if s.len() == 3 && s.ends_with("xx") {
+        let c = u16::from_str(&s[..1]).ok()?;
+        return Some(Cond::Status(c * 100, c * 100 + 99));
+    }
+    let c = u16::from_str(s).ok()?;
+    Some(Cond::Status(c, c))
+}
+
+lazy_static! {
+    static ref RULES: RwLock<Arc<Rules>> = RwLock::new(Arc::new(Rules::new(Vec::new())));
+    static ref STARTED: u64 = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+}
+static REQUESTS: AtomicUsize = AtomicUsize::new(0);
+
+///Number a request. Numbers are unique across workers.
+#[inline(always)]
+pub fn next_request_id() -> u64 {
+    REQUESTS.fetch_add(1, Ordering::Relaxed) as u64
+}
+
+///Format a request number as `$request_id`. The start time is included, so ids from before
+///a restart aren't repeated.
+pub fn request_id(id: u64) -> String {
+    format!("{:08x}{:012x}", *STARTED as u32, id)
+}
+
+///Replace the rewrite rules. They apply from the next message.
+pub fn set_rules(r: Rules) {
+    let mut guard = match RULES.write() {
+        Ok(g) => g,
+        Err(poison) => poison.into_inner()
+    };
+    *guard = Arc::new(r);
+}
+
+///Get the current rewrite rules
+pub fn get_rules() -> Arc<Rules> {
+    match RULES.read() {
+        Ok(g) => g.clone(),
+        Err(poison) => poison.into_inner().clone()
+    }
+}
+#[test]
+fn test_rewrite_rules() {
+    use std::net::SocketAddr;
+
+    let table = "
+        request  add X-Client $client_ip via $sni
+        request  method=POST set X-Request-Id $request_id
+        request  rename X-Old X-New
+        response status=4xx header=Content-Type~^text/ set X-Error yes $$5 $backend
+        response remove Server
+        response replace Set-Cookie ;\\s*[Ss]ecure ; HttpOnly$1
+    ";
+    let rules = Rules::from_str(table).unwrap();
+    let addr: SocketAddr = "10.0.0.1:443".parse().unwrap();
+    let peer = Peer { addr: Some(&addr), sni: Some("a.example"), tls: true };
+    let mut cx = Context { peer: &peer, id: 7, route: None, method: "GET", pool: Some(2), status: None };
+
+    let mut h = Headers::new();
+    h.append("X-Old", "1");
+    rules.apply(Side::Request, &mut h, &cx);
+    assert_eq!( h.get("X-Client"), Some("10.0.0.1 via a.example"));
+    assert!( ! h.contains("X-Request-Id") );
+    assert!( ! h.contains("X-Old") );
+    assert_eq!( h.get("X-New"), Some("1"));
+
+    cx.status = Some(404);
+    let mut h = Headers::new();
+    h.append("Server", "x");
+    h.append("Content-Type", "text/html");
+    h.append("Set-Cookie", "a=1; Secure");
+    rules.apply(Side::Response, &mut h, &cx);
+    assert_eq!( h.get("X-Error"), Some("yes $5 2"));
+    assert!( ! h.contains("Server") );
+    assert_eq!( h.get("Set-Cookie"), Some("a=1; HttpOnly"));
+
+    assert_eq!( Rules::from_str("request add X $nope").unwrap_err().line, 1);
+    assert!( Rules::from_str("sideways add X y").is_err() );
+    assert!( Rules::from_str("request status=abc remove X").is_err() );
+    assert!( Rules::from_str("request rename X").is_err() );
+}
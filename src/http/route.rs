@@ -0,0 +1,578 @@
+
+use super::head::Request;
+use super::redirect::is_redirect;
+use super::super::regex::Regex;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{
+    Arc,
+    RwLock
+};
+
+///Which hosts a route applies to. Hosts are compared lower case, without any port.
+#[derive(Clone,Debug)]
+pub enum HostMatch {
+    ///`*`
+    Any,
+    ///`example.com`
+    Exact(String),
+    ///`*.example.com` matches any name ending in `.example.com`, but not `example.com`
+    Suffix(String)
+}
+impl HostMatch {
+
+    ///Check a host, which must already be lower case
+    pub fn matches(&self, host: Option<&str>) -> bool {
+        match (self, host) {
+            (&HostMatch::Any,_) => true,
+            (&HostMatch::Exact(ref x),Option::Some(h)) => x == h,
+            (&HostMatch::Suffix(ref x),Option::Some(h)) => h.len() > x.len() && h.ends_with(x.as_str()),
+            (_,Option::None) => false
+        }
+    }
+}
+
+impl FromStr for HostMatch {
+    type Err = ();
+
+    ///Read `*`, `*.example.com` or `example.com`
+    fn from_str(s: &str) -> Result<HostMatch,()> {
+        if s.is_empty() {
+            Err(())
+        } else if s == "*" {
+            Ok(HostMatch::Any)
+        } else if s.starts_with("*.") {
+            Ok(HostMatch::Suffix(s[1..].to_ascii_lowercase()))
+        } else {
+            Ok(HostMatch::Exact(s.to_ascii_lowercase()))
+        }
+    }
+}
+
+///Which paths a route applies to. The query string isn't part of the path.
+#[derive(Clone,Debug)]
+pub enum PathMatch {
+    ///`*`
+    Any,
+    ///`=/exact`
+    Exact(String),
+    ///`/prefix`
+    Prefix(String),
+    ///`~^/v[0-9]+/`
+    Regex(Regex)
+}
+impl PathMatch {
+    fn matches(&self, path: &str) -> bool {
+        match self {
+            &PathMatch::Any => true,
+            &PathMatch::Exact(ref x) => x == path,
+            &PathMatch::Prefix(ref x) => path.starts_with(x.as_str()),
+            &PathMatch::Regex(ref r) => r.is_match(path)
+        }
+    }
+}
+
+///Where a request goes
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum Target {
+    ///A backend pool, by index
+    Pool(usize),
+    ///A site served from disk, by index
+    Files(usize)
+}
+impl FromStr for Target {
+    type Err = ();
+
+    ///Read a pool index, or `files:<site>`
+    fn from_str(s: &str) -> Result<Target,()> {
+        if s.starts_with("files:") {
+            usize::from_str(&s[6..]).map(Target::Files).map_err(|_| ())
+        } else {
+            usize::from_str(s).map(Target::Pool).map_err(|_| ())
+        }
+    }
+}
+
+///A change made to a request's path once it is routed. The query string is kept as it is.
+#[derive(Clone,Debug)]
+pub enum PathRewrite {
+    ///`strip=/api` removes the prefix, `/api/x` becomes `/x` but `/apix` is left alone
+    Strip(String),
+    ///`prefix=/v2` adds a prefix, `/x` becomes `/v2/x`
+    Prefix(String),
+    ///`rewrite=<regex> <replacement>`, the replacement may use `$1` or `${name}` for groups
+    Replace(Regex,String)
+}
+impl PathRewrite {
+    fn apply(&self, path: &str) -> String {
+        match self {
+            &PathRewrite::Strip(ref x) if path.starts_with(x.as_str()) => {
+                let rest = &path[x.len()..];
+                if rest.is_empty() {
+                    "/".to_string()
+                } else if rest.starts_with('/') {
+                    rest.to_string()
+                } else {
+                    path.to_string()
+                }
+            },
+            &PathRewrite::Strip(_) => path.to_string(),
+            &PathRewrite::Prefix(ref x) => format!("{}{}", x.trim_right_matches('/'), path),
+            &PathRewrite::Replace(ref r, ref rep) => r.replace_all(path, rep.as_str())
+        }
+    }
+}
+
+///Apply rewrites in order to a request target. Only targets which start with a path are
+///changed. The path is normalized before and after, see `normalize_path`.
+pub fn rewrite_target(list: &[PathRewrite], target: &str) -> String {
+    if ! target.starts_with('/') {
+        return target.to_string();
+    }
+    let (path, query) = match target.find('?') {
+        Option::Some(i) => target.split_at(i),
+        Option::None => (target, "")
+    };
+    let mut path = normalize_path(path);
+    for r in list {
+        path = r.apply(&path);
+    }
+    let mut path = normalize_path(&path);
+    path.push_str(query);
+    path
+}
+
+///Put a path in the form routes see. Escapes of unreserved characters are decoded and `.`
+///and `..` segments are removed (RFC 3986 section 5.2.4), so `/public/%2e%2e/admin` is
+///`/admin`. Other escapes are left as they are.
+pub fn normalize_path(path: &str) -> String {
+    let b = path.as_bytes();
+    let mut decoded = String::with_capacity(b.len());
+    let mut i = 0;
+    while i < b.len() {
+        let unreserved = match (b[i], b.get(i+1).and_then(|x| hex(*x)), b.get(i+2).and_then(|x| hex(*x))) {
+            (b'%',Option::Some(h),Option::Some(l)) => Some((h << 4) | l).filter(|c| is_unreserved(*c)),
+            _ => None
+        };
+        match unreserved {
+            Option::Some(c) => {
+                decoded.push(c as char);
+                i += 3;
+            },
+            Option::None => {
+                let len = path[i..].chars().next().map(|c| c.len_utf8()).unwrap_or(1);
+                decoded.push_str(&path[i..i+len]);
+                i += len;
+            }
+        };
+    }
+    let rest = decoded.trim_left_matches('/');
+    let lead = &decoded[..decoded.len()-rest.len()];
+    let parts: Vec<&str> = rest.split('/').collect();
+    let mut segs: Vec<&str> = Vec::with_capacity(parts.len());
+    for (i, seg) in parts.iter().enumerate() {
+        match *seg {
+            "." | ".." => {
+                if *seg == ".." {
+                    segs.pop();
+                }
+                //`/a/..` is the directory `/`
+                if i == parts.len() - 1 {
+                    segs.push("");
+                }
+            },
+            x => segs.push(x)
+        };
+    }
+    format!("{}{}", lead, segs.join("/"))
+}
+
+///Characters which mean the same escaped or not
+#[inline(always)]
+fn is_unreserved(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'-' || c == b'.' || c == b'_' || c == b'~'
+}
+
+#[inline(always)]
+fn hex(b: u8) -> Option<u8> {
+    (b as char).to_digit(16).map(|x| x as u8)
+}
+
+///A rule sending matching requests to a backend pool or site
+#[derive(Clone,Debug)]
+pub struct Route {
+    pub host: HostMatch,
+    pub path: PathMatch,
+    ///Only requests with this method
+    pub method: Option<String>,
+    ///Only requests with every one of these headers. Names are case insensitive, values aren't.
+    pub headers: Vec<(String,String)>,
+    pub target: Target,
+    ///Header policy for responses, in place of the listener's
+    pub policy: Option<usize>,
+    ///Lets rewrite rules refer to the route
+    pub name: Option<String>,
+    ///Applied to the path before it is forwarded
+    pub rewrites: Vec<PathRewrite>,
+    ///Send the client a redirect to the rewritten path, with this status, rather than
+    ///forwarding the request
+    pub redirect: Option<u16>,
+    ///Largest request body, in place of the listener's `Limits::max_body`
+    pub max_body: Option<u64>,
+    ///Error pages, in place of the listener's
+    pub pages: Option<usize>,
+    ///Compression settings, in place of the listener's
+    pub compress: Option<usize>,
+    ///Responses may be stored, and requests answered from the cache
+    pub cache: bool
+}
+impl Route {
+
+    ///Build a route sending everything to `target`
+    pub fn new(target: Target) -> Route {
+        Route {
+            host: HostMatch::Any,
+            path: PathMatch::Any,
+            method: None,
+            headers: Vec::new(),
+            target: target,
+            policy: None,
+            name: None,
+            rewrites: Vec::new(),
+            redirect: None,
+            max_body: None,
+            pages: None,
+            compress: None,
+            cache: false
+        }
+    }
+
+    pub fn host(mut self, h: HostMatch) -> Route {
+        self.host = h;
+        self
+    }
+
+    pub fn path(mut self, p: PathMatch) -> Route {
+        self.path = p;
+        self
+    }
+
+    pub fn method(mut self, m: &str) -> Route {
+        self.method = Some(m.to_string());
+        self
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Route {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn policy(mut self, p: usize) -> Route {
+        self.policy = Some(p);
+        self
+    }
+
+    pub fn name(mut self, n: &str) -> Route {
+        self.name = Some(n.to_string());
+        self
+    }
+
+    pub fn rewrite(mut self, r: PathRewrite) -> Route {
+        self.rewrites.push(r);
+        self
+    }
+
+    pub fn redirect(mut self, status: u16) -> Route {
+        self.redirect = Some(status);
+        self
+    }
+
+    pub fn max_body(mut self, n: u64) -> Route {
+        self.max_body = Some(n);
+        self
+    }
+
+    pub fn pages(mut self, p: usize) -> Route {
+        self.pages = Some(p);
+        self
+    }
+
+    pub fn compress(mut self, c: usize) -> Route {
+        self.compress = Some(c);
+        self
+    }
+
+    pub fn cache(mut self, b: bool) -> Route {
+        self.cache = b;
+        self
+    }
+
+    fn matches(&self, req: &Request, host: Option<&str>, path: &str) -> bool {
+        self.host.matches(host) &&
+        self.path.matches(path) &&
+        self.method.as_ref().map(|m| m == &req.method).unwrap_or(true) &&
+        self.headers.iter().all(|&(ref n, ref v)| req.headers.get_all(n).any(|x| x == v))
+    }
+}
+
+///Routes are tried in the order they're listed, and the first match wins
+#[derive(Clone,Debug)]
+pub struct Routes {
+    list: Vec<Route>
+}
+impl Routes {
+
+    pub fn new(list: Vec<Route>) -> Routes {
+        Routes {
+            list: list
+        }
+    }
+
+    #[inline(always)]
+    pub fn is_empty(&self) -> bool {
+        self.list.is_empty()
+    }
+
+    ///Every route
+    #[inline(always)]
+    pub fn iter(&self) -> ::std::slice::Iter<Route> {
+        self.list.iter()
+    }
+
+    ///Find the route a request takes. The Host header is used, or `sni` if there isn't one.
+    ///Paths are matched once normalized, see `normalize_path`.
+    pub fn find(&self, req: &Request, sni: Option<&str>) -> Option<&Route> {
+        let host = req.headers.get("Host")
+            .map(strip_port)
+            .or(sni)
+            .map(|x| x.to_ascii_lowercase());
+        let path = match req.target.find('?') {
+            Option::Some(i) => &req.target[..i],
+            Option::None => req.target.as_str()
+        };
+        let path = normalize_path(path);
+        self.list.iter()
+            .find(|r| r.matches(req, host.as_ref().map(|x| x.as_str()), &path))
+    }
+}
+
+///Remove the port from a Host header, minding IPv6 literals
+fn strip_port(h: &str) -> &str {
+    let h = h.trim();
+    if h.starts_with('[') {
+        return match h.find(']') {
+            Option::Some(i) => &h[..i+1],
+            Option::None => h
+        };
+    }
+    match h.rfind(':') {
+        Option::Some(i) => &h[..i],
+        Option::None => h
+    }
+}
+
+///Why a routing table couldn't be read
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub struct RouteError {
+    ///Line number, starting from 1
+    pub line: usize,
+    pub reason: String
+}
+impl fmt::Display for RouteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.reason)
+    }
+}
+
+impl FromStr for Routes {
+    type Err = RouteError;
+
+    ///Read a routing table, one route a line.
+    ///
+    ///`<host> <path> <target> [method=<METHOD>] [header=<Name>:<value>]... [policy=<n>] [name=<name>]`
+    ///
+    ///The target is a pool index, or `files:<site>` to serve a site from disk. Paths are
+    ///rewritten with `strip=<prefix>`, `prefix=<prefix>` and `rewrite=<regex> <replacement>`,
+    ///in the order given. `redirect=<status>` sends the client to the rewritten path instead.
+    ///`max_body=<bytes>` limits the size of request bodies. `errors=<n>` picks the pages sent
+    ///when the backend can't answer, and `compress=<n>` how responses are compressed. `cache`
+    ///answers requests from the response cache.
+    ///Hosts are `*`, `*.example.com` or `example.com`. Paths are `*`, `=/exact`, `/prefix` or
+    ///`~regex`. Blank lines and lines starting with `#` are ignored.
+    fn from_str(s: &str) -> Result<Routes,RouteError> {
+        let mut list = Vec::new();
+        for (i, line) in s.lines().enumerate() {
+            let err = |reason: String| RouteError { line: i+1, reason: reason };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            let (host, path, target) = match (words.next(), words.next(), words.next()) {
+                (Some(h),Some(p),Some(t)) => (h,p,t),
+                _ => return Err(err("expected a host, path and target".to_string()))
+            };
+            let target = Target::from_str(target).map_err(|_| err(format!("bad target `{}`", target)))?;
+            let host = HostMatch::from_str(host).map_err(|_| err(format!("bad host `{}`", host)))?;
+            let path = if path == "*" {
+                PathMatch::Any
+            } else if path.starts_with('=') {
+                PathMatch::Exact(path[1..].to_string())
+            } else if path.starts_with('~') {
+                PathMatch::Regex(Regex::new(&path[1..]).map_err(|e| err(format!("bad regex: {}", e)))?)
+            } else if path.starts_with('/') {
+                PathMatch::Prefix(path.to_string())
+            } else {
+                return Err(err(format!("bad path `{}`", path)));
+            };
+            let mut route = Route::new(target).host(host).path(path);
+            while let Option::Some(w) = words.next() {
+                if w.starts_with("method=") {
+                    route = route.method(&w[7..]);
+                } else if w.starts_with("policy=") {
+                    match usize::from_str(&w[7..]) {
+                        Ok(n) => route = route.policy(n),
+                        Err(_) => return Err(err(format!("bad policy `{}`", w)))
+                    };
+                } else if w.starts_with("strip=/") {
+                    route = route.rewrite(PathRewrite::Strip(w[6..].trim_right_matches('/').to_string()));
+                } else if w.starts_with("prefix=/") {
+                    route = route.rewrite(PathRewrite::Prefix(w[7..].to_string()));
+                } else if w.starts_with("rewrite=") {
+                    let r = Regex::new(&w[8..]).map_err(|e| err(format!("bad regex: {}", e)))?;
+                    match words.next() {
+                        Option::Some(rep) => route = route.rewrite(PathRewrite::Replace(r, rep.to_string())),
+                        Option::None => return Err(err(format!("`{}` needs a replacement", w)))
+                    };
+                } else if w.starts_with("redirect=") {
+                    match u16::from_str(&w[9..]) {
+                        Ok(x) if is_redirect(x) => route = route.redirect(x),
+                        _ => return Err(err(format!("bad redirect `{}`", w)))
+                    };
+                } else if w.starts_with("errors=") {
+                    match usize::from_str(&w[7..]) {
+                        Ok(n) => route = route.pages(n),
+                        Err(_) => return Err(err(format!("bad errors `{}`", w)))
+                    };
+                } else if w == "cache" {
+                    route = route.cache(true);
+                } else if w.starts_with("compress=") {
+                    match usize::from_str(&w[9..]) {
+                        Ok(n) => route = route.compress(n),
+                        Err(_) => return Err(err(format!("bad compress `{}`", w)))
+                    };
+                } else if w.starts_with("max_body=") {
+                    match u64::from_str(&w[9..]) {
+                        Ok(n) => route = route.max_body(n),
+                        Err(_) => return Err(err(format!("bad max_body `{}`", w)))
+                    };
+                } else if w.starts_with("name=") && w.len() > 5 {
+                    route = route.name(&w[5..]);
+                } else if w.starts_with("header=") {
+                    let mut kv = w[7..].splitn(2, ':');
+                    match (kv.next(), kv.next()) {
+                        (Some(n),Some(v)) if ! n.is_empty() => route = route.header(n, v),
+                        _ => return Err(err(format!("bad header match `{}`", w)))
+                    };
+                } else {
+                    return Err(err(format!("unknown option `{}`", w)));
+                }
+            }
+            list.push(route);
+        }
+        Ok(Routes::new(list))
+    }
+}
+
+lazy_static! {
+    static ref ROUTES: RwLock<Arc<Routes>> = RwLock::new(Arc::new(Routes::new(Vec::new())));
+}
+
+///Replace the routing table. Requests already routed keep their backend.
+pub fn set_routes(r: Routes) {
+    let mut guard = match ROUTES.write() {
+        Ok(g) => g,
+        Err(poison) => poison.into_inner()
+    };
+    *guard = Arc::new(r);
+}
+
+///Get the current routing table
+pub fn get_routes() -> Arc<Routes> {
+    match ROUTES.read() {
+        Ok(g) => g.clone(),
+        Err(poison) => poison.into_inner().clone()
+    }
+}
+#[test]
+fn test_routes() {
+    use super::head::Headers;
+    use super::Version;
+
+    let table = "
+        # api traffic
+        api.example.com  /v1/     1 compress=0 cache
+        *.example.com    ~^/v[0-9]+/  2  method=POST header=X-Beta:1
+        *                =/health files:3 policy=1 name=health errors=2
+        *                /old/    0 rewrite=^/old/(.*)$ /new/$1 redirect=308
+        *                /api/    4 strip=/api/ prefix=/v2 max_body=1024
+        *                *        0
+    ";
+    let routes = Routes::from_str(table).unwrap();
+    let req = |method: &str, target: &str, host: Option<&str>, beta: bool| {
+        let mut h = Headers::new();
+        match host {
+            Some(x) => h.append("Host", x),
+            None => { }
+        };
+        if beta {
+            h.append("x-beta", "1");
+        }
+        Request {
+            method: method.to_string(),
+            target: target.to_string(),
+            version: Version::Http11,
+            headers: h
+        }
+    };
+    assert_eq!( routes.find(&req("GET", "/v1/x?q=1", Some("API.example.com:443"), false), None).map(|r| r.target), Some(Target::Pool(1)));
+    assert_eq!( routes.find(&req("GET", "/v1/x", Some("api.example.com"), false), None).and_then(|r| r.compress), Some(0));
+    assert!( routes.find(&req("GET", "/v1/x", Some("api.example.com"), false), None).unwrap().cache );
+    assert_eq!( routes.find(&req("POST", "/v2/x", Some("www.example.com"), true), None).map(|r| r.target), Some(Target::Pool(2)));
+    assert_eq!( routes.find(&req("GET", "/v2/x", Some("www.example.com"), true), None).map(|r| r.target), Some(Target::Pool(0)));
+    //no Host header, so SNI is used
+    assert_eq!( routes.find(&req("GET", "/v1/", None, false), Some("api.example.com")).map(|r| r.target), Some(Target::Pool(1)));
+    assert_eq!( routes.find(&req("GET", "/health", None, false), None).map(|r| r.target), Some(Target::Files(3)));
+    assert_eq!( routes.find(&req("GET", "/health", None, false), None).and_then(|r| r.pages), Some(2));
+    assert_eq!( routes.find(&req("GET", "/health/x", None, false), None).map(|r| r.target), Some(Target::Pool(0)));
+
+    //path rewrites
+    let old = routes.find(&req("GET", "/old/a/b", None, false), None).unwrap();
+    assert_eq!( old.redirect, Some(308));
+    assert_eq!( rewrite_target(&old.rewrites, "/old/a/b?x=1"), "/new/a/b?x=1");
+    let api = routes.find(&req("GET", "/api/users", None, false), None).unwrap();
+    assert_eq!( rewrite_target(&api.rewrites, "/api/users?id=2"), "/v2/users?id=2");
+    assert_eq!( api.max_body, Some(1024));
+    assert_eq!( rewrite_target(&api.rewrites, "/api"), "/v2/");
+    //only whole segments are stripped
+    assert_eq!( rewrite_target(&api.rewrites, "/apix"), "/v2/apix");
+    assert_eq!( rewrite_target(&api.rewrites, "*"), "*");
+    //dot segments and escapes can't get past a route
+    assert_eq!( routes.find(&req("GET", "/health/%2e%2E", None, false), None).map(|r| r.target), Some(Target::Pool(0)));
+    assert_eq!( routes.find(&req("GET", "/x/../%61pi/../api/y", None, false), None).map(|r| r.target), Some(Target::Pool(4)));
+    assert_eq!( rewrite_target(&api.rewrites, "/api/./a/../../b?c=/../"), "/v2/b?c=/../");
+    assert_eq!( normalize_path("/a/b/.."), "/a/");
+    assert_eq!( normalize_path("/.."), "/");
+    assert_eq!( normalize_path("/a%2fb/%7euser/%41"), "/a%2fb/~user/A");
+    assert_eq!( normalize_path("//x/./y"), "//x/y");
+    assert!( Routes::from_str("* * 0 rewrite=^/x").is_err() );
+    assert!( Routes::from_str("* * 0 redirect=200").is_err() );
+    assert!( Routes::from_str("* * 0 max_body=-1").is_err() );
+
+    assert_eq!( Routes::from_str("* bad 0").unwrap_err().line, 1);
+    assert_eq!( Routes::from_str("\n* * x").unwrap_err().line, 2);
+    assert!( Routes::from_str("* ~( 0").is_err() );
+    assert!( Routes::from_str("* * 0 nope").is_err() );
+    assert_eq!( routes.iter().map(|r| r.policy).collect::<Vec<_>>(), vec![None, None, Some(1), None, None, None]);
+    assert_eq!( routes.iter().nth(2).and_then(|r| r.name.as_ref()).map(|x| x.as_str()), Some("health"));
+}
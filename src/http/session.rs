@@ -0,0 +1,1380 @@
+
+use super::{
+    reason,
+    Span,
+    Version,
+    Expect,
+    HttpConfig,
+    HttpError,
+    Status
+};
+use super::parse::{
+    parse_request,
+    parse_response,
+    find
+};
+use super::body::{
+    Body,
+    Framing,
+    request_body,
+    response_body
+};
+use super::head::{
+    Request,
+    Response
+};
+use super::policy::{
+    HeaderPolicy,
+    get_policy
+};
+use super::files::{
+    FileBody,
+    Reply,
+    get_site,
+    serve
+};
+use super::forwarded::{
+    FORWARDING,
+    Peer,
+    add_forwarded
+};
+use super::route::{
+    Target,
+    get_routes,
+    rewrite_target
+};
+use super::websocket::{
+    Upgrade,
+    wants_upgrade,
+    accepts
+};
+use super::compress::{
+    Coding,
+    Compress,
+    Encoder,
+    get_compress
+};
+use super::cache::{
+    Found,
+    Pending,
+    get_cache,
+    key
+};
+use super::date::unix_secs;
+use super::pages::{
+    ErrorPages,
+    get_pages
+};
+use super::rewrite::{
+    Context,
+    Side,
+    get_rules,
+    next_request_id
+};
+use super::super::buffer::Buffer;
+use super::super::pool::get_pool;
+use super::super::config::get_cache_budget;
+use super::super::metrics::{
+    Metric,
+    incr
+};
+use std::collections::VecDeque;
+use std::time::SystemTime;
+
+///Headers a client may not have removed by naming them in `Connection`, the backend needs
+///them to read the request
+const KEEP_NAMED: &'static [&'static str] = &[
+    "Host",
+    "Content-Length",
+    "Transfer-Encoding",
+    "Sec-WebSocket-Key",
+    "Sec-WebSocket-Version"
+];
+
+///Connection options sent on with a request to switch protocols, h2c needs its settings
+const UPGRADE_NAMED: &'static [&'static str] = &[
+    "Upgrade",
+    "HTTP2-Settings"
+];
+
+///Where a direction of the exchange is
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+enum Phase {
+    ///Waiting for a message head
+    Head,
+    ///Passing a body through
+    Body(Framing),
+    ///Throwing away the body of a request answered from disk
+    Skip(Framing),
+    ///A request to switch protocols was sent, nothing more is read until the backend answers
+    Upgrading,
+    ///The backend switched protocols, bytes are copied as they are
+    Tunnel,
+    ///Nothing more will be forwarded this way
+    Closed
+}
+
+///What routing decided for a request
+struct Routed {
+    target: Target,
+    ///Header policy of the route
+    policy: Option<usize>,
+    ///Name of the route
+    name: Option<String>,
+    ///The client is sent to the rewritten target with this status
+    redirect: Option<u16>,
+    ///Largest request body
+    max_body: Option<u64>,
+    ///Error pages of the route
+    pages: Option<usize>,
+    ///Compression settings of the route
+    compress: Option<usize>,
+    ///The route caches responses
+    cache: bool
+}
+
+///A response being read into the cache as it passes
+struct Capture {
+    pending: Pending,
+    head: Response,
+    body: Vec<u8>,
+    lifetime: u64,
+    now: u64
+}
+
+///A request forwarded to the backend, which hasn't had its final response. What's needed to
+///rewrite the response is kept.
+#[derive(Clone,Debug,PartialEq,Eq)]
+struct Waiting {
+    head: bool,
+    ///What the request asked to switch to
+    upgrade: Option<Upgrade>,
+    ///How the response may be compressed
+    compress: Option<(Coding,&'static Compress)>,
+    ///The response may be stored in the cache
+    cache: Option<Pending>,
+    policy: Option<usize>,
+    pages: Option<usize>,
+    id: u64,
+    method: String,
+    route: Option<String>,
+    ///The client reached the listener over TLS
+    tls: bool
+}
+
+///HTTP proxy mode state for a client and its backend. Requests are read from the client's
+///buffer and written toward the backend, responses the other way. Pipelined requests are
+///forwarded as they arrive, and the responses are matched to them in order. Each request is
+///routed to a backend pool, a request for a different pool than the backend's is held until
+///every earlier request has its response. Requests routed to a site, and every request on a
+///redirecting listener, are answered here once nothing is waiting on the backend.
+pub struct Session {
+    ///The listener's settings
+    pub cfg: &'static HttpConfig,
+    req: Phase,
+    resp: Phase,
+    ///Requests which haven't had a final response yet
+    waiting: VecDeque<Waiting>,
+    ///The client asked for the connection to close after this exchange, or a response can
+    ///only be ended by closing it
+    closing: bool,
+    ///The backend will close after its last response, so it can't be reused
+    backend_closing: bool,
+    ///Pool of the linked backend
+    pool: Option<usize>,
+    ///A file still being sent to the client. Later requests are held until it's done.
+    pub file: Option<FileBody>,
+    ///The request waiting for a backend from a new pool, answered if none can be had
+    held: Option<Waiting>,
+    ///What's left of the size limit for the chunked request body being read
+    body_left: Option<u64>,
+    ///Compressing the body of the response being forwarded
+    encoder: Option<Encoder>,
+    ///Storing the response being forwarded
+    capture: Option<Capture>,
+    spans: Vec<Span>
+}
+impl Session {
+
+    pub fn new(cfg: &'static HttpConfig) -> Session {
+        Session {
+            cfg: cfg,
+            req: Phase::Head,
+            resp: Phase::Head,
+            waiting: VecDeque::new(),
+            closing: false,
+            backend_closing: false,
+            pool: None,
+            file: None,
+            held: None,
+            body_left: None,
+            encoder: None,
+            capture: None,
+            spans: Vec::new()
+        }
+    }
+
+    ///If every request has been answered and the connection should now close
+    pub fn is_finished(&self) -> bool {
+        match self.resp {
+            Phase::Closed => true,
+            Phase::Head => self.closing && self.waiting.is_empty() && self.file.is_none(),
+            _ => false
+        }
+    }
+
+    ///A backend from `pool` has been linked
+    #[inline(always)]
+    pub fn routed(&mut self, pool: usize) {
+        self.pool = Some(pool);
+        self.backend_closing = false;
+        self.held = None;
+    }
+
+    ///Pool of the linked backend, `None` once it has said it will close
+    #[inline(always)]
+    pub fn pool(&self) -> Option<usize> {
+        self.pool
+    }
+
+    ///If the backend could be handed requests from another client. Every request must have
+    ///had its response, and the backend must not have said it will close.
+    pub fn backend_reusable(&self) -> bool {
+        let between = match (self.req, self.resp) {
+            (Phase::Body(_),_) | (_,Phase::Body(_)) => false,
+            (Phase::Upgrading,_) | (_,Phase::Tunnel) => false,
+            _ => true
+        };
+        between && self.pool.is_some() && ! self.backend_closing && self.waiting.is_empty()
+    }
+
+    ///Move as much of `input` as possible toward the backend, appending it to `out`. What isn't
+    ///a whole request head is left in `input` for next time. Request heads are rewritten to
+    ///describe `peer` on the way through. Responses made from disk are appended to `reply`,
+    ///which goes to the client, and a file which doesn't fit is left in `file`.
+    ///
+    ///Returns a pool when the next request must go to a backend from it, and the current
+    ///backend (if any) has nothing left to answer. Nothing more is forwarded until `routed` is
+    ///called for the new backend.
+    ///
+    ///A request which can't be read safely, or is over a size limit, is answered with a 400,
+    ///413, 414, 417 or 431, if it's the client's turn for an answer, and nothing more is read
+    ///from the client. A request over its body limit is refused before the client is told to
+    ///send the body with `100 Continue`.
+    pub fn on_request(&mut self, input: &mut Buffer, out: &mut Vec<u8>, reply: &mut Vec<u8>, peer: &Peer) -> Result<Option<usize>,HttpError> {
+        match self.requests(input, out, reply, peer) {
+            Err(HttpError::NoRoute) => Err(HttpError::NoRoute),
+            Err(e) => {
+                self.reject(&e, reply);
+                input.clear();
+                Err(e)
+            },
+            x => x
+        }
+    }
+
+    ///If the client and backend have switched protocols, and are just copied
+    #[inline(always)]
+    pub fn is_upgraded(&self) -> bool {
+        self.resp == Phase::Tunnel
+    }
+
+    ///If a request has been sent to the backend and none of its response has come back
+    #[inline(always)]
+    pub fn unanswered(&self) -> bool {
+        self.resp == Phase::Head && ! self.waiting.is_empty()
+    }
+
+    ///The backend couldn't be reached, broke off, or took too long. The oldest request still
+    ///waiting is sent an error page with `status`, unless part of its response has already
+    ///gone. Nothing more is read from the client, and the connection closes.
+    pub fn backend_failed(&mut self, status: u16, input: &mut Buffer, reply: &mut Vec<u8>) {
+        let w = match self.resp {
+            Phase::Head if self.file.is_none() => match self.waiting.pop_front() {
+                Option::Some(w) => Some(w),
+                Option::None => self.held.take()
+            },
+            _ => None
+        };
+        match w {
+            Option::Some(w) => {
+                let mut r = self.error_pages(w.pages).reply(status, w.id);
+                if w.head {
+                    r = r.without_body();
+                }
+                match self.policy(w.policy) {
+                    Option::Some(x) => x.apply(&mut r.head.headers, w.tls),
+                    Option::None => { }
+                };
+                r.head.headers.set("Connection", "close");
+                r.head.write_to(reply);
+                reply.extend_from_slice(&r.body);
+                self.resp = Phase::Head;
+            },
+            Option::None => self.resp = Phase::Closed
+        };
+        input.clear();
+        self.waiting.clear();
+        self.held = None;
+        self.encoder = None;
+        self.capture = None;
+        self.req = Phase::Closed;
+        self.closing = true;
+        self.pool = None;
+    }
+
+    ///Refuse the client's next request because of `e`, and stop reading. The refusal can only
+    ///be sent when it wouldn't be mixed up with a response still to come.
+    fn reject(&mut self, e: &HttpError, reply: &mut Vec<u8>) {
+        let turn = self.req == Phase::Head && self.resp == Phase::Head && self.waiting.is_empty() && self.file.is_none();
+        if turn {
+            let (status, reason) = e.status();
+            let mut r = Reply::text(status, reason, &format!("{}\n", reason));
+            r.head.headers.set("Connection", "close");
+            r.head.write_to(reply);
+            reply.extend_from_slice(&r.body);
+        }
+        self.req = Phase::Closed;
+        self.closing = true;
+    }
+
+    fn requests(&mut self, input: &mut Buffer, out: &mut Vec<u8>, reply: &mut Vec<u8>, peer: &Peer) -> Result<Option<usize>,HttpError> {
+        loop {
+            match self.req {
+                Phase::Closed => {
+                    //nothing after a `Connection: close` request is forwarded
+                    input.clear();
+                    return Ok(None);
+                },
+                //the handshake's answer decides what comes next
+                Phase::Upgrading => return Ok(None),
+                Phase::Tunnel => {
+                    out.extend_from_slice(input.as_slice());
+                    input.clear();
+                    return Ok(None);
+                },
+                Phase::Head => {
+                    if self.file.is_some() {
+                        return Ok(None);
+                    }
+                    let buf = input.as_slice();
+                    let head = match parse_request(buf, &self.cfg.limits)? {
+                        Status::Done(head) => head,
+                        Status::More => return Ok(None)
+                    };
+                    let body = request_body(&head, buf)?;
+                    let mut req = Request::from_parsed(&head, buf)?;
+                    let keep_alive = head.keep_alive(buf);
+                    let continues = expects_continue(&mut req, body)?;
+                    let id = next_request_id();
+                    let rules = get_rules();
+                    let mut policy = None;
+                    let mut name = None;
+                    let cfg = self.cfg;
+                    let mut r = match cfg.redirect {
+                        Option::Some(ref r) => {
+                            self.limit_body(body, cfg.limits.max_body)?;
+                            r.answer(&req)
+                        },
+                        Option::None => {
+                            let routed = self.target(&mut req, peer)?;
+                            let (cache_key, found) = lookup(&routed, &req, peer);
+                            match routed {
+                                Routed { target: Target::Pool(pool), policy: p, name: n, redirect: None, max_body, pages, compress, .. } if ! in_maintenance(pool) && ! found.is_hit() => {
+                                    if self.pool != Some(pool) {
+                                        let idle = self.waiting.is_empty() && self.resp == Phase::Head;
+                                        if self.pool.is_none() || idle {
+                                            self.held = Some(Waiting {
+                                                head: head.method(buf) == b"HEAD",
+                                                upgrade: None,
+                                                compress: None,
+                                                cache: None,
+                                                policy: p,
+                                                pages: pages,
+                                                id: id,
+                                                method: req.method.clone(),
+                                                route: n,
+                                                tls: peer.tls
+                                            });
+                                            return Ok(Some(pool));
+                                        }
+                                        //wait for the current backend to finish
+                                        return Ok(None);
+                                    }
+                                    self.limit_body(body, max_body)?;
+                                    if continues && self.cfg.expect == Expect::Answer && self.waiting.is_empty() && self.resp == Phase::Head {
+                                        req.headers.remove("Expect");
+                                        reply.extend_from_slice(b"HTTP/1.1 100 Continue\r\n\r\n");
+                                    }
+                                    add_forwarded(&mut req, &self.cfg.trust, peer);
+                                    if rules.has(Side::Request) {
+                                        let cx = Context {
+                                            peer: peer,
+                                            id: id,
+                                            route: n.as_ref().map(|x| x.as_str()),
+                                            method: &req.method,
+                                            pool: Some(pool),
+                                            status: None
+                                        };
+                                        rules.apply(Side::Request, &mut req.headers, &cx);
+                                    }
+                                    let upgrade = match body {
+                                        Body::None => wants_upgrade(&req),
+                                        _ => None
+                                    };
+                                    let cache = match cache_key {
+                                        Option::Some(k) => {
+                                            incr(Metric::CacheMiss);
+                                            let headers = req.headers.clone();
+                                            //a client checking its own copy is left to the backend
+                                            let own = req.headers.contains("If-None-Match") || req.headers.contains("If-Modified-Since");
+                                            let stale = match found {
+                                                Found::Stale(ref cond, ref stored) if ! own => {
+                                                    for &(n, ref v) in cond.iter() {
+                                                        req.headers.set(n, v);
+                                                    }
+                                                    Some(stored.clone())
+                                                },
+                                                _ => None
+                                            };
+                                            Some(Pending { key: k, headers: headers, stale: stale })
+                                        },
+                                        Option::None => None
+                                    };
+                                    keep_backend_open(&mut req, upgrade.is_some());
+                                    let compress = self.compressor(compress).and_then(|c| c.negotiate(&req).map(|x| (x, c)));
+                                    self.waiting.push_back(Waiting {
+                                        head: head.method(buf) == b"HEAD",
+                                        upgrade: upgrade.clone(),
+                                        compress: compress,
+                                        cache: cache,
+                                        policy: p,
+                                        pages: pages,
+                                        id: id,
+                                        method: req.method.clone(),
+                                        route: n,
+                                        tls: peer.tls
+                                    });
+                                    req.write_to(out);
+                                    input.consume(head.len);
+                                    self.req = match body {
+                                        Body::None if upgrade.is_some() => Phase::Upgrading,
+                                        Body::None if keep_alive => Phase::Head,
+                                        Body::None => Phase::Closed,
+                                        b => Phase::Body(Framing::new(b))
+                                    };
+                                    if ! keep_alive {
+                                        self.closing = true;
+                                    }
+                                    continue;
+                                },
+                                routed => {
+                                    policy = routed.policy;
+                                    name = routed.name;
+                                    //answers have to go in order
+                                    if ! self.waiting.is_empty() || self.resp != Phase::Head {
+                                        return Ok(None);
+                                    }
+                                    self.limit_body(body, routed.max_body)?;
+                                    match (routed.redirect, routed.target) {
+                                        (Option::Some(status),_) => {
+                                            let mut r = Reply::text(status, reason(status), "Moved\n");
+                                            r.head.headers.set("Location", &local_location(&req.target));
+                                            if req.method == "HEAD" {
+                                                r.without_body()
+                                            } else {
+                                                r
+                                            }
+                                        },
+                                        (Option::None,Target::Files(site)) => match get_site(site) {
+                                            Option::Some(x) => serve(x, &req),
+                                            Option::None => return Err(HttpError::NoRoute)
+                                        },
+                                        //from the cache, or the pool is in maintenance
+                                        (Option::None,Target::Pool(_)) => match found {
+                                            Found::Hit(hit) => {
+                                                incr(Metric::CacheHit);
+                                                //stored as the backend sent it, so it's compressed
+                                                //the way the backend's response would be
+                                                let c = self.compressor(routed.compress).and_then(|c| c.negotiate(&req).map(|x| (x, c)));
+                                                match c {
+                                                    Option::Some((x, c)) if c.applies(&hit.head, Body::Length(hit.body.len() as u64)) => {
+                                                        let mut head = hit.head;
+                                                        let body = c.encode(&mut head, x, &hit.body);
+                                                        Reply { head: head, body: body, file: None }
+                                                    },
+                                                    _ => hit.into_reply()
+                                                }
+                                            },
+                                            _ => {
+                                                let r = self.error_pages(routed.pages).reply(503, id);
+                                                if req.method == "HEAD" {
+                                                    r.without_body()
+                                                } else {
+                                                    r
+                                                }
+                                            }
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    };
+                    //answered here rather than by a backend. A client waiting for a `100 Continue`
+                    //may never send its body, so the connection can't be kept.
+                    let keep_alive = keep_alive && ! continues;
+                    if rules.has(Side::Response) {
+                        let cx = Context {
+                            peer: peer,
+                            id: id,
+                            route: name.as_ref().map(|x| x.as_str()),
+                            method: &req.method,
+                            pool: None,
+                            status: Some(r.head.status)
+                        };
+                        rules.apply(Side::Response, &mut r.head.headers, &cx);
+                    }
+                    match self.policy(policy) {
+                        Option::Some(x) => x.apply(&mut r.head.headers, peer.tls),
+                        Option::None => { }
+                    };
+                    if ! keep_alive {
+                        r.head.headers.set("Connection", "close");
+                        self.closing = true;
+                    }
+                    r.head.write_to(reply);
+                    reply.extend_from_slice(&r.body);
+                    self.file = r.file;
+                    input.consume(head.len);
+                    self.req = match body {
+                        Body::None if keep_alive => Phase::Head,
+                        Body::None => Phase::Closed,
+                        b => Phase::Skip(Framing::new(b))
+                    };
+                },
+                Phase::Body(mut f) => {
+                    if input.is_empty() {
+                        return Ok(None);
+                    }
+                    self.spans.clear();
+                    let n = f.advance(input.as_slice(), &mut self.spans)?;
+                    self.count_body()?;
+                    match f {
+                        //chunks are sent on without extensions or trailers, so the backend
+                        //can't read them differently
+                        Framing::Chunked(_) => write_chunks(&self.spans, input.as_slice(), f.is_done(), out),
+                        _ => out.extend_from_slice(&input.as_slice()[..n])
+                    };
+                    input.consume(n);
+                    self.req = if ! f.is_done() {
+                        Phase::Body(f)
+                    } else if self.closing {
+                        Phase::Closed
+                    } else {
+                        Phase::Head
+                    };
+                },
+                Phase::Skip(mut f) => {
+                    if input.is_empty() {
+                        return Ok(None);
+                    }
+                    self.spans.clear();
+                    let n = f.advance(input.as_slice(), &mut self.spans)?;
+                    self.count_body()?;
+                    input.consume(n);
+                    self.req = if ! f.is_done() {
+                        Phase::Skip(f)
+                    } else if self.closing {
+                        Phase::Closed
+                    } else {
+                        Phase::Head
+                    };
+                }
+            };
+        }
+    }
+
+    ///Route a request, and rewrite its target as the route says. Without a routing table it
+    ///goes to the listener's default.
+    fn target(&self, req: &mut Request, peer: &Peer) -> Result<Routed,HttpError> {
+        let routes = get_routes();
+        if routes.is_empty() {
+            return Ok(Routed {
+                target: self.cfg.target,
+                policy: None,
+                name: None,
+                redirect: None,
+                max_body: self.cfg.limits.max_body,
+                pages: None,
+                compress: None,
+                cache: false
+            });
+        }
+        let routed = match routes.find(req, peer.sni) {
+            Option::Some(r) => {
+                //the backend sees the path the route was chosen for
+                req.target = rewrite_target(&r.rewrites, &req.target);
+                Routed {
+                    target: r.target,
+                    policy: r.policy,
+                    name: r.name.clone(),
+                    redirect: r.redirect,
+                    max_body: r.max_body.or(self.cfg.limits.max_body),
+                    pages: r.pages,
+                    compress: r.compress,
+                    cache: r.cache
+                }
+            },
+            Option::None => return Err(HttpError::NoRoute)
+        };
+        Ok(routed)
+    }
+
+    ///Check a request body against its size limit. A length which is too large is refused
+    ///before anything is forwarded, a chunked body is counted as it passes.
+    fn limit_body(&mut self, body: Body, max: Option<u64>) -> Result<(),HttpError> {
+        self.body_left = None;
+        match (body, max) {
+            (Body::Length(n),Option::Some(m)) if n > m => Err(HttpError::BodyTooLarge),
+            (Body::Chunked,m) => {
+                self.body_left = m;
+                Ok(())
+            },
+            _ => Ok(())
+        }
+    }
+
+    ///Take the chunk data in `spans` from what's left of the body's limit. Once part of the
+    ///body has gone on the client can't be answered, and the connection is just closed.
+    fn count_body(&mut self) -> Result<(),HttpError> {
+        let left = match self.body_left {
+            Option::Some(x) => x,
+            Option::None => return Ok(())
+        };
+        let n = self.spans.iter().map(|s| s.len() as u64).sum::<u64>();
+        if n > left {
+            return Err(HttpError::BodyTooLarge);
+        }
+        self.body_left = Some(left - n);
+        Ok(())
+    }
+
+    ///The error pages for a request. A route's pages replace the listener's.
+    #[inline(always)]
+    fn error_pages(&self, route: Option<usize>) -> &'static ErrorPages {
+        let cfg = self.cfg;
+        route.and_then(get_pages).unwrap_or(&cfg.pages)
+    }
+
+    ///The compression settings for a response. A route's replace the listener's.
+    #[inline(always)]
+    fn compressor(&self, route: Option<usize>) -> Option<&'static Compress> {
+        let cfg = self.cfg;
+        match route {
+            Option::Some(i) => get_compress(i),
+            Option::None => cfg.compress.as_ref()
+        }
+    }
+
+    ///The header policy for a response. A route's policy replaces the listener's.
+    #[inline(always)]
+    fn policy(&self, route: Option<usize>) -> Option<&'static HeaderPolicy> {
+        let p = match route {
+            Option::Some(i) => get_policy(i),
+            Option::None => Some(&self.cfg.policy)
+        };
+        p.and_then(|x| if x.is_empty() { None } else { Some(x) })
+    }
+
+    ///Move as much of `input` as possible toward the client, appending it to `out`. `peer` is
+    ///the client, for rewrite rules which refer to it.
+    pub fn on_response(&mut self, input: &mut Buffer, out: &mut Vec<u8>, peer: &Peer) -> Result<(),HttpError> {
+        loop {
+            match self.resp {
+                Phase::Closed => {
+                    input.clear();
+                    return Ok(());
+                },
+                //only requests are skipped or wait on a handshake
+                Phase::Skip(_) | Phase::Upgrading => unreachable!(),
+                Phase::Tunnel => {
+                    out.extend_from_slice(input.as_slice());
+                    input.clear();
+                    return Ok(());
+                },
+                Phase::Head => {
+                    let buf = input.as_slice();
+                    let head = match parse_response(buf, &self.cfg.limits)? {
+                        Status::Done(head) => head,
+                        Status::More => return Ok(())
+                    };
+                    let (head_request, policy, upgrade, compress) = match self.waiting.front() {
+                        Option::Some(x) => (x.head, x.policy, x.upgrade.is_some(), x.compress),
+                        //a response nobody asked for
+                        Option::None => return Err(HttpError::BadStatusLine)
+                    };
+                    let body = response_body(&head, buf, head_request)?;
+                    let keep_alive = head.keep_alive(buf);
+                    let status = head.status;
+                    //Transfer-Encoding overrides Content-Length, which mustn't be passed on with
+                    //it (RFC 9112 section 6.3)
+                    let stray_length = find(&head.headers, buf, "transfer-encoding").is_some() &&
+                        find(&head.headers, buf, "content-length").is_some();
+                    if status == 101 {
+                        //only a request asking to switch may be switched, and a WebSocket
+                        //handshake must be accepted properly
+                        let resp = Response::from_parsed(&head, buf)?;
+                        let ok = match self.waiting.front().and_then(|w| w.upgrade.as_ref()) {
+                            Option::Some(u) => accepts(&resp, u),
+                            Option::None => false
+                        };
+                        if ! ok {
+                            return Err(HttpError::BadUpgrade);
+                        }
+                        out.extend_from_slice(&buf[..head.len]);
+                        input.consume(head.len);
+                        self.waiting.clear();
+                        self.req = Phase::Tunnel;
+                        self.resp = Phase::Tunnel;
+                        self.backend_closing = true;
+                        continue;
+                    }
+                    let rules = get_rules();
+                    let policy = self.policy(policy);
+                    let pending = if status >= 200 {
+                        self.waiting.front_mut().and_then(|w| w.cache.take())
+                    } else {
+                        None
+                    };
+                    //a stored response the backend said is still good, sent in place of the 304
+                    let mut cached = None;
+                    if stray_length || (status >= 200 && (policy.is_some() || rules.has(Side::Response) || compress.is_some() || pending.is_some())) {
+                        let mut resp = Response::from_parsed(&head, buf)?;
+                        if stray_length {
+                            resp.headers.remove("Content-Length");
+                        }
+                        match pending {
+                            Option::Some(p) => {
+                                let now = unix_secs(SystemTime::now());
+                                if status == 304 && p.stale.is_some() {
+                                    //the client didn't ask conditionally, so it's always sent the
+                                    //response the 304 is about rather than the 304
+                                    let refreshed = get_cache().refresh(&p, &resp, head_request, now);
+                                    match refreshed.map(|r| r.into_reply()) {
+                                        Option::Some(r) => {
+                                            resp = r.head;
+                                            cached = Some(r.body);
+                                        },
+                                        Option::None => { }
+                                    };
+                                } else {
+                                    match (p.lifetime(&resp, now), body) {
+                                        (Option::Some(_),_) if head_request => { },
+                                        (Option::Some(l),Body::None) => get_cache().store(&p, resp.clone(), Vec::new(), l, now),
+                                        (Option::Some(_),Body::Close) | (Option::None,_) => { },
+                                        (Option::Some(l),_) => self.capture = Some(Capture {
+                                            pending: p,
+                                            head: resp.clone(),
+                                            body: Vec::new(),
+                                            lifetime: l,
+                                            now: now
+                                        })
+                                    };
+                                }
+                            },
+                            Option::None => { }
+                        };
+                        match self.waiting.front() {
+                            Option::Some(w) => {
+                                let cx = Context {
+                                    peer: peer,
+                                    id: w.id,
+                                    route: w.route.as_ref().map(|x| x.as_str()),
+                                    method: &w.method,
+                                    pool: self.pool,
+                                    status: Some(resp.status)
+                                };
+                                rules.apply(Side::Response, &mut resp.headers, &cx);
+                            },
+                            Option::None => { }
+                        };
+                        match policy {
+                            Option::Some(p) => p.apply(&mut resp.headers, peer.tls),
+                            Option::None => { }
+                        };
+                        //compressed here, on the worker, as the body passes. A stored response sent
+                        //for a 304 is compressed whole.
+                        let sent = match cached {
+                            Option::Some(ref b) => Body::Length(b.len() as u64),
+                            Option::None => body
+                        };
+                        match compress {
+                            Option::Some((c, x)) if x.applies(&resp, sent) => match cached.take() {
+                                Option::Some(b) => cached = Some(x.encode(&mut resp, c, &b)),
+                                Option::None => self.encoder = Some(x.start(&mut resp, c))
+                            },
+                            _ => { }
+                        };
+                        resp.write_to(out);
+                        match cached {
+                            Option::Some(b) => out.extend_from_slice(&b),
+                            Option::None => { }
+                        };
+                    } else {
+                        out.extend_from_slice(&buf[..head.len]);
+                    }
+                    input.consume(head.len);
+                    if status >= 200 {
+                        self.waiting.pop_front();
+                        if upgrade {
+                            //the backend turned the handshake down, carry on with HTTP
+                            self.req = if self.closing { Phase::Closed } else { Phase::Head };
+                        }
+                    }
+                    if body == Body::Close {
+                        self.closing = true;
+                    }
+                    if ! keep_alive || body == Body::Close {
+                        self.backend_closing = true;
+                    }
+                    self.resp = match body {
+                        Body::None => self.after_response(),
+                        b => Phase::Body(Framing::new(b))
+                    };
+                },
+                Phase::Body(mut f) => {
+                    if input.is_empty() {
+                        return Ok(());
+                    }
+                    self.spans.clear();
+                    let n = f.advance(input.as_slice(), &mut self.spans)?;
+                    match self.encoder.as_mut() {
+                        Option::Some(e) => e.write(&self.spans, input.as_slice(), out),
+                        Option::None => out.extend_from_slice(&input.as_slice()[..n])
+                    };
+                    let full = match self.capture.as_mut() {
+                        Option::Some(c) => {
+                            for s in self.spans.iter() {
+                                c.body.extend_from_slice(s.get(input.as_slice()));
+                            }
+                            c.body.len() > get_cache_budget() / 8
+                        },
+                        Option::None => false
+                    };
+                    if full {
+                        //too big to be stored
+                        self.capture = None;
+                    }
+                    if f.is_done() {
+                        match self.encoder.take() {
+                            Option::Some(e) => e.finish(out),
+                            Option::None => { }
+                        };
+                        match self.capture.take() {
+                            Option::Some(c) => get_cache().store(&c.pending, c.head, c.body, c.lifetime, c.now),
+                            Option::None => { }
+                        };
+                    }
+                    input.consume(n);
+                    self.resp = if f.is_done() {
+                        self.after_response()
+                    } else {
+                        Phase::Body(f)
+                    };
+                }
+            };
+        }
+    }
+
+    ///What to expect after a response has been forwarded. If the backend is closing, later
+    ///requests need a new one.
+    #[inline(always)]
+    fn after_response(&mut self) -> Phase {
+        if self.closing && self.waiting.is_empty() {
+            self.req = Phase::Closed;
+            Phase::Closed
+        } else {
+            if self.backend_closing && self.waiting.is_empty() {
+                self.pool = None;
+            }
+            Phase::Head
+        }
+    }
+}
+
+///Check a request's `Expect` header, returns if the client will wait for a `100 Continue`
+///before sending its body. Only `100-continue` is understood, and only on HTTP/1.1 requests
+///with a body. It's dropped from anything else.
+fn expects_continue(req: &mut Request, body: Body) -> Result<bool,HttpError> {
+    let value = match req.headers.get("Expect") {
+        Option::Some(v) => v.trim().to_ascii_lowercase(),
+        Option::None => return Ok(false)
+    };
+    if req.version == Version::Http10 {
+        req.headers.remove("Expect");
+        return Ok(false);
+    }
+    if value != "100-continue" {
+        return Err(HttpError::ExpectationFailed);
+    }
+    if body == Body::None {
+        req.headers.remove("Expect");
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+///Look a request up in the response cache, if its route caches. The key it's stored under
+///is returned too, `None` if it can't be cached. A request without a `Host` is keyed by the
+///server name it was sent to, or else its route.
+fn lookup(routed: &Routed, req: &Request, peer: &Peer) -> (Option<String>,Found) {
+    let k = match (routed.cache, routed.target, routed.redirect) {
+        (true,Target::Pool(p),Option::None) if wants_upgrade(req).is_none() => {
+            let scope = match (peer.sni, routed.name.as_ref()) {
+                (Option::Some(sni),_) => sni.to_string(),
+                (Option::None,Option::Some(name)) => format!("@{}", name),
+                (Option::None,Option::None) => format!("@{}", p)
+            };
+            let route = match routed.name.as_ref() {
+                Option::Some(name) => format!("{} {}", p, name),
+                Option::None => p.to_string()
+            };
+            key(req, &scope, &route)
+        },
+        _ => None
+    };
+    match k {
+        Option::Some(k) => {
+            let found = get_cache().lookup(&k, req, unix_secs(SystemTime::now()));
+            (Some(k), found)
+        },
+        Option::None => (None, Found::Miss)
+    }
+}
+
+///A `Location` for a path on this server. Leading slashes are collapsed, and backslashes
+///which browsers read as slashes, so `//evil.com` can't send the client to another host.
+fn local_location(target: &str) -> String {
+    format!("/{}", target.trim_left_matches(|c| c == '/' || c == '\\'))
+}
+#[test]
+fn test_local_location() {
+    assert_eq!( local_location("/new/x?y=1"), "/new/x?y=1");
+    assert_eq!( local_location("//evil.com/x"), "/evil.com/x");
+    assert_eq!( local_location("/\\evil.com"), "/evil.com");
+}
+
+///If requests for a pool are being answered with a 503
+#[inline(always)]
+fn in_maintenance(pool: usize) -> bool {
+    get_pool(pool).map(|p| p.in_maintenance()).unwrap_or(false)
+}
+
+///Encode the data of a chunked body afresh
+fn write_chunks(spans: &[Span], buf: &[u8], done: bool, out: &mut Vec<u8>) {
+    for s in spans.iter().filter(|s| s.len() > 0) {
+        out.extend_from_slice(format!("{:x}\r\n", s.len()).as_bytes());
+        out.extend_from_slice(s.get(buf));
+        out.extend_from_slice(b"\r\n");
+    }
+    if done {
+        out.extend_from_slice(b"0\r\n\r\n");
+    }
+}
+
+///Stop a client's wish to close reaching the backend, so the backend connection can be
+///reused. Headers the client named in `Connection` are hop-by-hop and are removed with it
+///(RFC 7230 section 6.1), except the ones which frame the request or were written by the proxy.
+///`Upgrade` is only sent on for a request which may switch protocols.
+fn keep_backend_open(req: &mut Request, upgrade: bool) {
+    req.headers.remove("Keep-Alive");
+    req.headers.remove("Proxy-Connection");
+    let mut named: Vec<String> = Vec::new();
+    for value in req.headers.get_all("Connection") {
+        for o in value.split(',').map(|x| x.trim()) {
+            if ! o.is_empty() {
+                named.push(o.to_string());
+            }
+        }
+    }
+    req.headers.remove("Connection");
+    for n in named.iter() {
+        let kept = KEEP_NAMED.iter().chain(FORWARDING.iter()).any(|x| x.eq_ignore_ascii_case(n)) ||
+            (upgrade && UPGRADE_NAMED.iter().any(|x| x.eq_ignore_ascii_case(n)));
+        if ! kept {
+            req.headers.remove(n);
+        }
+    }
+    let mut options: Vec<&str> = Vec::new();
+    if upgrade {
+        options.extend(UPGRADE_NAMED.iter().filter(|x| req.headers.contains(x)));
+    } else {
+        //a request with a body, or a broken WebSocket handshake, may not switch protocols
+        req.headers.remove("Upgrade");
+    }
+    if req.version == Version::Http10 {
+        options.push("keep-alive");
+    }
+    if ! options.is_empty() {
+        req.headers.set("Connection", &options.join(", "));
+    }
+}
+#[test]
+fn test_session_pipelining() {
+    let cfg: &'static HttpConfig = Box::leak(Box::new(HttpConfig::new()));
+    let peer = Peer { addr: None, sni: None, tls: true };
+    let mut s = Session::new(cfg);
+    let mut client = Buffer::new();
+    let mut reply = Vec::new();
+    let mut backend = Vec::new();
+
+    //nothing goes anywhere until a backend is linked
+    client.extend(b"GET /z HTTP/1.1\r\n\r\n");
+    assert_eq!( s.on_request(&mut client, &mut backend, &mut reply, &peer), Ok(Some(0)));
+    assert!( backend.is_empty() );
+    client.clear();
+    s.routed(0);
+
+    //two pipelined requests, the second split across reads
+    client.extend(b"POST /a HTTP/1.1\r\nContent-Length: 3\r\n\r\nabcHEAD /b HTTP/1.1\r\n");
+    s.on_request(&mut client, &mut backend, &mut reply, &peer).unwrap();
+    assert_eq!( backend.as_slice(), &b"POST /a HTTP/1.1\r\nContent-Length: 3\r\nX-Forwarded-For: unknown\r\nX-Forwarded-Proto: https\r\nForwarded: for=unknown;proto=https\r\n\r\nabc"[..]);
+    assert_eq!( client.as_slice(), b"HEAD /b HTTP/1.1\r\n");
+    client.extend(b"Host: x\r\n\r\n");
+    s.on_request(&mut client, &mut backend, &mut reply, &peer).unwrap();
+    assert!( client.is_empty() );
+    assert!( backend.ends_with(b"HEAD /b HTTP/1.1\r\nHost: x\r\nX-Forwarded-For: unknown\r\nX-Forwarded-Proto: https\r\nX-Forwarded-Host: x\r\nForwarded: for=unknown;proto=https;host=\"x\"\r\n\r\n") );
+
+    //responses, the HEAD response has no body despite its length
+    let mut server = Buffer::new();
+    let mut out = Vec::new();
+    server.extend(b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nxyz\r\n0\r\n\r\n");
+    server.extend(b"HTTP/1.1 200 OK\r\nContent-Length: 50\r\n\r\n");
+    s.on_response(&mut server, &mut out, &peer).unwrap();
+    assert!( server.is_empty() );
+    assert!( out.ends_with(b"Content-Length: 50\r\n\r\n") );
+    assert!( ! s.is_finished() );
+
+    //closing after the last response
+    client.extend(b"GET /c HTTP/1.1\r\nConnection: close\r\n\r\nGET /ignored HTTP/1.1\r\n\r\n");
+    let len = backend.len();
+    s.on_request(&mut client, &mut backend, &mut reply, &peer).unwrap();
+    //the client closing doesn't close the backend
+    assert!( backend[len..].starts_with(b"GET /c HTTP/1.1\r\nX-Forwarded-For") );
+    assert!( ! backend[len..].windows(8).any(|w| w == b"/ignored") );
+    server.extend(b"HTTP/1.1 204 No Content\r\n\r\n");
+    s.on_response(&mut server, &mut out, &peer).unwrap();
+    assert!( s.is_finished() );
+    assert!( s.backend_reusable() );
+}
+#[test]
+fn test_session_backend_reuse() {
+    let cfg: &'static HttpConfig = Box::leak(Box::new(HttpConfig::new()));
+    let peer = Peer { addr: None, sni: None, tls: true };
+    let mut s = Session::new(cfg);
+    s.routed(0);
+    let mut client = Buffer::new();
+    let mut reply = Vec::new();
+    let mut server = Buffer::new();
+    let mut out = Vec::new();
+
+    //not reusable while a response is due
+    client.extend(b"GET / HTTP/1.1\r\n\r\n");
+    assert_eq!( s.on_request(&mut client, &mut out, &mut reply, &peer), Ok(None));
+    assert!( ! s.backend_reusable() );
+
+    //the backend closes, so the next request needs another
+    server.extend(b"HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 2\r\n\r\nok");
+    s.on_response(&mut server, &mut out, &peer).unwrap();
+    assert!( ! s.backend_reusable() );
+    assert!( ! s.is_finished() );
+    client.extend(b"GET /next HTTP/1.1\r\n\r\n");
+    assert_eq!( s.on_request(&mut client, &mut out, &mut reply, &peer), Ok(Some(0)));
+    s.routed(0);
+    assert!( s.backend_reusable() );
+
+    //HTTP/1.0 clients close, but the backend is asked to stay open
+    let mut s = Session::new(cfg);
+    s.routed(0);
+    let mut out = Vec::new();
+    client.clear();
+    client.extend(b"GET / HTTP/1.0\r\nConnection: close\r\n\r\n");
+    s.on_request(&mut client, &mut out, &mut reply, &peer).unwrap();
+    assert!( out.windows(24).any(|w| w == b"\r\nConnection: keep-alive") );
+    assert!( ! out.windows(5).any(|w| w == b"close") );
+
+    //headers named in `Connection` go with it, unless the backend needs them
+    let mut s = Session::new(cfg);
+    s.routed(0);
+    let mut out = Vec::new();
+    client.clear();
+    client.extend(b"GET / HTTP/1.1\r\nHost: x\r\nX-Secret: 1\r\nConnection: X-Secret, Host, X-Forwarded-For\r\n\r\n");
+    s.on_request(&mut client, &mut out, &mut reply, &peer).unwrap();
+    assert!( ! out.windows(8).any(|w| w == b"X-Secret") );
+    assert!( ! out.windows(11).any(|w| w == b"Connection:") );
+    assert!( out.windows(9).any(|w| w == b"\r\nHost: x") );
+    assert!( out.windows(17).any(|w| w == b"\r\nX-Forwarded-For") );
+
+    //the listener's header policy is enforced on final responses
+    let cfg: &'static HttpConfig = Box::leak(Box::new(HttpConfig::new().policy(HeaderPolicy::secure())));
+    let mut s = Session::new(cfg);
+    s.routed(0);
+    client.extend(b"GET / HTTP/1.1\r\n\r\n");
+    s.on_request(&mut client, &mut out, &mut reply, &peer).unwrap();
+    let mut out = Vec::new();
+    server.extend(b"HTTP/1.1 103 Early Hints\r\nServer: x\r\n\r\nHTTP/1.1 200 OK\r\nServer: x\r\nContent-Length: 0\r\n\r\n");
+    s.on_response(&mut server, &mut out, &peer).unwrap();
+    assert_eq!( out.as_slice(), &b"HTTP/1.1 103 Early Hints\r\nServer: x\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: 0\r\nStrict-Transport-Security: max-age=31536000; includeSubDomains\r\nX-Content-Type-Options: nosniff\r\nReferrer-Policy: strict-origin-when-cross-origin\r\n\r\n"[..]);
+
+    //a response both chunked and with a length is sent on as chunked only
+    let cfg: &'static HttpConfig = Box::leak(Box::new(HttpConfig::new()));
+    let mut s = Session::new(cfg);
+    s.routed(0);
+    client.extend(b"GET / HTTP/1.1\r\n\r\n");
+    s.on_request(&mut client, &mut out, &mut reply, &peer).unwrap();
+    let mut out = Vec::new();
+    server.extend(b"HTTP/1.1 200 OK\r\nContent-Length: 9\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nxyz\r\n0\r\n\r\n");
+    s.on_response(&mut server, &mut out, &peer).unwrap();
+    assert_eq!( out.as_slice(), &b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nxyz\r\n0\r\n\r\n"[..]);
+}
+#[test]
+fn test_session_errors() {
+    use super::Limits;
+
+    let cfg: &'static HttpConfig = Box::leak(Box::new(HttpConfig::new()));
+    let peer = Peer { addr: None, sni: None, tls: true };
+    let mut s = Session::new(cfg);
+    let mut client = Buffer::new();
+    let mut reply = Vec::new();
+    let mut backend = Vec::new();
+    client.extend(b"GET / HTTP/1.1\r\nBad Header: x\r\n\r\n");
+    assert_eq!( s.on_request(&mut client, &mut backend, &mut reply, &peer), Err(HttpError::BadHeader));
+    assert!( backend.is_empty() );
+    assert!( reply.starts_with(b"HTTP/1.1 400 Bad Request\r\n") );
+    assert!( s.is_finished() );
+
+    //smuggling attempts are refused
+    let mut s = Session::new(cfg);
+    s.routed(0);
+    reply.clear();
+    client.clear();
+    client.extend(b"POST / HTTP/1.1\r\nContent-Length: 4\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n");
+    assert_eq!( s.on_request(&mut client, &mut backend, &mut reply, &peer), Err(HttpError::LengthAndChunked));
+    assert!( backend.is_empty() && client.is_empty() );
+    assert!( reply.starts_with(b"HTTP/1.1 400 Bad Request\r\n") );
+
+    //chunked bodies are sent on without extensions or trailers
+    let mut s = Session::new(cfg);
+    s.routed(0);
+    client.extend(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3;a=b\r\nabc\r\n0\r\nX-Trailer: 1\r\n\r\n");
+    s.on_request(&mut client, &mut backend, &mut reply, &peer).unwrap();
+    assert!( backend.ends_with(b"\r\n\r\n3\r\nabc\r\n0\r\n\r\n") );
+    backend.clear();
+
+    //size limits
+    let limits = Limits { max_line: 16, max_body: Some(4), ..Limits::new() };
+    let small: &'static HttpConfig = Box::leak(Box::new(HttpConfig::new().limits(limits)));
+    let mut s = Session::new(small);
+    client.clear();
+    reply.clear();
+    client.extend(b"GET /aaaaaaaaaaaaaaaa HTTP/1.1\r\n\r\n");
+    assert_eq!( s.on_request(&mut client, &mut backend, &mut reply, &peer), Err(HttpError::LineTooLong));
+    assert!( reply.starts_with(b"HTTP/1.1 414 URI Too Long\r\n") );
+    let mut s = Session::new(small);
+    s.routed(0);
+    reply.clear();
+    client.extend(b"PUT / HTTP/1.1\r\nContent-Length: 5\r\n\r\nabcde");
+    assert_eq!( s.on_request(&mut client, &mut backend, &mut reply, &peer), Err(HttpError::BodyTooLarge));
+    assert!( backend.is_empty() );
+    assert!( reply.starts_with(b"HTTP/1.1 413 Payload Too Large\r\n") );
+    let mut s = Session::new(small);
+    s.routed(0);
+    client.extend(b"PUT / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n");
+    assert_eq!( s.on_request(&mut client, &mut backend, &mut reply, &peer), Err(HttpError::BodyTooLarge));
+    backend.clear();
+
+    let mut s = Session::new(cfg);
+    let mut server = Buffer::new();
+    server.extend(b"HTTP/1.1 200 OK\r\n\r\n");
+    assert_eq!( s.on_response(&mut server, &mut backend, &peer), Err(HttpError::BadStatusLine));
+}
+#[test]
+fn test_session_expect() {
+    use super::Limits;
+
+    let limits = Limits { max_body: Some(10), ..Limits::new() };
+    let cfg: &'static HttpConfig = Box::leak(Box::new(HttpConfig::new().limits(limits).expect(Expect::Answer)));
+    let peer = Peer { addr: None, sni: None, tls: true };
+    let mut client = Buffer::new();
+    let mut reply = Vec::new();
+    let mut backend = Vec::new();
+
+    //answered here, the backend doesn't see the expectation
+    let mut s = Session::new(cfg);
+    s.routed(0);
+    client.extend(b"PUT / HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 3\r\n\r\n");
+    s.on_request(&mut client, &mut backend, &mut reply, &peer).unwrap();
+    assert_eq!( reply.as_slice(), b"HTTP/1.1 100 Continue\r\n\r\n");
+    assert!( backend.starts_with(b"PUT / HTTP/1.1\r\nContent-Length: 3\r\n") );
+
+    //too large, refused before the body is sent
+    let mut s = Session::new(cfg);
+    s.routed(0);
+    reply.clear();
+    backend.clear();
+    client.extend(b"PUT / HTTP/1.1\r\nExpect: 100-continue\r\nContent-Length: 11\r\n\r\n");
+    assert_eq!( s.on_request(&mut client, &mut backend, &mut reply, &peer), Err(HttpError::BodyTooLarge));
+    assert!( reply.starts_with(b"HTTP/1.1 413 ") && backend.is_empty() );
+
+    let mut s = Session::new(cfg);
+    s.routed(0);
+    reply.clear();
+    client.extend(b"PUT / HTTP/1.1\r\nExpect: teapot\r\nContent-Length: 3\r\n\r\n");
+    assert_eq!( s.on_request(&mut client, &mut backend, &mut reply, &peer), Err(HttpError::ExpectationFailed));
+    assert!( reply.starts_with(b"HTTP/1.1 417 Expectation Failed\r\n") );
+}
+#[test]
+fn test_session_backend_failed() {
+    let cfg: &'static HttpConfig = Box::leak(Box::new(HttpConfig::new()));
+    let peer = Peer { addr: None, sni: None, tls: true };
+    let mut client = Buffer::new();
+    let mut reply = Vec::new();
+    let mut backend = Vec::new();
+
+    //no backend could be had for the request
+    let mut s = Session::new(cfg);
+    client.extend(b"GET / HTTP/1.1\r\n\r\n");
+    assert_eq!( s.on_request(&mut client, &mut backend, &mut reply, &peer), Ok(Some(0)));
+    s.backend_failed(502, &mut client, &mut reply);
+    assert!( reply.starts_with(b"HTTP/1.1 502 Bad Gateway\r\n") );
+    assert!( s.is_finished() );
+
+    //the backend took too long with the second of two requests
+    let mut s = Session::new(cfg);
+    s.routed(0);
+    reply.clear();
+    client.extend(b"GET /a HTTP/1.1\r\n\r\nHEAD /b HTTP/1.1\r\n\r\n");
+    s.on_request(&mut client, &mut backend, &mut reply, &peer).unwrap();
+    let mut server = Buffer::new();
+    server.extend(b"HTTP/1.1 204 No Content\r\n\r\n");
+    s.on_response(&mut server, &mut reply, &peer).unwrap();
+    assert!( s.unanswered() );
+    s.backend_failed(504, &mut client, &mut reply);
+    assert!( reply.ends_with(b"Connection: close\r\n\r\n") );
+    assert!( reply.windows(3).any(|w| w == b"504") );
+    assert!( s.is_finished() && ! s.unanswered() );
+}
+#[test]
+fn test_session_websocket() {
+    let cfg: &'static HttpConfig = Box::leak(Box::new(HttpConfig::new()));
+    let peer = Peer { addr: None, sni: None, tls: true };
+    let mut s = Session::new(cfg);
+    s.routed(0);
+    let mut client = Buffer::new();
+    let mut reply = Vec::new();
+    let mut backend = Vec::new();
+    let mut server = Buffer::new();
+
+    //the handshake goes on, anything after it waits for the answer
+    client.extend(b"GET /chat HTTP/1.1\r\nHost: x\r\nConnection: Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n\x81\x00");
+    s.on_request(&mut client, &mut backend, &mut reply, &peer).unwrap();
+    assert!( backend.windows(20).any(|w| w == b"Connection: Upgrade\r") );
+    assert_eq!( client.as_slice(), b"\x81\x00");
+    assert!( ! s.backend_reusable() );
+
+    server.extend(b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n\x82\x01z");
+    s.on_response(&mut server, &mut reply, &peer).unwrap();
+    assert!( reply.ends_with(b"\r\n\r\n\x82\x01z") );
+    assert!( s.is_upgraded() );
+    backend.clear();
+    s.on_request(&mut client, &mut backend, &mut reply, &peer).unwrap();
+    assert_eq!( backend.as_slice(), b"\x81\x00");
+
+    //other protocols are tunneled without being checked
+    let mut s = Session::new(cfg);
+    s.routed(0);
+    client.extend(b"GET / HTTP/1.1\r\nConnection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\nHTTP2-Settings: AAMAAABkAAQAoAAAAAIAAAAA\r\n\r\n");
+    backend.clear();
+    s.on_request(&mut client, &mut backend, &mut reply, &peer).unwrap();
+    assert!( backend.windows(13).any(|w| w == b"Upgrade: h2c\r") );
+    assert!( backend.windows(36).any(|w| w == &b"Connection: Upgrade, HTTP2-Settings\r"[..]) );
+    assert!( backend.windows(15).any(|w| w == b"HTTP2-Settings:") );
+    server.extend(b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\nPRI");
+    reply.clear();
+    s.on_response(&mut server, &mut reply, &peer).unwrap();
+    assert!( reply.ends_with(b"\r\n\r\nPRI") );
+    assert!( s.is_upgraded() );
+
+    //a switch the client didn't ask for, or with the wrong accept key
+    let mut s = Session::new(cfg);
+    s.routed(0);
+    client.extend(b"GET / HTTP/1.1\r\nConnection: Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Key: a2V5\r\n\r\n");
+    backend.clear();
+    s.on_request(&mut client, &mut backend, &mut reply, &peer).unwrap();
+    server.extend(b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: websocket\r\nSec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n");
+    assert_eq!( s.on_response(&mut server, &mut reply, &peer), Err(HttpError::BadUpgrade));
+
+    let mut s = Session::new(cfg);
+    s.routed(0);
+    client.extend(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n");
+    s.on_request(&mut client, &mut backend, &mut reply, &peer).unwrap();
+    server.extend(b"HTTP/1.1 101 Switching Protocols\r\nUpgrade: h2c\r\n\r\n");
+    assert_eq!( s.on_response(&mut server, &mut reply, &peer), Err(HttpError::BadUpgrade));
+}
+#[test]
+fn test_session_compress() {
+    use super::compress::Compress;
+
+    let cfg: &'static HttpConfig = Box::leak(Box::new(HttpConfig::new().compress(Compress::new().min_size(3))));
+    let peer = Peer { addr: None, sni: None, tls: true };
+    let mut s = Session::new(cfg);
+    s.routed(0);
+    let mut client = Buffer::new();
+    let mut reply = Vec::new();
+    let mut backend = Vec::new();
+    let mut server = Buffer::new();
+
+    client.extend(b"GET /a HTTP/1.1\r\nAccept-Encoding: gzip\r\n\r\nGET /b HTTP/1.1\r\n\r\n");
+    s.on_request(&mut client, &mut backend, &mut reply, &peer).unwrap();
+    server.extend(b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 5\r\n\r\nhello");
+    s.on_response(&mut server, &mut reply, &peer).unwrap();
+    assert!( reply.windows(22).any(|w| w == b"Content-Encoding: gzip") );
+    assert!( reply.windows(26).any(|w| w == b"Transfer-Encoding: chunked") );
+    assert!( reply.ends_with(b"\r\n0\r\n\r\n") );
+
+    //the second request didn't ask for it
+    reply.clear();
+    server.extend(b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 5\r\n\r\nhello");
+    s.on_response(&mut server, &mut reply, &peer).unwrap();
+    assert!( reply.ends_with(b"Content-Length: 5\r\n\r\nhello") );
+}
+#[test]
+fn test_session_files() {
+    use super::files::{Site,TestDir,add_site};
+
+    let dir = TestDir::new("session", &[("x.txt", b"hello")]);
+    let site = add_site(Site::new(dir.root.clone()));
+    let cfg: &'static HttpConfig = Box::leak(Box::new(HttpConfig::new().target(Target::Files(site))));
+    let peer = Peer { addr: None, sni: None, tls: false };
+    let mut s = Session::new(cfg);
+    let mut client = Buffer::new();
+    let mut backend = Vec::new();
+    let mut reply = Vec::new();
+
+    //the file is left to be sent, the next request waits for it
+    client.extend(b"GET /x.txt HTTP/1.1\r\n\r\nPOST /nope HTTP/1.1\r\nContent-Length: 3\r\n\r\nabc");
+    assert_eq!( s.on_request(&mut client, &mut backend, &mut reply, &peer), Ok(None));
+    assert!( reply.starts_with(b"HTTP/1.1 200 OK\r\n") );
+    assert_eq!( s.file.as_ref().map(|f| f.remaining), Some(5));
+    assert!( ! s.is_finished() );
+    s.file = None;
+
+    //the body of a request answered from disk is dropped
+    reply.clear();
+    client.extend(b"HEAD /missing HTTP/1.1\r\nConnection: close\r\n\r\n");
+    s.on_request(&mut client, &mut backend, &mut reply, &peer).unwrap();
+    assert!( reply.starts_with(b"HTTP/1.1 405 Method Not Allowed\r\n") );
+    assert!( reply.windows(24).any(|w| w == b"HTTP/1.1 404 Not Found\r\n") );
+    assert!( client.is_empty() && backend.is_empty() );
+    assert!( s.is_finished() );
+}
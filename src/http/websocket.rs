@@ -0,0 +1,116 @@
+
+use super::super::openssl::hash::{
+    MessageDigest,
+    hash
+};
+use super::super::base64::encode;
+use super::Version;
+use super::head::{
+    Headers,
+    Request,
+    Response
+};
+
+///Appended to the client's key before hashing, RFC 6455 section 1.3
+const GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+///If a header has `token` in its comma separated list, in any case
+fn has_token(headers: &Headers, name: &str, token: &str) -> bool {
+    headers.get_all(name)
+        .flat_map(|v| v.split(','))
+        .any(|x| x.trim().eq_ignore_ascii_case(token))
+}
+
+///What a request asks the backend to switch to
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum Upgrade {
+    ///A WebSocket handshake, with its key
+    WebSocket(String),
+    ///Another protocol, such as h2c. The proxy doesn't speak it, the bytes are just copied.
+    Other
+}
+
+///If a request asks to switch protocols. Only a bodiless HTTP/1.1 request with
+///`Connection: Upgrade` and an `Upgrade` header may. A WebSocket handshake must also be a GET
+///with a key, one which isn't is no upgrade at all.
+pub fn wants_upgrade(req: &Request) -> Option<Upgrade> {
+    let asks = req.version == Version::Http11 &&
+        has_token(&req.headers, "Connection", "upgrade") &&
+        req.headers.get("Upgrade").map(|x| ! x.trim().is_empty()).unwrap_or(false);
+    if ! asks {
+        return None;
+    }
+    if ! has_token(&req.headers, "Upgrade", "websocket") {
+        return Some(Upgrade::Other);
+    }
+    if req.method != "GET" {
+        return None;
+    }
+    match req.headers.get("Sec-WebSocket-Key").map(|k| k.trim()) {
+        Option::Some(k) if ! k.is_empty() => Some(Upgrade::WebSocket(k.to_string())),
+        _ => None
+    }
+}
+
+///Check a backend's `101 Switching Protocols` answers `upgrade`. A WebSocket handshake must be
+///accepted with the right key, other protocols just have to be named.
+pub fn accepts(resp: &Response, upgrade: &Upgrade) -> bool {
+    if resp.status != 101 || ! resp.headers.contains("Upgrade") {
+        return false;
+    }
+    match upgrade {
+        &Upgrade::WebSocket(ref key) => {
+            has_token(&resp.headers, "Connection", "upgrade") &&
+                has_token(&resp.headers, "Upgrade", "websocket") &&
+                resp.headers.get("Sec-WebSocket-Accept").map(|v| v.trim()) == Some(accept_key(key).as_str())
+        },
+        &Upgrade::Other => true
+    }
+}
+
+///The `Sec-WebSocket-Accept` value for a key, the base64 of the SHA-1 of the key and `GUID`
+pub fn accept_key(key: &str) -> String {
+    let mut v = key.as_bytes().to_vec();
+    v.extend_from_slice(GUID.as_bytes());
+    match hash(MessageDigest::sha1(), &v) {
+        Ok(digest) => encode(&digest),
+        //nothing will match, so the handshake is refused
+        Err(_) => String::new()
+    }
+}
+#[test]
+fn test_websocket_handshake() {
+    //the example from RFC 6455
+    assert_eq!( accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+
+    let mut h = Headers::new();
+    h.append("Connection", "keep-alive, Upgrade");
+    h.append("Upgrade", "WebSocket");
+    h.append("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ==");
+    let mut req = Request {
+        method: "GET".to_string(),
+        target: "/chat".to_string(),
+        version: Version::Http11,
+        headers: h
+    };
+    let ws = Upgrade::WebSocket("dGhlIHNhbXBsZSBub25jZQ==".to_string());
+    assert_eq!( wants_upgrade(&req), Some(ws.clone()));
+    req.method = "POST".to_string();
+    assert_eq!( wants_upgrade(&req), None);
+
+    //other protocols are passed through unchecked
+    req.headers.set("Upgrade", "h2c");
+    assert_eq!( wants_upgrade(&req), Some(Upgrade::Other));
+    req.headers.set("Connection", "keep-alive");
+    assert_eq!( wants_upgrade(&req), None);
+
+    let mut resp = Response::new(101, "Switching Protocols");
+    resp.headers.append("Connection", "Upgrade");
+    resp.headers.append("Upgrade", "websocket");
+    resp.headers.append("Sec-WebSocket-Accept", "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    assert!( accepts(&resp, &ws) );
+    assert!( ! accepts(&resp, &Upgrade::WebSocket("another key".to_string())) );
+    assert!( accepts(&resp, &Upgrade::Other) );
+    resp.headers.remove("Upgrade");
+    assert!( ! accepts(&resp, &Upgrade::Other) );
+}
@@ -0,0 +1,130 @@
+
+use super::config::{
+    get_idle_backends,
+    get_idle_timeout
+};
+use super::mio::Token;
+use std::collections::VecDeque;
+use std::time::{
+    Duration,
+    Instant
+};
+
+///Backend connections a worker has finished with but kept open, by pool. They're handed out
+///again for later HTTP requests to the same pool so a new connection isn't needed each time.
+///The most recently used connection is reused first, so the others are left to time out.
+pub struct IdleBackends {
+    pools: Vec<VecDeque<(Token,Instant)>>,
+    ///Most kept for a single pool
+    max: usize,
+    ///How long a connection is kept
+    ttl: Duration
+}
+impl IdleBackends {
+
+    ///Build an empty set using the configured limits
+    pub fn new() -> IdleBackends {
+        IdleBackends::with_limits(get_idle_backends(), get_idle_timeout())
+    }
+
+    pub fn with_limits(max: usize, ttl: Duration) -> IdleBackends {
+        IdleBackends {
+            pools: Vec::new(),
+            max: max,
+            ttl: ttl
+        }
+    }
+
+    ///Keep a connection for `pool`. If the pool is full the oldest connection is dropped, and
+    ///returned so it can be closed.
+    pub fn put(&mut self, pool: usize, t: Token, now: Instant) -> Option<Token> {
+        if self.max == 0 {
+            return Some(t);
+        }
+        while self.pools.len() <= pool {
+            self.pools.push(VecDeque::new());
+        }
+        let list = &mut self.pools[pool];
+        list.push_back((t, now));
+        if list.len() > self.max {
+            list.pop_front().map(|x| x.0)
+        } else {
+            None
+        }
+    }
+
+    ///Take a connection for `pool`. `usable` checks each one, those it rejects are pushed on
+    ///`close`, as are any which have been idle too long.
+    pub fn take<F>(&mut self, pool: usize, now: Instant, close: &mut Vec<Token>, mut usable: F) -> Option<Token>
+        where F: FnMut(&Token) -> bool
+    {
+        let ttl = self.ttl;
+        let list = match self.pools.get_mut(pool) {
+            Option::Some(x) => x,
+            Option::None => return None
+        };
+        while let Option::Some((t, since)) = list.pop_back() {
+            if now.duration_since(since) >= ttl || ! usable(&t) {
+                close.push(t);
+                continue;
+            }
+            return Some(t);
+        }
+        None
+    }
+
+    ///Forget a connection, because the backend closed it or sent something. Returns if it was
+    ///being kept.
+    pub fn remove(&mut self, t: &Token) -> bool {
+        for list in self.pools.iter_mut() {
+            match list.iter().position(|x| x.0 == *t) {
+                Option::Some(i) => {
+                    list.remove(i);
+                    return true;
+                },
+                Option::None => { }
+            };
+        }
+        false
+    }
+
+    ///Push every connection which has been idle too long on `close`
+    pub fn expire(&mut self, now: Instant, close: &mut Vec<Token>) {
+        let ttl = self.ttl;
+        for list in self.pools.iter_mut() {
+            //oldest first
+            while list.front().map(|x| now.duration_since(x.1) >= ttl).unwrap_or(false) {
+                match list.pop_front() {
+                    Option::Some((t,_)) => close.push(t),
+                    Option::None => break
+                };
+            }
+        }
+    }
+}
+#[test]
+fn test_idle_backends() {
+    let now = Instant::now();
+    let later = now + Duration::from_secs(10);
+    let mut idle = IdleBackends::with_limits(2, Duration::from_secs(5));
+    let mut close = Vec::new();
+
+    assert_eq!( idle.put(1, Token(10), now), None);
+    assert_eq!( idle.put(1, Token(11), now), None);
+    //full, the oldest is dropped
+    assert_eq!( idle.put(1, Token(12), now), Some(Token(10)));
+    assert_eq!( idle.take(0, now, &mut close, |_| true), None);
+
+    //the newest is reused first, rejected ones are closed
+    assert_eq!( idle.take(1, now, &mut close, |t| *t != Token(12)), Some(Token(11)));
+    assert_eq!( close, vec![Token(12)]);
+    assert_eq!( idle.take(1, now, &mut close, |_| true), None);
+
+    close.clear();
+    idle.put(2, Token(20), now);
+    idle.put(2, Token(21), later);
+    assert!( idle.remove(&Token(21)) );
+    assert!( ! idle.remove(&Token(21)) );
+    idle.expire(later, &mut close);
+    assert_eq!( close, vec![Token(20)]);
+}
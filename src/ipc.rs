@@ -1,4 +1,5 @@
 use super::slab::get_workerid;
+use super::listener::ListenerID;
 use super::workerid::{
     WorkerID,
     get_id
@@ -11,12 +12,23 @@ use super::crossbeam::sync::SegQueue;
 use super::mio::{
     Event,
     Token,
-    Ready
+    Ready,
+    Poll,
+    PollOpt,
+    Registration,
+    SetReadiness
 };
 use std::sync::atomic::{
     AtomicPtr,
+    AtomicBool,
     Ordering
 };
+use std::ptr::null_mut;
+use std::sync::{
+    Mutex,
+    Condvar
+};
+use std::time::Duration;
 
 
 ///The Reqeuests a client can make to the event thread
@@ -38,12 +50,14 @@ pub enum Requests {
 pub struct PresentRequests(pub WorkerID, pub Requests);
 
 ///Responses. What the event loop can say to a worker. Open shows that it has opened a new
-///connection like the worker has requested. Event is a MIO event the worker thread in question
-///has a lock on.
+///connection like the worker has requested. Accept hands the worker a newly accepted client,
+///still a plain TCP stream, which the worker must start the TLS handshake on as the given
+///listener says. Event is a MIO event the worker thread in question has a lock on.
 #[derive(Clone,Copy,Debug,PartialEq,Eq)]
 pub enum Events {
     Failure,
     Open(Token),
+    Accept(Token,ListenerID),
     Event(Event)
 }
 
@@ -52,7 +66,10 @@ pub enum Events {
 struct WorkerIPC {
     id: WorkerID,
     to: SegQueue<Requests>,
-    from: SegQueue<Events>
+    from: SegQueue<Events>,
+    parked: AtomicBool,
+    park_lock: Mutex<()>,
+    park_cond: Condvar
 }
 impl WorkerIPC {
     
@@ -61,10 +78,97 @@ impl WorkerIPC {
         WorkerIPC {
             id: WorkerID(id),
             to: SegQueue::new(),
-            from: SegQueue::new()
+            from: SegQueue::new(),
+            parked: AtomicBool::new(false),
+            park_lock: Mutex::new(()),
+            park_cond: Condvar::new()
+        }
+    }
+
+    ///Move everything waiting for the worker into the buffer. Returns if anything was read.
+    #[inline(always)]
+    fn drain(&self, v: &mut Vec<Events>) -> bool {
+        let len = v.len();
+        while let Some(item) = self.from.try_pop() {
+            v.push(item);
+        }
+        v.len() != len
+    }
+
+    ///Push an event to the worker. The worker is only signaled if it is parked, so under
+    ///load this is just the queue push.
+    #[inline(always)]
+    fn push(&self, e: Events) {
+        self.from.push(e);
+        if self.parked.load(Ordering::SeqCst) {
+            let _guard = self.park_lock.lock();
+            self.park_cond.notify_one();
+        }
+    }
+
+    ///Park until something is pushed or the timeout elapses. The parked flag is raised
+    ///_before_ the queue is checked again, so a push racing this will either be seen by
+    ///the re-check, or the pusher will see the flag and signal.
+    fn wait(&self, v: &mut Vec<Events>, timeout: Option<Duration>) {
+        if self.drain(v) {
+            return;
+        }
+        let guard = match self.park_lock.lock() {
+            Ok(g) => g,
+            Err(poison) => poison.into_inner()
+        };
+        self.parked.store(true, Ordering::SeqCst);
+        if self.drain(v) {
+            self.parked.store(false, Ordering::SeqCst);
+            return;
         }
+        let guard = match timeout {
+            Option::None => match self.park_cond.wait(guard) {
+                Ok(g) => g,
+                Err(poison) => poison.into_inner()
+            },
+            Option::Some(d) => match self.park_cond.wait_timeout(guard, d) {
+                Ok((g,_)) => g,
+                Err(poison) => poison.into_inner().0
+            }
+        };
+        self.parked.store(false, Ordering::SeqCst);
+        drop(guard);
+        self.drain(v);
     }
 }
+#[test]
+fn test_worker_wait() {
+    use std::sync::Arc;
+    use std::thread;
+
+    let w = Arc::new(WorkerIPC::new(1));
+    let mut v = Vec::new();
+
+    //nothing sent, times out empty handed
+    w.wait(&mut v, Some(Duration::from_millis(10)));
+    assert!( v.is_empty() );
+
+    //already queued, returns without parking
+    w.push(Events::Failure);
+    w.wait(&mut v, None);
+    assert_eq!( v.as_slice(), &[Events::Failure] );
+    v.clear();
+
+    //parked worker is woken by a push
+    let w2 = w.clone();
+    let handle = thread::spawn(move || {
+        let mut v = Vec::new();
+        while v.is_empty() {
+            w2.wait(&mut v, None);
+        }
+        v
+    });
+    thread::sleep(Duration::from_millis(10));
+    w.push(Events::Open(Token(10)));
+    let v = handle.join().unwrap();
+    assert_eq!( v.as_slice(), &[Events::Open(Token(10))] );
+}
 
 lazy_static! {
     static ref WORKER_BUS: AtomicPtr<Vec<WorkerIPC>> = 
@@ -86,11 +190,76 @@ fn worker_bus<'a>() -> &'a mut Vec<WorkerIPC> {
     };
     reference
 }
-///Constructs the worker thread IPC.
+lazy_static! {
+    static ref WAKER: AtomicPtr<SetReadiness> = AtomicPtr::new(null_mut());
+    static ref WAKE_PENDING: AtomicBool = AtomicBool::new(false);
+    static ref POLL: AtomicPtr<Poll> = AtomicPtr::new(null_mut());
+}
+
+///Share the event loop's `Poll` so workers can change what their connections are registered
+///for. This must be called once by the event loop, before any connections exist. The `Poll`
+///lives for the rest of the process.
+pub fn share_poll(p: Poll) -> &'static Poll {
+    let ptr = Box::into_raw(Box::new(p));
+    POLL.store(ptr, Ordering::Release);
+    unsafe{ &*ptr }
+}
+
+///The event loop's `Poll`, `None` until it is shared
+#[inline(always)]
+pub fn get_poll() -> Option<&'static Poll> {
+    let ptr: *mut Poll = POLL.load(Ordering::Acquire);
+    unsafe{ ptr.as_ref() }
+}
+
+///Builds the wakeup handle for the event loop. The returned `Registration` must be kept alive
+///by the event loop, dropping it disables the wakeup. Events for this will arrive on token `t`.
+pub fn build_waker(poll: &Poll, t: Token) -> Registration {
+    let (reg, set) = Registration::new(poll, t, Ready::readable(), PollOpt::edge());
+    let old = WAKER.swap(Box::into_raw(Box::new(set)), Ordering::AcqRel);
+    if ! old.is_null() {
+        unsafe{ drop(Box::from_raw(old)) };
+    }
+    reg
+}
+
+///Wakes the event loop. Only the first request since the event loop last reset the waker will
+///actually touch the `Poll`, so a burst of requests costs a single wakeup.
+#[inline(always)]
+fn wake_event_loop() {
+    if WAKE_PENDING.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let ptr: *mut SetReadiness = WAKER.load(Ordering::Acquire);
+    match unsafe{ ptr.as_ref() } {
+        Option::Some(set) => {
+            let _ = set.set_readiness(Ready::readable());
+        },
+        Option::None => { }
+    };
+}
+
+///Called by the event loop when it has been woken. This _must_ be called before the event loop
+///reads requests via `get_requests` otherwise a request can be missed.
+///
+///Readiness is cleared _before_ the pending flag so a worker racing this call can't have its
+///wakeup erased.
+pub fn reset_waker() {
+    let ptr: *mut SetReadiness = WAKER.load(Ordering::Acquire);
+    match unsafe{ ptr.as_ref() } {
+        Option::Some(set) => {
+            let _ = set.set_readiness(Ready::none());
+        },
+        Option::None => { }
+    };
+    WAKE_PENDING.store(false, Ordering::SeqCst);
+}
+
+///Constructs the worker thread IPC. Workers are numbered from 1.
 pub fn build_ipc( worker_count: usize) {
     set_workers(worker_count);
     let mut ptr = worker_bus();
-    for workerid in 1..worker_count {
+    for workerid in 1..=worker_count {
         ptr.push(WorkerIPC::new(workerid));
     }
 }
@@ -105,12 +274,25 @@ pub fn my_events(v: &mut Vec<Events>) {
     if worker.id != WorkerID(id) {
         return;
     }
-    while let Some(item) = worker.from.try_pop() {
-        v.push(item);
+    worker.drain(v);
+}
+
+///Get messages from the event loop, parking the worker thread until there are some. If there
+///are already messages waiting this doesn't block. Waiting is bound by `timeout`, `None` will
+///wait forever. This may return without any events, so callers should loop.
+#[inline(never)]
+pub fn wait_events(v: &mut Vec<Events>, timeout: Option<Duration>) {
+    let id = get_id();
+    let i = id-1;
+    let bus = worker_bus();
+    let worker = &bus[i];
+    if worker.id != WorkerID(id) {
+        return;
     }
+    worker.wait(v, timeout);
 }
 
-///Send Requests to the event loop
+///Send Requests to the event loop. This will wake the event loop if it is blocked in `poll`.
 pub fn send_request(r: Requests) {
     let id = get_id();
     let i = id-1;
@@ -120,6 +302,7 @@ pub fn send_request(r: Requests) {
         return;
     }
     worker.to.push(r);
+    wake_event_loop();
 }
 
 
@@ -147,7 +330,7 @@ pub fn send_event(e: Event) -> Option<Event> {
         Option::Some(id) => {
             let i = id.0-1;
             let worker = &bus[i];
-            worker.from.push(Events::Event(e));
+            worker.push(Events::Event(e));
             None
         }
     }
@@ -158,5 +341,5 @@ pub fn send_futfillment(w: WorkerID, e: Events) {
     let bus = worker_bus();
     let i = w.0-1;
     let worker = &bus[i];
-    worker.from.push(e);
+    worker.push(e);
 }
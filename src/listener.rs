@@ -0,0 +1,161 @@
+
+use super::conn::acceptor::{
+    AcceptorID,
+    add_acceptor
+};
+use super::cidr::Cidr;
+use super::sni::SniRoutes;
+use super::http::HttpConfig;
+use super::http::redirect::Redirect;
+use super::native_tls::TlsAcceptor;
+use std::net::SocketAddr;
+use std::sync::atomic::{
+    AtomicPtr,
+    Ordering
+};
+
+///Handle to a `Listener`. The event loop hands this to a worker along with each client it
+///accepts, so the worker knows how to treat the client.
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub struct ListenerID(pub usize);
+
+///Where a worker is in accepting a client
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub struct Accepting {
+    pub listener: ListenerID,
+    ///A PROXY protocol header must be read before anything else
+    pub proxy_header: bool
+}
+
+///How the bytes from clients are treated
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum Mode {
+    ///Copied to the backend as they are
+    L4,
+    ///Parsed as HTTP/1.1 requests
+    Http,
+    ///TLS isn't terminated. The ClientHello is read to pick a pool by SNI, then replayed to
+    ///the backend and everything is copied as it is.
+    Passthrough,
+    ///Plain HTTP. Every request is answered with a redirect to HTTPS.
+    Redirect
+}
+
+///A socket clients connect to, and how they're handled.
+pub struct Listener {
+    pub addr: SocketAddr,
+    pub mode: Mode,
+    ///Only used in HTTP and redirect modes
+    pub http: HttpConfig,
+    ///Picks the pool for clients in L4 mode. HTTP clients are routed by request.
+    pub sni: SniRoutes,
+    ///Terminates TLS for clients, `None` in passthrough and redirect modes
+    pub acceptor: Option<AcceptorID>,
+    ///Upstreams which must send a PROXY protocol header. Clients from anywhere else are
+    ///treated as the real client. Empty by default.
+    pub proxy_from: Vec<Cidr>,
+    ///Most bytes an L4 or passthrough client may send over its connection, `None` (the
+    ///default) for no limit
+    pub max_stream: Option<u64>
+}
+impl Listener {
+
+    ///Build a listener which terminates TLS
+    pub fn new(addr: SocketAddr, a: TlsAcceptor) -> Listener {
+        Listener {
+            addr: addr,
+            mode: Mode::L4,
+            http: HttpConfig::new(),
+            sni: SniRoutes::new(),
+            acceptor: Some(add_acceptor(a)),
+            proxy_from: Vec::new(),
+            max_stream: None
+        }
+    }
+
+    ///Build a listener which passes TLS through to backends. Clients are routed by SNI.
+    pub fn passthrough(addr: SocketAddr) -> Listener {
+        Listener {
+            addr: addr,
+            mode: Mode::Passthrough,
+            http: HttpConfig::new(),
+            sni: SniRoutes::new(),
+            acceptor: None,
+            proxy_from: Vec::new(),
+            max_stream: None
+        }
+    }
+
+    ///Build a plain HTTP listener which sends clients to HTTPS
+    pub fn redirect(addr: SocketAddr, r: Redirect) -> Listener {
+        Listener {
+            addr: addr,
+            mode: Mode::Redirect,
+            http: HttpConfig::new().redirect(r),
+            sni: SniRoutes::new(),
+            acceptor: None,
+            proxy_from: Vec::new(),
+            max_stream: None
+        }
+    }
+
+    ///Parse clients as HTTP, configured by `cfg`
+    pub fn http(mut self, cfg: HttpConfig) -> Listener {
+        self.mode = Mode::Http;
+        self.http = cfg;
+        self
+    }
+
+    ///Pick L4 clients' pools by their server name
+    pub fn sni_routes(mut self, r: SniRoutes) -> Listener {
+        self.sni = r;
+        self
+    }
+
+    ///Require PROXY protocol headers from these upstreams
+    pub fn proxy_from(mut self, v: Vec<Cidr>) -> Listener {
+        self.proxy_from = v;
+        self
+    }
+
+    ///Close L4 and passthrough clients which send more than `n` bytes
+    pub fn max_stream(mut self, n: u64) -> Listener {
+        self.max_stream = Some(n);
+        self
+    }
+}
+
+lazy_static! {
+    static ref LISTENERS: AtomicPtr<Vec<Listener>> =
+        AtomicPtr::new(
+            Box::into_raw(
+                Box::new(
+                    Vec::with_capacity(4))));
+}
+
+///function to get the raw pointer to the array
+#[inline(always)]
+fn raw_ptr<'a>() -> &'a mut Vec<Listener> {
+    let ptr: *mut Vec<Listener> = LISTENERS.load(Ordering::Acquire);
+    unsafe {
+        match ptr.as_mut() {
+            Option::Some(x) => x,
+            Option::None => unreachable!()
+        }
+    }
+}
+
+///Store a listener so workers can use it. This must only be called during start up, before
+///any worker threads exist.
+pub fn add_listener(l: Listener) -> ListenerID {
+    let v = raw_ptr();
+    v.push(l);
+    ListenerID(v.len()-1)
+}
+
+///Look up a listener
+#[inline(always)]
+pub fn get_listener<'a>(id: ListenerID) -> Option<&'a Listener> {
+    let v: &'a mut Vec<Listener> = raw_ptr();
+    v.get(id.0)
+}
@@ -20,6 +20,12 @@ extern crate mio;
 extern crate lazy_static;
 extern crate native_tls;
 extern crate crossbeam;
+extern crate libc;
+extern crate regex;
+extern crate flate2;
+extern crate brotli2;
+extern crate openssl;
+extern crate base64;
 
 mod conn;
 mod lock;
@@ -28,6 +34,17 @@ mod config;
 mod workerid;
 mod ipc;
 mod eventloop;
+mod metrics;
+mod worker;
+mod proxy;
+mod pool;
+mod cidr;
+mod listener;
+mod buffer;
+mod http;
+mod control;
+mod sni;
+mod idle;
 
 fn main() {
     println!("Hello, world!");
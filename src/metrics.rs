@@ -0,0 +1,110 @@
+
+use std::sync::atomic::{AtomicUsize,Ordering};
+
+///Things the proxy counts. Each variant is an index into the counter table.
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum Metric {
+    ///A client was accepted/refused while the token pool was empty
+    OverloadAccept,
+    ///A worker asked for a backend connection while the token pool was empty
+    OverloadConnect,
+    ///A backend connection request was dropped as the pending queue was full
+    OverloadDropped,
+    ///Accepting failed for a reason other than a single bad client, the listener backed off
+    AcceptBackoff,
+    ///A request line was longer than the limit, 414
+    LimitRequestLine,
+    ///A request head was too large or had too many headers, 431
+    LimitRequestHead,
+    ///A request body was larger than the limit, 413
+    LimitRequestBody,
+    ///A backend's response head was too large
+    LimitResponseHead,
+    ///An L4 or passthrough client sent more than its listener's `max_stream` and was closed
+    LimitStream,
+    ///A request was refused as it could be framed differently by the backend, see
+    ///`HttpError::is_smuggling`
+    Smuggling,
+    ///A request was answered from the response cache
+    CacheHit,
+    ///A cacheable request went to the backend
+    CacheMiss,
+    ///A stale cached response was confirmed by the backend with a 304
+    CacheRevalidate,
+    ///A cached response was dropped to stay within the budget
+    CacheEvict,
+}
+impl Metric {
+
+    ///Every metric, in table order
+    pub fn all() -> &'static [Metric] {
+        const ALL: &'static [Metric] = &[
+            Metric::OverloadAccept,
+            Metric::OverloadConnect,
+            Metric::OverloadDropped,
+            Metric::AcceptBackoff,
+            Metric::LimitRequestLine,
+            Metric::LimitRequestHead,
+            Metric::LimitRequestBody,
+            Metric::LimitResponseHead,
+            Metric::LimitStream,
+            Metric::Smuggling,
+            Metric::CacheHit,
+            Metric::CacheMiss,
+            Metric::CacheRevalidate,
+            Metric::CacheEvict,
+        ];
+        ALL
+    }
+
+    ///Name reported for the metric
+    pub fn name(&self) -> &'static str {
+        match self {
+            &Metric::OverloadAccept => "overload_accept",
+            &Metric::OverloadConnect => "overload_connect",
+            &Metric::OverloadDropped => "overload_dropped",
+            &Metric::AcceptBackoff => "accept_backoff",
+            &Metric::LimitRequestLine => "limit_request_line",
+            &Metric::LimitRequestHead => "limit_request_head",
+            &Metric::LimitRequestBody => "limit_request_body",
+            &Metric::LimitResponseHead => "limit_response_head",
+            &Metric::LimitStream => "limit_stream",
+            &Metric::Smuggling => "smuggling",
+            &Metric::CacheHit => "cache_hit",
+            &Metric::CacheMiss => "cache_miss",
+            &Metric::CacheRevalidate => "cache_revalidate",
+            &Metric::CacheEvict => "cache_evict",
+        }
+    }
+}
+
+lazy_static! {
+    static ref COUNTERS: Vec<AtomicUsize> = {
+        let mut v = Vec::with_capacity(Metric::all().len());
+        for _ in Metric::all() {
+            v.push(AtomicUsize::new(0));
+        }
+        v
+    };
+}
+
+///Increment a counter
+#[inline(always)]
+pub fn incr(m: Metric) {
+    COUNTERS[m as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+///Read a counter
+#[inline(always)]
+pub fn get(m: Metric) -> usize {
+    COUNTERS[m as usize].load(Ordering::Relaxed)
+}
+#[test]
+fn test_metrics() {
+    for (i,m) in Metric::all().iter().enumerate() {
+        assert_eq!( *m as usize, i);
+    }
+    let before = get(Metric::OverloadDropped);
+    incr(Metric::OverloadDropped);
+    assert_eq!( get(Metric::OverloadDropped), before+1);
+}
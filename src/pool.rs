@@ -0,0 +1,148 @@
+
+use super::conn::stream::Stream;
+use super::conn::fault::Fault;
+use super::proxy::ProxyVersion;
+use super::mio::{
+    Poll,
+    Token,
+};
+use super::mio::tcp::TcpStream;
+use super::mio::deprecated::UnixStream;
+use std::str::FromStr;
+use std::path::PathBuf;
+use std::net::SocketAddr;
+use std::io::{
+    Error as OSFault,
+    ErrorKind
+};
+use std::sync::atomic::{
+    AtomicPtr,
+    AtomicUsize,
+    AtomicBool,
+    Ordering
+};
+
+///What we forward connections too
+pub enum Forward {
+    Unix(PathBuf),
+    Network(SocketAddr)
+}
+impl Forward {
+
+    ///Build a forwarding address. If it returns NONE then a invalid socket address was given AND
+    ///in the event it WAS a path. The file path points to nothing
+    pub fn build(s: &str) -> Option<Forward> {
+        match SocketAddr::from_str(s) {
+            Ok(x) => Some(Forward::Network(x)),
+            Err(_) => {
+                let p = PathBuf::from(s);
+                if p.exists() {
+                    Some(Forward::Unix(p))
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    ///Setup up a new connection
+    pub fn connect(&self, p: &Poll, t: Token) -> Result<Stream,Fault> {
+        match self {
+            &Forward::Network(ref socket) => {
+                let tcp = TcpStream::connect(socket)?;
+                let stream = Stream::create_tcp(tcp,p,t)?;
+                Ok(stream)
+            },
+            &Forward::Unix(ref path) => {
+                let unix = UnixStream::connect(path)?;
+                let stream = Stream::create_unix(unix,p,t)?;
+                Ok(stream)
+            }
+        }
+    }
+}
+
+///A group of backends. Workers ask for a connection to a pool by its index with
+///`Requests::New`, and the event loop picks a member round robin.
+pub struct Pool {
+    pub members: Vec<Forward>,
+    ///Header written to the backend before any client data
+    pub proxy: Option<ProxyVersion>,
+    next: AtomicUsize,
+    ///HTTP requests for the pool are answered with a 503 rather than forwarded
+    maintenance: AtomicBool
+}
+impl Pool {
+
+    ///Build a pool. No PROXY protocol header is sent
+    pub fn new(members: Vec<Forward>) -> Pool {
+        Pool {
+            members: members,
+            proxy: None,
+            next: AtomicUsize::new(0),
+            maintenance: AtomicBool::new(false)
+        }
+    }
+
+    ///Send a PROXY protocol header to backends in this pool
+    pub fn proxy(mut self, v: ProxyVersion) -> Pool {
+        self.proxy = Some(v);
+        self
+    }
+
+    ///Take the pool out of service, or put it back. HTTP clients are sent a 503 page while it
+    ///is out, L4 clients are still connected.
+    #[inline(always)]
+    pub fn set_maintenance(&self, on: bool) {
+        self.maintenance.store(on, Ordering::Relaxed);
+    }
+
+    #[inline(always)]
+    pub fn in_maintenance(&self) -> bool {
+        self.maintenance.load(Ordering::Relaxed)
+    }
+
+    ///Connect to the next member of the pool
+    pub fn connect(&self, p: &Poll, t: Token) -> Result<Stream,Fault> {
+        if self.members.is_empty() {
+            return Err(Fault::OS(OSFault::new(ErrorKind::NotFound, "backend pool has no members")));
+        }
+        let i = self.next.fetch_add(1, Ordering::Relaxed) % self.members.len();
+        self.members[i].connect(p, t)
+    }
+}
+
+lazy_static! {
+    static ref POOLS: AtomicPtr<Vec<Pool>> =
+        AtomicPtr::new(
+            Box::into_raw(
+                Box::new(
+                    Vec::with_capacity(8))));
+}
+
+///function to get the raw pointer to the array
+#[inline(always)]
+fn raw_ptr<'a>() -> &'a mut Vec<Pool> {
+    let ptr: *mut Vec<Pool> = POOLS.load(Ordering::Acquire);
+    unsafe {
+        match ptr.as_mut() {
+            Option::Some(x) => x,
+            Option::None => unreachable!()
+        }
+    }
+}
+
+///Store a pool so workers and the event loop can use it. This must only be called during
+///start up, before any worker threads exist. Returns the pool's index.
+pub fn add_pool(p: Pool) -> usize {
+    let v = raw_ptr();
+    v.push(p);
+    v.len()-1
+}
+
+///Look up a pool
+#[inline(always)]
+pub fn get_pool<'a>(i: usize) -> Option<&'a Pool> {
+    let v: &'a mut Vec<Pool> = raw_ptr();
+    v.get(i)
+}
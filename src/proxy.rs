@@ -0,0 +1,339 @@
+
+use super::conn::hello::TlsInfo;
+use std::net::{
+    SocketAddr,
+    Ipv6Addr,
+    IpAddr
+};
+use std::io::prelude::*;
+
+///Which PROXY protocol header to send to a backend
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum ProxyVersion {
+    ///Human readable
+    V1,
+    ///Binary, carries TLS details as TLVs
+    V2
+}
+
+///Every v2 header starts with this
+pub const V2_SIGNATURE: [u8;12] = [0x0D,0x0A,0x0D,0x0A,0x00,0x0D,0x0A,0x51,0x55,0x49,0x54,0x0A];
+
+const V2_CMD_LOCAL: u8 = 0x20;
+const V2_CMD_PROXY: u8 = 0x21;
+const V2_AF_UNSPEC: u8 = 0x00;
+const V2_TCP4: u8 = 0x11;
+const V2_TCP6: u8 = 0x21;
+
+const PP2_TYPE_ALPN: u8 = 0x01;
+const PP2_TYPE_AUTHORITY: u8 = 0x02;
+const PP2_TYPE_SSL: u8 = 0x20;
+const PP2_SUBTYPE_SSL_VERSION: u8 = 0x21;
+const PP2_CLIENT_SSL: u8 = 0x01;
+
+///IPv4 addresses are mapped into IPv6 when the two sides don't share a family
+fn to_v6(a: &SocketAddr) -> (Ipv6Addr,u16) {
+    match a.ip() {
+        IpAddr::V4(ip) => (ip.to_ipv6_mapped(), a.port()),
+        IpAddr::V6(ip) => (ip, a.port())
+    }
+}
+
+///Build a header. `src` is the client, `dst` is the address the client connected to. If either
+///isn't known the header says so rather than lying.
+pub fn header(v: ProxyVersion, src: Option<&SocketAddr>, dst: Option<&SocketAddr>, tls: Option<&TlsInfo>) -> Vec<u8> {
+    match v {
+        ProxyVersion::V1 => v1_header(src, dst),
+        ProxyVersion::V2 => v2_header(src, dst, tls)
+    }
+}
+
+///Build a v1 (text) header
+pub fn v1_header(src: Option<&SocketAddr>, dst: Option<&SocketAddr>) -> Vec<u8> {
+    let mut v = Vec::with_capacity(108);
+    let _ = match (src,dst) {
+        (Some(&SocketAddr::V4(ref s)), Some(&SocketAddr::V4(ref d))) => {
+            write!(v, "PROXY TCP4 {} {} {} {}\r\n", s.ip(), d.ip(), s.port(), d.port())
+        },
+        (Some(s), Some(d)) => {
+            let (sip,sport) = to_v6(s);
+            let (dip,dport) = to_v6(d);
+            write!(v, "PROXY TCP6 {} {} {} {}\r\n", sip, dip, sport, dport)
+        },
+        _ => write!(v, "PROXY UNKNOWN\r\n")
+    };
+    v
+}
+
+#[inline(always)]
+fn push_u16(v: &mut Vec<u8>, x: usize) {
+    v.push((x >> 8) as u8);
+    v.push(x as u8);
+}
+
+#[inline(always)]
+fn push_tlv(v: &mut Vec<u8>, kind: u8, value: &[u8]) {
+    v.push(kind);
+    push_u16(v, value.len());
+    v.extend_from_slice(value);
+}
+
+///Build a v2 (binary) header. For a TLS client the SNI is sent as a TLV, along with one saying
+///the client used TLS. When TLS was terminated here the agreed ALPN protocol and TLS version
+///are sent too. A passed through client only says what it offered, which may not be what was
+///agreed, so neither is sent for it.
+pub fn v2_header(src: Option<&SocketAddr>, dst: Option<&SocketAddr>, tls: Option<&TlsInfo>) -> Vec<u8> {
+    let mut body = Vec::with_capacity(64);
+    let (cmd,fam) = match (src,dst) {
+        (Some(&SocketAddr::V4(ref s)), Some(&SocketAddr::V4(ref d))) => {
+            body.extend_from_slice(&s.ip().octets());
+            body.extend_from_slice(&d.ip().octets());
+            push_u16(&mut body, s.port() as usize);
+            push_u16(&mut body, d.port() as usize);
+            (V2_CMD_PROXY,V2_TCP4)
+        },
+        (Some(s), Some(d)) => {
+            let (sip,sport) = to_v6(s);
+            let (dip,dport) = to_v6(d);
+            body.extend_from_slice(&sip.octets());
+            body.extend_from_slice(&dip.octets());
+            push_u16(&mut body, sport as usize);
+            push_u16(&mut body, dport as usize);
+            (V2_CMD_PROXY,V2_TCP6)
+        },
+        _ => (V2_CMD_LOCAL,V2_AF_UNSPEC)
+    };
+    match tls {
+        Option::Some(info) => {
+            match info.agreed_alpn {
+                Option::Some(ref p) => push_tlv(&mut body, PP2_TYPE_ALPN, p),
+                Option::None => { }
+            };
+            match info.sni {
+                Option::Some(ref name) => push_tlv(&mut body, PP2_TYPE_AUTHORITY, name.as_bytes()),
+                Option::None => { }
+            };
+            //client flags, then verify. No client certificates are checked so verify is non-zero
+            let mut ssl = vec![PP2_CLIENT_SSL, 0, 0, 0, 1];
+            match info.agreed_version {
+                Option::Some(v) => push_tlv(&mut ssl, PP2_SUBTYPE_SSL_VERSION, v.as_bytes()),
+                Option::None => { }
+            };
+            push_tlv(&mut body, PP2_TYPE_SSL, &ssl);
+        },
+        Option::None => { }
+    };
+    let mut v = Vec::with_capacity(16 + body.len());
+    v.extend_from_slice(&V2_SIGNATURE);
+    v.push(cmd);
+    v.push(fam);
+    push_u16(&mut v, body.len());
+    v.extend_from_slice(&body);
+    v
+}
+#[test]
+fn test_proxy_header() {
+    use std::str::FromStr;
+    let s4 = SocketAddr::from_str("192.168.0.1:56324").unwrap();
+    let d4 = SocketAddr::from_str("10.0.0.1:443").unwrap();
+    let d6 = SocketAddr::from_str("[2001:db8::1]:443").unwrap();
+
+    assert_eq!( v1_header(Some(&s4), Some(&d4)), b"PROXY TCP4 192.168.0.1 10.0.0.1 56324 443\r\n".to_vec());
+    assert_eq!( v1_header(Some(&s4), Some(&d6)), b"PROXY TCP6 ::ffff:192.168.0.1 2001:db8::1 56324 443\r\n".to_vec());
+    assert_eq!( v1_header(None, Some(&d4)), b"PROXY UNKNOWN\r\n".to_vec());
+
+    let v = v2_header(Some(&s4), Some(&d4), None);
+    assert_eq!( &v[..12], &V2_SIGNATURE);
+    assert_eq!( &v[12..16], &[0x21, 0x11, 0x00, 0x0c]);
+    assert_eq!( &v[16..], &[192,168,0,1, 10,0,0,1, 0xdc,0x04, 0x01,0xbb]);
+
+    let v = v2_header(None, None, None);
+    assert_eq!( &v[12..], &[0x20, 0x00, 0x00, 0x00]);
+
+    //passed through, only that the client used TLS, no version or protocol
+    let mut info = TlsInfo {
+        sni: Some("a.io".to_string()),
+        alpn: vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+        version: 0x0303,
+        agreed_alpn: None,
+        agreed_version: None
+    };
+    let v = v2_header(Some(&s4), Some(&d4), Some(&info));
+    let tlvs = &v[28..];
+    assert_eq!( v.len() - 16, ((v[14] as usize) << 8) | v[15] as usize);
+    assert_eq!( &tlvs[..7], &[PP2_TYPE_AUTHORITY, 0, 4, b'a', b'.', b'i', b'o']);
+    assert_eq!( &tlvs[7..], &[PP2_TYPE_SSL, 0, 5, PP2_CLIENT_SSL, 0, 0, 0, 1]);
+
+    //terminated here, what was agreed
+    info.agreed_alpn = Some(b"http/1.1".to_vec());
+    info.agreed_version = Some("TLSv1.2");
+    let v = v2_header(Some(&s4), Some(&d4), Some(&info));
+    let tlvs = &v[28..];
+    assert_eq!( v.len() - 16, ((v[14] as usize) << 8) | v[15] as usize);
+    assert_eq!( &tlvs[..11], &b"\x01\x00\x08http/1.1"[..]);
+    assert_eq!( &tlvs[11..18], &[PP2_TYPE_AUTHORITY, 0, 4, b'a', b'.', b'i', b'o']);
+    assert_eq!( &tlvs[18..], &b"\x20\x00\x0f\x01\x00\x00\x00\x01\x21\x00\x07TLSv1.2"[..]);
+}
+
+///Largest v1 header allowed by the spec, including the CRLF
+pub const V1_MAX: usize = 107;
+
+///Largest v2 header accepted. The spec allows 64KiB of TLVs but no load balancer sends that.
+pub const V2_MAX: usize = 16 + 4096;
+
+///Result of reading a PROXY protocol header from the front of a buffer
+#[derive(Clone,Debug,PartialEq,Eq)]
+pub enum Parsed {
+    ///A header `len` bytes long. `src` is the real client, `None` if the sender didn't know it
+    ///or it was a health check.
+    Done {
+        len: usize,
+        src: Option<SocketAddr>,
+        dst: Option<SocketAddr>
+    },
+    ///Not enough bytes to tell yet
+    More,
+    ///Not a valid header
+    Invalid(&'static str)
+}
+
+///Parse a v1 or v2 header from the start of `buf`. Anything after the header is ignored.
+pub fn parse(buf: &[u8]) -> Parsed {
+    let n = ::std::cmp::min(buf.len(), V2_SIGNATURE.len());
+    if buf[..n] == V2_SIGNATURE[..n] {
+        if n < V2_SIGNATURE.len() {
+            return Parsed::More;
+        }
+        return parse_v2(buf);
+    }
+    let n = ::std::cmp::min(buf.len(), 6);
+    if &buf[..n] == &b"PROXY "[..n] {
+        if n < 6 {
+            return Parsed::More;
+        }
+        return parse_v1(buf);
+    }
+    Parsed::Invalid("missing PROXY protocol signature")
+}
+
+fn parse_v1(buf: &[u8]) -> Parsed {
+    use std::str::{from_utf8,FromStr};
+
+    let end = match buf.windows(2).take(V1_MAX-1).position(|w| w == b"\r\n") {
+        Option::Some(i) => i,
+        Option::None if buf.len() < V1_MAX => return Parsed::More,
+        Option::None => return Parsed::Invalid("PROXY v1 header is too long")
+    };
+    let line = match from_utf8(&buf[..end]) {
+        Ok(s) => s,
+        Err(_) => return Parsed::Invalid("PROXY v1 header is not ascii")
+    };
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts.get(1) {
+        Option::Some(&"UNKNOWN") => return Parsed::Done{ len: end+2, src: None, dst: None },
+        Option::Some(&"TCP4") | Option::Some(&"TCP6") if parts.len() == 6 => { },
+        _ => return Parsed::Invalid("PROXY v1 header is malformed")
+    };
+    let v6 = parts[1] == "TCP6";
+    let addr = |ip: &str, port: &str| -> Option<SocketAddr> {
+        let ip = IpAddr::from_str(ip).ok()?;
+        if ip.is_ipv6() != v6 {
+            return None;
+        }
+        //ports may not have leading zeros
+        if port.len() > 1 && port.starts_with('0') {
+            return None;
+        }
+        let port = u16::from_str(port).ok()?;
+        Some(SocketAddr::new(ip, port))
+    };
+    match (addr(parts[2],parts[4]), addr(parts[3],parts[5])) {
+        (Some(src),Some(dst)) => Parsed::Done{ len: end+2, src: Some(src), dst: Some(dst) },
+        _ => Parsed::Invalid("PROXY v1 header has a bad address")
+    }
+}
+
+fn parse_v2(buf: &[u8]) -> Parsed {
+    use std::net::Ipv4Addr;
+
+    if buf.len() < 16 {
+        return Parsed::More;
+    }
+    let ver_cmd = buf[12];
+    let fam = buf[13];
+    let len = 16 + (((buf[14] as usize) << 8) | buf[15] as usize);
+    if ver_cmd >> 4 != 2 {
+        return Parsed::Invalid("PROXY v2 header has a bad version");
+    }
+    if len > V2_MAX {
+        return Parsed::Invalid("PROXY v2 header is too long");
+    }
+    if buf.len() < len {
+        return Parsed::More;
+    }
+    let body = &buf[16..len];
+    let port = |b: &[u8]| ((b[0] as u16) << 8) | b[1] as u16;
+    match ver_cmd {
+        V2_CMD_LOCAL => Parsed::Done{ len: len, src: None, dst: None },
+        V2_CMD_PROXY => match fam {
+            V2_TCP4 if body.len() >= 12 => {
+                let src = Ipv4Addr::new(body[0],body[1],body[2],body[3]);
+                let dst = Ipv4Addr::new(body[4],body[5],body[6],body[7]);
+                Parsed::Done {
+                    len: len,
+                    src: Some(SocketAddr::new(IpAddr::V4(src), port(&body[8..10]))),
+                    dst: Some(SocketAddr::new(IpAddr::V4(dst), port(&body[10..12])))
+                }
+            },
+            V2_TCP6 if body.len() >= 36 => {
+                let mut src = [0u8;16];
+                let mut dst = [0u8;16];
+                src.copy_from_slice(&body[0..16]);
+                dst.copy_from_slice(&body[16..32]);
+                Parsed::Done {
+                    len: len,
+                    src: Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(src)), port(&body[32..34]))),
+                    dst: Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(dst)), port(&body[34..36])))
+                }
+            },
+            V2_TCP4 | V2_TCP6 => Parsed::Invalid("PROXY v2 header address block is too short"),
+            //UDP, unix sockets, and unspecified don't describe a TCP client
+            _ => Parsed::Done{ len: len, src: None, dst: None }
+        },
+        _ => Parsed::Invalid("PROXY v2 header has a bad command")
+    }
+}
+#[test]
+fn test_proxy_parse() {
+    use std::str::FromStr;
+    let s4 = SocketAddr::from_str("192.168.0.1:56324").unwrap();
+    let d4 = SocketAddr::from_str("10.0.0.1:443").unwrap();
+    let s6 = SocketAddr::from_str("[2001:db8::2]:1000").unwrap();
+    let d6 = SocketAddr::from_str("[2001:db8::1]:443").unwrap();
+
+    //round trip what we send
+    for &(ref s,ref d) in &[(s4,d4),(s6,d6)] {
+        for v in &[ProxyVersion::V1, ProxyVersion::V2] {
+            let mut h = header(*v, Some(s), Some(d), None);
+            let len = h.len();
+            h.extend_from_slice(b"\x16\x03\x01");
+            assert_eq!( parse(&h), Parsed::Done{ len: len, src: Some(*s), dst: Some(*d)});
+            for i in 0..len {
+                assert_eq!( parse(&h[..i]), Parsed::More);
+            }
+        }
+    }
+    assert_eq!( parse(b"PROXY UNKNOWN\r\n"), Parsed::Done{ len: 15, src: None, dst: None});
+    assert_eq!( parse(&v2_header(None,None,None)), Parsed::Done{ len: 16, src: None, dst: None});
+
+    //broken headers
+    assert!( match parse(b"\x16\x03\x01\x00") { Parsed::Invalid(_) => true, _ => false });
+    assert!( match parse(b"PROXY TCP4 1.1.1.1 2.2.2.2 01 2\r\n") { Parsed::Invalid(_) => true, _ => false });
+    assert!( match parse(b"PROXY TCP6 1.1.1.1 2.2.2.2 1 2\r\n") { Parsed::Invalid(_) => true, _ => false });
+    assert!( match parse(b"PROXY TCP4 1.1.1.1 2.2.2.2 1\r\n") { Parsed::Invalid(_) => true, _ => false });
+    assert!( match parse(b"PX") { Parsed::Invalid(_) => true, _ => false });
+    let long = vec![b' '; V1_MAX];
+    let mut h = b"PROXY TCP4".to_vec();
+    h.extend_from_slice(&long);
+    assert!( match parse(&h) { Parsed::Invalid(_) => true, _ => false });
+}
@@ -1,4 +1,7 @@
-use super::workerid::WorkerID;
+use super::workerid::{
+    WorkerID,
+    get_id
+};
 use super::conn::connection::Connection;
 use super::conn::stream::{
     Stream,
@@ -11,6 +14,7 @@ use super::lock::{
 };
 use super::mio::Token;
 use std::cell::RefCell;
+use std::net::SocketAddr;
 use std::sync::atomic::{
     AtomicPtr,
     Ordering
@@ -99,14 +103,33 @@ pub fn get_connection<'a>( t: &Token) -> Access<'a> {
     }
 }
 
+///Give the calling worker access to a connection it owns. Connections are owned by the worker
+///they were assigned to by `assign_stream`.
+pub fn owned_connection<'a>( t: &Token) -> Access<'a> {
+    if t.0 < 10 {
+        return Access::UnAllocated;
+    }
+    let i = to_index(t);
+    let mut slab: &'a mut Vec<Connection> = raw_ptr();
+    let ptr: &'a mut Connection = &mut slab[i];
+    if ! ptr.token_valid() {
+        return Access::UnAllocated;
+    }
+    if ptr.worker() == Some(WorkerID(get_id())) {
+        Access::Ok(ptr)
+    } else {
+        Access::Locked
+    }
+}
+
 ///Insert a stream. This spinlocks, as the lock MUST succeed. The event loop thread
 ///generally shouldn't be blocked, and it has a queue of de-allocated tokens so 
 ///this spinlock really should block _long_ if at all. Tokens _should not be_
 ///returned until their respective connections are closed.
-pub fn assign_stream(t: &Token, x: Stream, w: WorkerID) -> Result<(),Stream> {
+pub fn assign_stream(t: &Token, x: Stream, w: WorkerID, peer: Option<SocketAddr>) -> Result<(),Stream> {
     let i: usize = to_index(t);
     let mut slab = raw_ptr(); 
     let mut ptr = &mut slab[i];
-    let ret_val = ptr.setup(x,w);
+    let ret_val = ptr.setup(x,w,peer);
     ret_val
 }
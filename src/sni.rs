@@ -0,0 +1,90 @@
+
+use super::http::route::HostMatch;
+use std::str::FromStr;
+
+///Picks a backend pool from the server name a client sent in its ClientHello. This lets an
+///L4 listener front many TCP services without understanding their protocol. Routes are tried
+///in order and the first match wins.
+#[derive(Clone,Debug)]
+pub struct SniRoutes {
+    list: Vec<(HostMatch,usize)>,
+    ///Pool for clients which sent no name, or a name nothing matched
+    fallback: Option<usize>
+}
+impl SniRoutes {
+
+    ///Build a table which sends everyone to the first pool
+    pub fn new() -> SniRoutes {
+        SniRoutes {
+            list: Vec::new(),
+            fallback: Some(0)
+        }
+    }
+
+    ///Send names matching `host` to `pool`
+    pub fn route(mut self, host: HostMatch, pool: usize) -> SniRoutes {
+        self.list.push((host, pool));
+        self
+    }
+
+    ///Where unmatched clients go, `None` to turn them away
+    pub fn fallback(mut self, pool: Option<usize>) -> SniRoutes {
+        self.fallback = pool;
+        self
+    }
+
+    ///Every pool a client can be sent to
+    pub fn pools<'a>(&'a self) -> Box<dyn Iterator<Item=usize> + 'a> {
+        Box::new(self.list.iter().map(|x| x.1).chain(self.fallback))
+    }
+
+    ///Find the pool for a server name
+    pub fn find(&self, sni: Option<&str>) -> Option<usize> {
+        let name = sni.map(|x| x.trim_right_matches('.').to_ascii_lowercase());
+        let name = name.as_ref().map(|x| x.as_str());
+        self.list.iter()
+            .find(|&&(ref h, _)| name.is_some() && h.matches(name))
+            .map(|&(_, p)| p)
+            .or(self.fallback)
+    }
+}
+impl FromStr for SniRoutes {
+    type Err = String;
+
+    ///Read `<host>=<pool>` pairs split by commas, such as `db.example.com=1,*.mqtt.example.com=2,*=0`.
+    ///A `*` route is the fallback, without one unmatched clients are turned away.
+    fn from_str(s: &str) -> Result<SniRoutes,String> {
+        let mut r = SniRoutes::new().fallback(None);
+        for item in s.split(',').map(|x| x.trim()).filter(|x| ! x.is_empty()) {
+            let mut kv = item.splitn(2, '=');
+            let (host, pool) = match (kv.next(), kv.next()) {
+                (Some(h),Some(p)) => (h.trim(),p.trim()),
+                _ => return Err(format!("expected `host=pool` not `{}`", item))
+            };
+            let pool = usize::from_str(pool).map_err(|_| format!("bad pool `{}`", pool))?;
+            match HostMatch::from_str(host) {
+                Ok(HostMatch::Any) => r.fallback = Some(pool),
+                Ok(h) => r.list.push((h, pool)),
+                Err(_) => return Err(format!("bad host `{}`", host))
+            };
+        }
+        Ok(r)
+    }
+}
+#[test]
+fn test_sni_routes() {
+    let r = SniRoutes::from_str("db.example.com=1, *.mqtt.example.com=2").unwrap();
+    assert_eq!( r.find(Some("DB.example.com.")), Some(1));
+    assert_eq!( r.find(Some("a.mqtt.example.com")), Some(2));
+    assert_eq!( r.find(Some("mqtt.example.com")), None);
+    assert_eq!( r.find(None), None);
+
+    let r = SniRoutes::from_str("db.example.com=1,*=0").unwrap();
+    assert_eq!( r.find(Some("other.example.com")), Some(0));
+    assert_eq!( r.find(None), Some(0));
+    assert_eq!( SniRoutes::new().find(Some("x")), Some(0));
+    assert_eq!( r.pools().collect::<Vec<_>>(), vec![1,0]);
+
+    assert!( SniRoutes::from_str("db.example.com").is_err() );
+    assert!( SniRoutes::from_str("=1").is_err() );
+}
@@ -0,0 +1,1094 @@
+
+use super::slab::{
+    Access,
+    owned_connection
+};
+use super::conn::acceptor::get_acceptor;
+use super::listener::{
+    Accepting,
+    ListenerID,
+    Mode,
+    get_listener
+};
+use super::http::session::Session;
+use super::http::forwarded::Peer;
+use super::http::HttpError;
+use super::metrics::{
+    Metric,
+    incr
+};
+use super::conn::hello::TlsInfo;
+use super::cidr::any_contains;
+use super::conn::fault::Fault;
+use super::conn::connection::Connection;
+use super::conn::stream::StreamType;
+use super::pool::get_pool;
+use super::proxy::header;
+use super::idle::IdleBackends;
+use super::config::{
+    get_backend_timeout,
+    get_upgraded_timeout
+};
+use super::ipc::{
+    Events,
+    Requests,
+    send_request,
+    wait_events
+};
+use super::workerid::set_id;
+use super::mio::{
+    Ready,
+    Token
+};
+use std::net::SocketAddr;
+use std::collections::{
+    HashMap,
+    HashSet,
+    VecDeque
+};
+use std::time::{
+    Duration,
+    Instant
+};
+use std::io::{
+    Error as OSFault,
+    ErrorKind
+};
+
+///Most bytes held for a connection, read but not yet written to its partner. Reading from a
+///connection stops while its partner is this backed up.
+const MAX_BUFFERED: usize = 64 * 1024;
+
+///How long a client whose request was refused is read from before it's closed, see `linger`
+pub const LINGER_MS: u64 = 2000;
+
+///Explain why a connection could not be accessed
+#[inline(always)]
+fn access_fault(a: Access) -> Fault {
+    match a {
+        Access::UnAllocated => Fault::OS(OSFault::new(ErrorKind::NotFound, "connection is not allocated")),
+        Access::Locked => Fault::OS(OSFault::new(ErrorKind::PermissionDenied, "connection is owned by another worker")),
+        Access::Ok(_) => Fault::None
+    }
+}
+
+///Strip any PROXY protocol header, peek the ClientHello, then start TLS once it has arrived.
+///In passthrough mode the ClientHello is read and kept instead, and there is no handshake.
+///Redirect listeners speak plain HTTP, so they are done once any PROXY header is read.
+///Returns if the handshake is done.
+fn resume_accept(conn: &mut Connection) -> Result<bool,Fault> {
+    let mut a = match conn.accepting {
+        Option::Some(a) => a,
+        Option::None => return conn.handshake()
+    };
+    let listener = match get_listener(a.listener) {
+        Option::Some(x) => x,
+        Option::None => return Err(Fault::OS(OSFault::new(ErrorKind::NotFound, "unknown listener")))
+    };
+    if a.proxy_header {
+        if ! conn.read_proxy_header()? {
+            return Ok(false);
+        }
+        a.proxy_header = false;
+        conn.accepting = Some(a);
+    }
+    match listener.mode {
+        Mode::Passthrough => {
+            if ! conn.read_hello()? {
+                return Ok(false);
+            }
+            conn.accepting = None;
+            return Ok(true);
+        },
+        Mode::Redirect => {
+            //plain HTTP, there's nothing to handshake
+            conn.accepting = None;
+            return Ok(true);
+        },
+        _ => { }
+    };
+    let acceptor = match listener.acceptor.and_then(get_acceptor) {
+        Option::Some(x) => x,
+        Option::None => return Err(Fault::OS(OSFault::new(ErrorKind::NotFound, "unknown acceptor")))
+    };
+    if ! conn.peek_hello()? {
+        return Ok(false);
+    }
+    conn.accepting = None;
+    conn.start_tls(acceptor)
+}
+
+///Handle `Events::Accept`. Starts the TLS handshake on a client the event loop accepted and
+///handed to this worker. A PROXY protocol header from a trusted upstream, and the ClientHello
+///are looked at first, so this may need to be continued with `continue_handshake` before the
+///TLS library has even seen the client. Returns if the handshake finished already, which is
+///unlikely.
+pub fn start_handshake(t: &Token, l: ListenerID) -> Result<bool,Fault> {
+    let listener = match get_listener(l) {
+        Option::Some(x) => x,
+        Option::None => return Err(Fault::OS(OSFault::new(ErrorKind::NotFound, "unknown listener")))
+    };
+    match owned_connection(t) {
+        Access::Ok(conn) => {
+            let trusted = match conn.peer {
+                Option::Some(ref addr) => any_contains(&listener.proxy_from, &addr.ip()),
+                Option::None => false
+            };
+            conn.listener = Some(l);
+            conn.accepting = Some(Accepting {
+                listener: l,
+                proxy_header: trusted
+            });
+            if listener.mode == Mode::Http || listener.mode == Mode::Redirect {
+                conn.http = Some(Box::new(Session::new(&listener.http)));
+            }
+            resume_accept(conn)
+        },
+        x => Err(access_fault(x))
+    }
+}
+
+///Continue a handshake after an event on a connection which is still handshaking. Returns if
+///the handshake has finished.
+pub fn continue_handshake(t: &Token) -> Result<bool,Fault> {
+    match owned_connection(t) {
+        Access::Ok(conn) => resume_accept(conn),
+        x => Err(access_fault(x))
+    }
+}
+#[test]
+fn test_start_handshake() {
+    use super::slab::{assign_stream,build_connections};
+    use super::conn::stream::Stream;
+    use super::conn::hello::test_hello;
+    use super::listener::{Listener,add_listener};
+    use super::workerid::{WorkerID,set_id};
+    use super::mio::tcp::TcpStream as MioTcpStream;
+    use std::net::{TcpListener,TcpStream};
+    use std::os::unix::io::{FromRawFd,IntoRawFd};
+    use std::io::Write;
+    use std::thread::sleep;
+
+    let done = |r: Result<bool,Fault>| match r {
+        Ok(x) => x,
+        Err(_) => panic!("handshake failed")
+    };
+    build_connections();
+    set_id(7);
+    let sock = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = sock.local_addr().unwrap();
+    let mut client = TcpStream::connect(addr).unwrap();
+    let (x, peer) = sock.accept().unwrap();
+    x.set_nonblocking(true).unwrap();
+    let x = unsafe{ MioTcpStream::from_raw_fd(x.into_raw_fd()) };
+    //passthrough needs no certificate, but takes the same path up to the TLS library
+    let l = add_listener(Listener::passthrough(addr));
+    let t = Token(10);
+    assert!( assign_stream(&t, Stream::Tcp(x), WorkerID(7), Some(peer)).is_ok() );
+    assert!( ! done(start_handshake(&t, l)) );
+
+    let hello = test_hello("db.example.com", &[]);
+    client.write_all(&hello[..10]).unwrap();
+    sleep(Duration::from_millis(10));
+    assert!( ! done(continue_handshake(&t)) );
+    client.write_all(&hello[10..]).unwrap();
+    let mut tries = 0;
+    while ! done(continue_handshake(&t)) {
+        tries += 1;
+        assert!( tries < 100 );
+        sleep(Duration::from_millis(10));
+    }
+    match owned_connection(&t) {
+        Access::Ok(c) => {
+            assert_eq!( c.tls.as_ref().and_then(|x| x.sni.clone()), Some("db.example.com".to_string()));
+            assert_eq!( c.buf.as_slice(), hello.as_slice());
+            assert!( c.accepting.is_none() );
+        },
+        _ => panic!("the worker should own the connection")
+    };
+
+    //only the owning worker may touch it
+    set_id(8);
+    assert!( match continue_handshake(&t) { Err(Fault::OS(_)) => true, _ => false } );
+}
+
+///Pick the pool for a client whose handshake has finished. L4 and passthrough clients are
+///routed by the server name from their ClientHello, so no protocol needs to be understood.
+///HTTP clients are routed per request by their `Session`, and redirected clients aren't sent
+///anywhere, so this is `None` for them.
+pub fn pick_pool(t: &Token) -> Result<Option<usize>,Fault> {
+    let conn = match owned_connection(t) {
+        Access::Ok(c) => c,
+        x => return Err(access_fault(x))
+    };
+    let listener = match conn.listener.and_then(get_listener) {
+        Option::Some(x) => x,
+        Option::None => return Err(Fault::OS(OSFault::new(ErrorKind::NotFound, "unknown listener")))
+    };
+    if listener.mode == Mode::Http || listener.mode == Mode::Redirect {
+        return Ok(None);
+    }
+    let sni = conn.tls.as_ref().and_then(|x| x.sni.as_ref()).map(|x| x.as_str());
+    match listener.sni.find(sni) {
+        Option::Some(pool) => Ok(Some(pool)),
+        Option::None => Err(Fault::NoRoute)
+    }
+}
+
+///Pair a client with a backend connection the event loop opened for pool `pool`. If the pool
+///wants a PROXY protocol header it is queued so it is the first thing the backend receives.
+///Bytes an L4 client sent before it was linked are queued after it. An HTTP client's waiting
+///request is left in its buffer, and goes once the client is pumped.
+pub fn link_backend(client: &Token, backend: &Token, pool: usize) -> Result<(),Fault> {
+    pair(client, backend, pool, true)
+}
+
+///Link an idle backend from `pool` to an HTTP client, rather than asking the event loop for a
+///new connection. Idle backends which have closed, or sent something unasked, are pushed on
+///`close`. Returns false if there was none to use.
+pub fn reuse_backend(client: &Token, pool: usize, idle: &mut IdleBackends, now: Instant, close: &mut Vec<Token>) -> Result<bool,Fault> {
+    let backend = idle.take(pool, now, close, |t| match owned_connection(t) {
+        Access::Ok(b) => ! b.is_stale(),
+        _ => false
+    });
+    match backend {
+        Option::Some(b) => {
+            pair(client, &b, pool, false)?;
+            Ok(true)
+        },
+        Option::None => Ok(false)
+    }
+}
+
+///Link a client and backend. A PROXY protocol header is only sent on a `fresh` connection.
+fn pair(client: &Token, backend: &Token, pool: usize, fresh: bool) -> Result<(),Fault> {
+    let c = match owned_connection(client) {
+        Access::Ok(c) => c,
+        x => return Err(access_fault(x))
+    };
+    let b = match owned_connection(backend) {
+        Access::Ok(b) => b,
+        x => return Err(access_fault(x))
+    };
+    c.other = *backend;
+    b.other = *client;
+    match get_pool(pool).and_then(|p| p.proxy) {
+        Option::Some(v) if fresh => {
+            let local = c.local_addr();
+            //set from the ClientHello whether TLS was terminated or passed through
+            let tls = c.tls.as_ref().map(|x| &**x);
+            b.pending = header(v, c.peer.as_ref(), local.as_ref(), tls);
+        },
+        _ => { }
+    };
+    match c.http.as_mut() {
+        Option::Some(session) => session.routed(pool),
+        Option::None => {
+            //replay what was read before there was a backend, such as a passed through ClientHello
+            b.pending.extend_from_slice(c.buf.as_slice());
+            c.buf.clear();
+        }
+    };
+    Ok(())
+}
+
+///Separate an HTTP client from its backend, so its next request can go to another pool or
+///before the client is closed. A backend which can take more requests is kept in `idle`.
+///Returns a token the caller should close, the backend if it can't be reused, or a connection
+///`idle` dropped to make room.
+pub fn unlink_backend(client: &Token, idle: &mut IdleBackends, now: Instant) -> Result<Option<Token>,Fault> {
+    let c = match owned_connection(client) {
+        Access::Ok(c) => c,
+        x => return Err(access_fault(x))
+    };
+    if ! c.has_partner() {
+        return Ok(None);
+    }
+    let backend = c.other;
+    c.other = Token(0);
+    let b = match owned_connection(&backend) {
+        Access::Ok(b) => b,
+        x => return Err(access_fault(x))
+    };
+    b.other = Token(0);
+    let pool = match c.http.as_ref() {
+        Option::Some(session) if session.backend_reusable() => session.pool(),
+        _ => None
+    };
+    //a PROXY protocol header describes a single client, so those backends aren't shared
+    let pool = pool.and_then(|p| match get_pool(p) {
+        Option::Some(x) if x.proxy.is_none() => Some(p),
+        _ => None
+    });
+    match pool {
+        Option::Some(p) if b.pending.is_empty() && b.buf.is_empty() => Ok(idle.put(p, backend, now)),
+        _ => Ok(Some(backend))
+    }
+}
+
+///An HTTP client's backend couldn't be connected, after `Events::Failure`, or hasn't answered
+///in time. `status` is 502 or 504. The client is sent an error page if it's still waiting for
+///a response, and should then be closed. Returns `Pumped::Closed`, or a fault for a client
+///which isn't HTTP, as it can only be closed.
+pub fn backend_failed(client: &Token, status: u16) -> Result<Pumped,Fault> {
+    let c = match owned_connection(client) {
+        Access::Ok(c) => c,
+        x => return Err(access_fault(x))
+    };
+    if c.http.is_none() {
+        return Err(Fault::OS(OSFault::new(ErrorKind::NotConnected, "backend connection failed")));
+    }
+    fail_client(c, status);
+    Ok(Pumped::Closed)
+}
+
+///Send an HTTP client an error page for its backend, best effort
+fn fail_client(c: &mut Connection, status: u16) {
+    match c.http.as_mut() {
+        Option::Some(session) => session.backend_failed(status, &mut c.buf, &mut c.pending),
+        Option::None => return
+    };
+    let _ = flush(c);
+}
+
+///How long a connection may go without traffic before the worker closes it. Pairs which have
+///switched protocols, such as WebSocket, use `config::get_upgraded_timeout` for both the client
+///and backend. Other connections have no idle timeout, `None`.
+pub fn idle_timeout(t: &Token) -> Result<Option<Duration>,Fault> {
+    let conn = match owned_connection(t) {
+        Access::Ok(c) => c,
+        x => return Err(access_fault(x))
+    };
+    let upgraded = |c: &Connection| c.http.as_ref().map(|s| s.is_upgraded()).unwrap_or(false);
+    if upgraded(conn) {
+        return Ok(Some(get_upgraded_timeout()));
+    }
+    if ! conn.has_partner() || conn.http.is_some() {
+        return Ok(None);
+    }
+    match owned_connection(&conn.other) {
+        Access::Ok(o) if upgraded(o) => Ok(Some(get_upgraded_timeout())),
+        Access::Ok(_) => Ok(None),
+        x => Err(access_fault(x))
+    }
+}
+
+///What `pump` found
+#[derive(Copy,Clone,Debug,PartialEq,Eq)]
+pub enum Pumped {
+    ///The pair is still open
+    Open,
+    ///The connection has hit EOF, or the HTTP exchange is finished, and the pair should be
+    ///closed. An HTTP client's backend should be let go with `unlink_backend` first, so it
+    ///may be reused.
+    Closed,
+    ///An HTTP client's next request is for this pool. Any current backend should be let go
+    ///with `unlink_backend`, then an idle backend tried with `reuse_backend`, before a new
+    ///connection is asked for with `Requests::New`.
+    Route(usize)
+}
+
+///Read what has arrived on a connection into its buffer. Returns false on EOF.
+#[inline(always)]
+fn fill_buffer(conn: &mut Connection) -> Result<bool,Fault> {
+    match conn.fill(MAX_BUFFERED) {
+        Ok(0) => Ok(false),
+        Ok(_) => Ok(true),
+        Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(true),
+        Err(e) => Err(Fault::from(e))
+    }
+}
+
+///Write what is queued for a connection, as much as it will take
+#[inline(always)]
+fn flush(conn: &mut Connection) -> Result<(),Fault> {
+    match conn.write_pending() {
+        Ok(_) => Ok(()),
+        Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+        Err(e) => Err(Fault::from(e))
+    }
+}
+
+///Send what a client can take of a file its `Session` is answering with. Returns true when
+///the file has just been sent in full, so requests held behind it can go.
+fn send_file(c: &mut Connection) -> Result<bool,Fault> {
+    let mut f = match c.http.as_mut().and_then(|s| s.file.take()) {
+        Option::Some(f) => f,
+        Option::None => return Ok(false)
+    };
+    let done = match c.send_file(&mut f) {
+        Ok(done) => done,
+        Err(ref e) if e.kind() == ErrorKind::WouldBlock => false,
+        Err(e) => return Err(Fault::from(e))
+    };
+    if ! done {
+        match c.http.as_mut() {
+            Option::Some(s) => s.file = Some(f),
+            Option::None => { }
+        };
+    }
+    Ok(done)
+}
+
+///Run a client's buffered requests through its `Session`, writing those for the backend to
+///`out`. Responses made from disk are queued for the client, and any file sent.
+fn client_requests(c: &mut Connection, out: &mut Vec<u8>) -> Result<Option<usize>,Fault> {
+    loop {
+        let tls = c.is_tls();
+        let route = {
+            let peer = client_peer(&c.peer, &c.tls, tls);
+            match c.http.as_mut() {
+                Option::Some(session) => session.on_request(&mut c.buf, out, &mut c.pending, &peer),
+                Option::None => return Err(Fault::OS(OSFault::new(ErrorKind::InvalidInput, "connection is not HTTP")))
+            }
+        };
+        let route = match route {
+            Ok(x) => x,
+            Err(HttpError::NoRoute) => return Err(Fault::NoRoute),
+            Err(e) => {
+                count_limit(&e);
+                //try to get the refusal out before the client is closed
+                let _ = flush(c);
+                if e.is_smuggling() {
+                    incr(Metric::Smuggling);
+                    return Err(Fault::Smuggling(e));
+                }
+                return Err(Fault::BadRequest(e));
+            }
+        };
+        if route.is_some() || ! send_file(c)? {
+            return Ok(route);
+        }
+    }
+}
+
+///Count a request or response refused for being over a size limit
+#[inline(always)]
+fn count_limit(e: &HttpError) {
+    match e {
+        &HttpError::LineTooLong => incr(Metric::LimitRequestLine),
+        &HttpError::HeadTooLarge | &HttpError::TooManyHeaders => incr(Metric::LimitRequestHead),
+        &HttpError::BodyTooLarge => incr(Metric::LimitRequestBody),
+        _ => { }
+    };
+}
+
+///Check an L4 or passthrough client hasn't sent more than its listener's `max_stream`. There
+///are no requests to refuse, so a client over the limit is just closed.
+fn limit_stream(c: &mut Connection, n: usize) -> Result<(),Fault> {
+    c.sent += n as u64;
+    let max = c.listener.and_then(get_listener).and_then(|l| l.max_stream);
+    match max {
+        Option::Some(m) if c.sent > m => {
+            incr(Metric::LimitStream);
+            Err(Fault::OS(OSFault::new(ErrorKind::InvalidData, "client sent more than max_stream")))
+        },
+        _ => Ok(())
+    }
+}
+
+///Start closing an HTTP client whose request was refused, after `Fault::BadRequest` or
+///`Fault::Smuggling`. Closing while the client is still sending could reset the connection
+///before it reads the refusal, so the refusal is written out, the write side shut, and what
+///the client sends is read and thrown away with `drain` until it closes or `LINGER_MS` pass.
+///Any backend should be let go first.
+pub fn linger(t: &Token) -> Result<(),Fault> {
+    let conn = match owned_connection(t) {
+        Access::Ok(c) => c,
+        x => return Err(access_fault(x))
+    };
+    conn.buf.clear();
+    match conn.http.as_mut() {
+        Option::Some(session) => session.file = None,
+        Option::None => { }
+    };
+    flush(conn)?;
+    if conn.pending.is_empty() {
+        conn.shutdown_write()?;
+    }
+    let r = interest(conn, 0);
+    conn.set_interest(r)?;
+    Ok(())
+}
+
+///Handle an event on a client `linger` was called for. Returns `Pumped::Closed` once the
+///client has closed.
+pub fn drain(t: &Token) -> Result<Pumped,Fault> {
+    let conn = match owned_connection(t) {
+        Access::Ok(c) => c,
+        x => return Err(access_fault(x))
+    };
+    if ! conn.pending.is_empty() {
+        flush(conn)?;
+        if conn.pending.is_empty() {
+            conn.shutdown_write()?;
+        }
+    }
+    loop {
+        match conn.fill(MAX_BUFFERED) {
+            Ok(0) => return Ok(Pumped::Closed),
+            Ok(_) => conn.buf.clear(),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => break,
+            Err(e) => return Err(Fault::from(e))
+        };
+    }
+    let r = interest(conn, 0);
+    conn.set_interest(r)?;
+    Ok(Pumped::Open)
+}
+
+///What a connection should be polled for. It is written to while anything is queued for it,
+///or a file is being sent. It isn't read while its partner has `partner_pending` bytes backed
+///up, as level triggered readiness would report it over and over with nothing to be done.
+fn interest(c: &Connection, partner_pending: usize) -> Ready {
+    let mut r = Ready::none();
+    if partner_pending < MAX_BUFFERED {
+        r = r | Ready::readable();
+    }
+    let sending = c.http.as_ref().map(|s| s.file.is_some()).unwrap_or(false);
+    if ! c.pending.is_empty() || sending {
+        r = r | Ready::writable();
+    }
+    r
+}
+#[test]
+fn test_interest() {
+    let mut c = Connection::new();
+    assert_eq!( interest(&c, 0), Ready::readable());
+    c.pending.extend_from_slice(b"left over");
+    assert_eq!( interest(&c, 0), Ready::readable() | Ready::writable());
+    assert_eq!( interest(&c, MAX_BUFFERED), Ready::writable());
+    c.pending.clear();
+    assert_eq!( interest(&c, MAX_BUFFERED), Ready::none());
+}
+
+#[test]
+fn test_linger() {
+    use super::slab::{assign_stream,build_connections};
+    use super::conn::stream::Stream;
+    use super::workerid::{WorkerID,set_id};
+    use super::mio::tcp::TcpStream as MioTcpStream;
+    use std::net::{TcpListener,TcpStream};
+    use std::os::unix::io::{FromRawFd,IntoRawFd};
+    use std::io::{Read,Write};
+    use std::thread::sleep;
+
+    build_connections();
+    set_id(9);
+    let sock = TcpListener::bind("127.0.0.1:0").unwrap();
+    let mut client = TcpStream::connect(sock.local_addr().unwrap()).unwrap();
+    let (x, _) = sock.accept().unwrap();
+    x.set_nonblocking(true).unwrap();
+    let x = unsafe{ MioTcpStream::from_raw_fd(x.into_raw_fd()) };
+    let t = Token(11);
+    assert!( assign_stream(&t, Stream::Tcp(x), WorkerID(9), None).is_ok() );
+    match owned_connection(&t) {
+        Access::Ok(c) => c.pending.extend_from_slice(b"HTTP/1.1 400 Bad Request\r\n\r\n"),
+        _ => panic!("the worker should own the connection")
+    };
+
+    //the refusal arrives and is followed by EOF, while the client can still send
+    assert!( linger(&t).is_ok() );
+    let mut got = Vec::new();
+    client.read_to_end(&mut got).unwrap();
+    assert_eq!( got.as_slice(), b"HTTP/1.1 400 Bad Request\r\n\r\n");
+    client.write_all(b"the rest of a body").unwrap();
+    sleep(Duration::from_millis(10));
+    assert_eq!( drain(&t).ok(), Some(Pumped::Open));
+    drop(client);
+    sleep(Duration::from_millis(10));
+    assert_eq!( drain(&t).ok(), Some(Pumped::Closed));
+}
+
+///If an HTTP client's exchange is over
+#[inline(always)]
+fn is_finished(c: &Connection) -> bool {
+    c.http.as_ref().map(|s| s.is_finished()).unwrap_or(false)
+}
+
+///Describe a client for its `Session`
+#[inline(always)]
+fn client_peer<'a>(addr: &'a Option<SocketAddr>, info: &'a Option<Box<TlsInfo>>, tls: bool) -> Peer<'a> {
+    Peer {
+        addr: addr.as_ref(),
+        sni: info.as_ref().and_then(|x| x.sni.as_ref()).map(|x| x.as_str()),
+        tls: tls
+    }
+}
+
+///Move bytes which have arrived on a connection to its partner. In L4 mode they're copied as
+///they are, in HTTP mode they pass through the client's `Session`, which decides the pool
+///each request goes to. An HTTP client has no partner until its first request is routed, and
+///may never have one if it is answered from disk. Either side is registered writable while it
+///has bytes queued, and should be pumped when it becomes writable as well.
+pub fn pump(t: &Token) -> Result<Pumped,Fault> {
+    let conn = match owned_connection(t) {
+        Access::Ok(c) => c,
+        x => return Err(access_fault(x))
+    };
+
+    if ! conn.has_partner() {
+        if conn.http.is_none() {
+            return Err(Fault::OS(OSFault::new(ErrorKind::NotConnected, "connection has no partner")));
+        }
+        let open = fill_buffer(conn)?;
+        let route = client_requests(conn, &mut Vec::new())?;
+        flush(conn)?;
+        let r = interest(conn, 0);
+        conn.set_interest(r)?;
+        let finished = is_finished(conn);
+        return Ok(match route {
+            Option::Some(pool) => Pumped::Route(pool),
+            Option::None if open && ! finished => Pumped::Open,
+            Option::None => Pumped::Closed
+        });
+    }
+
+    let other = match owned_connection(&conn.other) {
+        Access::Ok(o) => o,
+        x => return Err(access_fault(x))
+    };
+
+    //don't read more while the partner is backed up
+    let open = if other.pending.len() >= MAX_BUFFERED {
+        true
+    } else {
+        match fill_buffer(conn) {
+            Ok(x) => x,
+            Err(f) => {
+                //a backend which broke off gets its client a 502
+                if conn.http.is_none() && other.http.is_some() {
+                    fail_client(other, 502);
+                }
+                return Err(f);
+            }
+        }
+    };
+
+    let (finished, route) = match (conn.http.is_some(), other.http.is_some()) {
+        //client to backend
+        (true,_) => {
+            let route = client_requests(conn, &mut other.pending)?;
+            (is_finished(conn), route)
+        },
+        //backend to client
+        (false,true) => {
+            let tls = other.is_tls();
+            let peer = client_peer(&other.peer, &other.tls, tls);
+            let r = match other.http.as_mut() {
+                Option::Some(session) => session.on_response(&mut conn.buf, &mut other.pending, &peer),
+                Option::None => Ok(())
+            };
+            match r {
+                Ok(()) => { },
+                Err(e) => {
+                    match e {
+                        HttpError::HeadTooLarge | HttpError::TooManyHeaders => incr(Metric::LimitResponseHead),
+                        _ => { }
+                    };
+                    //the backend can't be understood, its client gets a 502
+                    fail_client(other, 502);
+                    return Err(Fault::from(e));
+                }
+            };
+            if ! open {
+                //the backend closed without answering everything
+                match other.http.as_mut() {
+                    Option::Some(ref mut session) if session.unanswered() => session.backend_failed(502, &mut other.buf, &mut other.pending),
+                    _ => { }
+                };
+            }
+            //a request may have been held until this response finished
+            let route = if other.buf.is_empty() {
+                None
+            } else {
+                client_requests(other, &mut conn.pending)?
+            };
+            (is_finished(other), route)
+        },
+        (false,false) => {
+            if conn.listener.is_some() {
+                let n = conn.buf.len();
+                limit_stream(conn, n)?;
+            }
+            other.pending.extend_from_slice(conn.buf.as_slice());
+            conn.buf.clear();
+            (false, None)
+        }
+    };
+
+    flush(other)?;
+    flush(conn)?;
+    let r = interest(other, conn.pending.len());
+    other.set_interest(r)?;
+    let r = interest(conn, other.pending.len());
+    conn.set_interest(r)?;
+    Ok(match route {
+        Option::Some(pool) => Pumped::Route(pool),
+        Option::None if open && ! finished => Pumped::Open,
+        Option::None => Pumped::Closed
+    })
+}
+
+///How often a worker looks for connections which have been quiet too long
+const TICK_MS: u64 = 250;
+
+///The client and backend of the pair a connection belongs to. Clients are the connections
+///accepted on a listener, the only ones with a peer address. Either may be `Token(0)`.
+fn sides(t: &Token) -> Option<(Token,Token)> {
+    match owned_connection(t) {
+        Access::Ok(c) => if c.peer.is_some() {
+            Some((*t, c.other))
+        } else {
+            Some((c.other, *t))
+        },
+        _ => None
+    }
+}
+
+///If an HTTP client has sent its backend a request which hasn't begun to be answered
+pub fn unanswered(t: &Token) -> Result<bool,Fault> {
+    let conn = match owned_connection(t) {
+        Access::Ok(c) => c,
+        x => return Err(access_fault(x))
+    };
+    Ok(conn.has_partner() && conn.http.as_ref().map(|s| s.unanswered()).unwrap_or(false))
+}
+
+///Stop reading a client while it waits for a backend. Anything queued for it is still written.
+fn hold(t: &Token) -> Result<(),Fault> {
+    let conn = match owned_connection(t) {
+        Access::Ok(c) => c,
+        x => return Err(access_fault(x))
+    };
+    flush(conn)?;
+    let r = interest(conn, MAX_BUFFERED);
+    conn.set_interest(r)?;
+    Ok(())
+}
+
+///What a worker thread keeps between messages from the event loop
+struct Worker {
+    idle: IdleBackends,
+    ///Clients which asked for a backend with `Requests::New`, oldest first, with the pool. The
+    ///event loop answers each worker in order, so a client closed while waiting is left as
+    ///`None` for its answer to be matched to.
+    waiting: VecDeque<(Option<Token>,usize)>,
+    ///Clients which haven't finished their handshake
+    handshaking: HashSet<Token>,
+    ///Refused clients being drained, and when they're closed regardless
+    lingering: HashMap<Token,Instant>,
+    ///When each open connection last had an event
+    seen: HashMap<Token,Instant>
+}
+impl Worker {
+
+    fn new() -> Worker {
+        Worker {
+            idle: IdleBackends::new(),
+            waiting: VecDeque::new(),
+            handshaking: HashSet::new(),
+            lingering: HashMap::new(),
+            seen: HashMap::new()
+        }
+    }
+
+    ///If a client is waiting for a backend
+    #[inline(always)]
+    fn is_waiting(&self, t: &Token) -> bool {
+        self.waiting.iter().any(|x| x.0 == Some(*t))
+    }
+
+    ///Handle a message from the event loop
+    fn on_event(&mut self, e: Events, now: Instant) {
+        match e {
+            Events::Accept(t, l) => {
+                self.seen.insert(t, now);
+                match start_handshake(&t, l) {
+                    Ok(true) => self.ready(t, now),
+                    Ok(false) => {
+                        self.handshaking.insert(t);
+                    },
+                    Err(_) => self.abort(t)
+                };
+            },
+            Events::Open(b) => match self.waiting.pop_front() {
+                Option::Some((Option::Some(c), pool)) => {
+                    self.seen.insert(b, now);
+                    match link_backend(&c, &b, pool) {
+                        Ok(()) => self.serve(c, now),
+                        Err(_) => {
+                            self.shut(b);
+                            self.abort(c);
+                        }
+                    };
+                },
+                //the client has gone, or nobody asked
+                _ => self.shut(b)
+            },
+            Events::Failure => match self.waiting.pop_front() {
+                Option::Some((Option::Some(c), _)) => self.fail(c, 502, now),
+                _ => { }
+            },
+            Events::Event(e) => {
+                let t = e.token();
+                match owned_connection(&t) {
+                    Access::Ok(_) => { },
+                    //closed since the event was sent
+                    _ => return
+                };
+                self.seen.insert(t, now);
+                if self.lingering.contains_key(&t) {
+                    match drain(&t) {
+                        Ok(Pumped::Open) => { },
+                        _ => self.shut(t)
+                    };
+                } else if self.handshaking.contains(&t) {
+                    match continue_handshake(&t) {
+                        Ok(true) => {
+                            self.handshaking.remove(&t);
+                            self.ready(t, now);
+                        },
+                        Ok(false) => { },
+                        Err(_) => self.abort(t)
+                    };
+                } else if self.idle.remove(&t) {
+                    //an idle backend closed, or sent something nobody asked for
+                    self.shut(t);
+                } else if self.is_waiting(&t) {
+                    if hold(&t).is_err() {
+                        self.abort(t);
+                    }
+                } else {
+                    self.serve(t, now);
+                }
+            }
+        };
+    }
+
+    ///A client's handshake is done. L4 and passthrough clients are given a backend, HTTP
+    ///clients start on their requests.
+    fn ready(&mut self, t: Token, now: Instant) {
+        match pick_pool(&t) {
+            Ok(Option::Some(pool)) => self.request(t, pool),
+            Ok(Option::None) => self.serve(t, now),
+            Err(_) => self.abort(t)
+        };
+    }
+
+    ///Ask the event loop for a connection to `pool` for a client
+    fn request(&mut self, t: Token, pool: usize) {
+        if hold(&t).is_err() {
+            return self.abort(t);
+        }
+        self.waiting.push_back((Some(t), pool));
+        send_request(Requests::New(pool));
+    }
+
+    ///Pump a connection and act on what was found
+    fn serve(&mut self, t: Token, now: Instant) {
+        match pump(&t) {
+            Ok(Pumped::Open) => { },
+            Ok(Pumped::Closed) => self.end(t, now),
+            Ok(Pumped::Route(pool)) => self.route(t, pool, now),
+            Err(Fault::BadRequest(_)) |
+            Err(Fault::Smuggling(_)) => self.refuse(t, now),
+            Err(_) => self.abort(t)
+        };
+    }
+
+    ///An HTTP client's next request is for `pool`. Its backend is let go, and an idle one
+    ///from `pool` used if there is one, otherwise the event loop is asked for a new one.
+    fn route(&mut self, t: Token, pool: usize, now: Instant) {
+        let client = match sides(&t) {
+            Option::Some((c,_)) => c,
+            Option::None => return
+        };
+        if self.is_waiting(&client) {
+            return;
+        }
+        if ! self.let_go(client, now) {
+            return self.abort(client);
+        }
+        let mut close = Vec::new();
+        let reused = reuse_backend(&client, pool, &mut self.idle, now, &mut close);
+        for b in close {
+            self.shut(b);
+        }
+        match reused {
+            Ok(true) => self.serve(client, now),
+            Ok(false) => self.request(client, pool),
+            Err(_) => self.abort(client)
+        };
+    }
+
+    ///Separate an HTTP client from its backend, closing the backend unless it is kept idle.
+    ///Returns false if the client can't be reached.
+    fn let_go(&mut self, client: Token, now: Instant) -> bool {
+        match unlink_backend(&client, &mut self.idle, now) {
+            Ok(Option::Some(b)) => self.shut(b),
+            Ok(Option::None) => { },
+            Err(_) => return false
+        };
+        true
+    }
+
+    ///A pair is finished. An HTTP client's backend is kept for reuse if it can be, a backend
+    ///which closed is closed with its client.
+    fn end(&mut self, t: Token, now: Instant) {
+        let (client, backend) = match sides(&t) {
+            Option::Some(x) => x,
+            Option::None => return
+        };
+        let http = match owned_connection(&client) {
+            Access::Ok(c) => c.http.is_some(),
+            _ => false
+        };
+        if t != client || ! http || ! self.let_go(client, now) {
+            self.shut(backend);
+        }
+        self.shut(client);
+    }
+
+    ///Close a pair outright
+    fn abort(&mut self, t: Token) {
+        match sides(&t) {
+            Option::Some((client, backend)) => {
+                self.shut(backend);
+                self.shut(client);
+            },
+            Option::None => { }
+        };
+    }
+
+    ///A client's request was refused. Its backend is let go, and the client lingers.
+    fn refuse(&mut self, t: Token, now: Instant) {
+        let client = match sides(&t) {
+            Option::Some((c,_)) => c,
+            Option::None => return
+        };
+        if ! self.let_go(client, now) {
+            return self.abort(client);
+        }
+        self.linger(client, now);
+    }
+
+    ///A client's backend couldn't be connected, or took too long, and it's sent `status`. The
+    ///backend is closed, and the client lingers.
+    fn fail(&mut self, client: Token, status: u16, now: Instant) {
+        match backend_failed(&client, status) {
+            Ok(_) => {
+                match sides(&client) {
+                    Option::Some((_, backend)) => self.shut(backend),
+                    Option::None => { }
+                };
+                match owned_connection(&client) {
+                    Access::Ok(c) => c.other = Token(0),
+                    _ => { }
+                };
+                self.linger(client, now);
+            },
+            Err(_) => self.abort(client)
+        };
+    }
+
+    ///Drain a client until it closes, or `LINGER_MS` pass
+    fn linger(&mut self, client: Token, now: Instant) {
+        match linger(&client) {
+            Ok(()) => {
+                self.lingering.insert(client, now + Duration::from_millis(LINGER_MS));
+            },
+            Err(_) => self.shut(client)
+        };
+    }
+
+    ///Close a connection and give its token back to the event loop. Its partner should be
+    ///closed, or let go, as well.
+    fn shut(&mut self, t: Token) {
+        match owned_connection(&t) {
+            Access::Ok(c) => c.close(),
+            _ => return
+        };
+        self.seen.remove(&t);
+        self.handshaking.remove(&t);
+        self.lingering.remove(&t);
+        self.idle.remove(&t);
+        for w in self.waiting.iter_mut() {
+            if w.0 == Some(t) {
+                w.0 = None;
+            }
+        }
+        send_request(Requests::Close(t));
+    }
+
+    ///Close what has been quiet too long. Idle backends are dropped, and lingering clients
+    ///closed, when their time is up. An HTTP client whose backend has left a request unanswered
+    ///for `config::get_backend_timeout` is sent a 504, and an upgraded pair is closed after
+    ///`idle_timeout`. Traffic either way counts.
+    fn tick(&mut self, now: Instant) {
+        let mut close = Vec::new();
+        self.idle.expire(now, &mut close);
+        close.extend(self.lingering.iter().filter(|x| *x.1 <= now).map(|x| *x.0));
+        for t in close {
+            self.shut(t);
+        }
+
+        let backend_timeout = get_backend_timeout();
+        let mut quiet = Vec::new();
+        let mut slow = Vec::new();
+        for (t, since) in self.seen.iter() {
+            let backend = match sides(t) {
+                Option::Some((c, b)) if c == *t && b.0 != 0 => b,
+                _ => continue
+            };
+            let last = match self.seen.get(&backend) {
+                Option::Some(b) if b > since => *b,
+                _ => *since
+            };
+            let quiet_for = now.duration_since(last);
+            match idle_timeout(t) {
+                Ok(Option::Some(d)) => if quiet_for >= d {
+                    quiet.push(*t);
+                },
+                Ok(Option::None) => match unanswered(t) {
+                    Ok(true) if quiet_for >= backend_timeout => slow.push(*t),
+                    _ => { }
+                },
+                Err(_) => { }
+            };
+        }
+        for t in quiet {
+            self.abort(t);
+        }
+        for t in slow {
+            self.fail(t, 504, now);
+        }
+    }
+}
+
+///Run worker `id`. Messages from the event loop are handled as they arrive, and connections
+///which have been quiet too long are looked for every `TICK_MS`. This never returns.
+pub fn run(id: usize) {
+    set_id(id);
+    let mut worker = Worker::new();
+    let mut events = Vec::with_capacity(256);
+    let tick = Duration::from_millis(TICK_MS);
+    let mut next_tick = Instant::now() + tick;
+    loop {
+        let now = Instant::now();
+        let timeout = if next_tick > now {
+            next_tick - now
+        } else {
+            Duration::from_millis(0)
+        };
+        wait_events(&mut events, Some(timeout));
+        let now = Instant::now();
+        for e in events.drain(..) {
+            worker.on_event(e, now);
+        }
+        if now >= next_tick {
+            worker.tick(now);
+            next_tick = now + tick;
+        }
+    }
+}